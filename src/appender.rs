@@ -1,11 +1,18 @@
 //! Appenders are mmap'ed files intended for append-only use.
 
-use crate::{growable_mmap::GrowableMmap, Error, SharedMmap};
+use crate::{
+    growable_mmap::{GrowableMmap, Reclaim},
+    holes::Holes,
+    quota::{self, Quota},
+    Error, SharedMmap,
+};
+use memmap2::{Mmap, MmapMut};
 use std::{
     cell::UnsafeCell,
-    fs::OpenOptions,
+    fs::{File, OpenOptions},
+    io,
     marker::Sync,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::atomic::{AtomicUsize, Ordering},
 };
 
@@ -17,6 +24,8 @@ pub(crate) struct Appender {
     // reads. Since this value is updated only after the write has finished it
     // is safe to use it as the upper boundary for reads.
     actual_size: AtomicUsize,
+    /// The size the file may not grow over.
+    quota: Option<(Quota, usize)>,
 }
 
 impl Appender {
@@ -29,11 +38,12 @@ impl Appender {
     ///   limits the size of the file. If the `map_size` is smaller than the
     ///   size of the file, an error will be returned.
     pub fn new(path: Option<PathBuf>) -> Result<Self, Error> {
-        let (file, actual_size) = if let Some(path) = path {
+        let (file, actual_size, holes, zeroing) = if let Some(path) = path {
             let file = OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(&path)
                 .map_err(|err| Error::FileOpen(path.clone(), err))?;
 
@@ -42,15 +52,35 @@ impl Appender {
                 .map_err(|err| Error::FileOpen(path.clone(), err))?
                 .len() as usize;
 
-            (Some(file), actual_size)
+            let holes = Holes::load(Some(&path), actual_size)?;
+            let zeroing = Holes::load_zeroing(Some(&path), actual_size)?;
+            (Some(file), actual_size, holes, zeroing)
         } else {
-            (None, 0)
+            (None, 0, Holes::load(None, 0)?, Holes::load(None, 0)?)
         };
 
-        let mmap = UnsafeCell::new(GrowableMmap::new(file)?);
+        let mmap = UnsafeCell::new(GrowableMmap::new(file, holes, zeroing)?);
         let actual_size = AtomicUsize::from(actual_size);
 
-        Ok(Self { mmap, actual_size })
+        Ok(Self {
+            mmap,
+            actual_size,
+            quota: None,
+        })
+    }
+
+    /// Fail to reserve space that takes the size over `max`, reporting it as
+    /// exceeding `quota`.
+    pub fn with_quota(mut self, quota: Quota, max: Option<usize>) -> Self {
+        self.quota = max.map(|max| (quota, max));
+        self
+    }
+
+    fn check_quota(&self, size: usize) -> Result<(), Error> {
+        match self.quota {
+            Some((quota, max)) => quota::check(quota, size, Some(max)),
+            None => Ok(()),
+        }
     }
 
     /// Append data to the file. The mutable pointer to the new data location is
@@ -58,24 +88,72 @@ impl Appender {
     /// another write is in progress.
     pub fn append<F>(&self, size_inc: usize, f: F) -> Result<(), Error>
     where
-        F: Fn(&mut [u8]),
+        F: FnOnce(&mut [u8]),
     {
         if size_inc == 0 {
             return Ok(());
         }
 
-        let mmap = unsafe { self.mmap.get().as_mut().unwrap() };
+        let mut page = self.reserve(size_inc)?;
+        f(page.as_mut());
+        self.commit(page)
+    }
+
+    /// Extend the file by `size_inc` bytes and return the mapping of the new
+    /// part. The data written there does not become visible to readers until
+    /// the page is given to `commit`. If the page is not needed anymore, it
+    /// should be given to `abort`. There can be only one reserved page at a
+    /// time.
+    pub fn reserve(&self, size_inc: usize) -> Result<MmapMut, Error> {
+        self.check_quota(self.size() + size_inc)?;
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.grow(size_inc)
+    }
+
+    /// Change the size of a reserved page preserving its content.
+    pub fn resize(&self, page: &mut MmapMut, new_len: usize) -> Result<(), Error> {
+        self.check_quota(self.size() + new_len)?;
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.resize(page, new_len)
+    }
+
+    /// Make the data written to a reserved page visible to readers.
+    pub fn commit(&self, page: MmapMut) -> Result<(), Error> {
+        let page = self.seal(page)?;
+        self.publish(page);
+        Ok(())
+    }
+
+    /// Write the data of a reserved page to the file and protect it from
+    /// changes without making it visible. This is the part of a commit that
+    /// can fail. If it does, the page is dropped and the file is shrunk back.
+    pub fn seal(&self, page: MmapMut) -> Result<Mmap, Error> {
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.seal(page)
+    }
+
+    /// Make the data of a sealed page visible to readers.
+    pub fn publish(&self, page: Mmap) {
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
         let actual_size = self.actual_size.load(Ordering::Relaxed);
 
-        let new_file_size = actual_size + size_inc;
+        let new_file_size = actual_size + page.len();
 
-        let mut page = mmap.grow(size_inc)?;
-        f(page.as_mut());
-        mmap.append_page(page)?;
+        mmap.append_page(page);
 
         self.actual_size.store(new_file_size, Ordering::Release);
+    }
 
-        Ok(())
+    /// Drop a sealed page and shrink the file back.
+    pub fn discard(&self, page: Mmap) -> Result<(), Error> {
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.discard_sealed(page)
+    }
+
+    /// Drop a reserved page and shrink the file back.
+    pub fn abort(&self, page: MmapMut) -> Result<(), Error> {
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.discard(page)
     }
 
     /// The whole data buffer is given to `f` which should return the data back
@@ -91,6 +169,123 @@ impl Appender {
     pub fn size(&self) -> usize {
         self.actual_size.load(Ordering::Acquire)
     }
+
+    /// The underlying file unless this is an in-memory appender.
+    pub fn file(&self) -> Option<&File> {
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.file()
+    }
+
+    pub fn mappings(&self) -> usize {
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.mappings()
+    }
+
+    /// Overwrite `len` bytes at `offset` with zeros. Views into the range
+    /// are not handed out anymore. The data is overwritten once views handed
+    /// out earlier into the same pages are dropped, which is checked by this
+    /// and later calls to `zero` and `punch`, or when the file is opened
+    /// again: the range is stored until then. Fails for in-memory appenders,
+    /// whose pages cannot be written after a commit.
+    pub fn zero(&self, offset: usize, len: usize) -> Result<(), Error> {
+        if self.file().is_none() {
+            return Err(Error::InvalidOptions(
+                "records of in-memory databases cannot be zeroed",
+            ));
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.zeroing().add(offset..offset + len)?;
+        mmap.schedule(offset..offset + len, Reclaim::Zero)
+    }
+
+    /// Give `len` bytes at `offset` back to the filesystem. The size of the
+    /// file and offsets in it do not change, but views into the range are not
+    /// handed out anymore. Like with `zero`, the space is given back once
+    /// views handed out earlier are dropped. Only works for files on Linux.
+    /// In-memory appenders free the memory of the range instead.
+    pub fn punch(&self, offset: usize, len: usize) -> Result<(), Error> {
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.check_punch()?;
+        mmap.holes().add(offset..offset + len)?;
+        mmap.schedule(offset..offset + len, Reclaim::Punch)
+    }
+
+    /// Fail if holes cannot be punched in the file.
+    pub fn check_punch(&self) -> Result<(), Error> {
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        mmap.check_punch()
+    }
+
+    /// Whether holes were punched in the file.
+    pub fn has_holes(&self) -> bool {
+        let mmap = unsafe { self.mmap.get().as_ref().unwrap() };
+        !mmap.holes().is_empty()
+    }
+
+    /// Cut the file at `path` to `size` bytes. The file must not be opened by
+    /// any appender while doing this.
+    pub fn truncate(path: &Path, size: usize) -> Result<(), Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(|err| Error::FileOpen(path.to_path_buf(), err))?;
+
+        let actual_size = file
+            .metadata()
+            .map_err(|err| Error::FileOpen(path.to_path_buf(), err))?
+            .len() as usize;
+
+        if actual_size <= size {
+            return Ok(());
+        }
+
+        file.set_len(size as u64).map_err(Error::Truncate)?;
+        Holes::truncate(path, size)
+    }
 }
 
 unsafe impl Sync for Appender {}
+
+/// Write the whole `buf` to `file` at `offset` without moving the cursor.
+#[cfg(unix)]
+pub(crate) fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+/// Write the whole `buf` to `file` at `offset`.
+#[cfg(windows)]
+pub(crate) fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                buf = &buf[written..];
+                offset += written as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Make changes to the entries of the directory at `path`, e.g. renamed
+/// files, survive a crash.
+#[cfg(unix)]
+pub(crate) fn sync_dir(path: &Path) -> Result<(), Error> {
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .map_err(Error::Flush)
+}
+
+/// Directories cannot be opened as files on Windows.
+#[cfg(windows)]
+pub(crate) fn sync_dir(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
@@ -0,0 +1,424 @@
+//! `pile` - inspect and modify data piles from the command line.
+
+use clap::{Parser, Subcommand, ValueEnum};
+use data_pile::{Cipher, Database, Error, Format, Key, Options, Payload, SharedMmap};
+use std::{
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+#[derive(Parser)]
+#[command(name = "pile", version, about = "Inspect and modify data piles")]
+struct Cli {
+    /// Path to the database directory.
+    path: PathBuf,
+    /// How records are printed.
+    #[arg(short, long, value_enum, default_value_t = Output::Raw)]
+    output: Output,
+    /// File holding the 32-byte key of an encrypted database. New databases
+    /// are encrypted with it.
+    #[arg(short, long)]
+    key_file: Option<PathBuf>,
+    /// The cipher new encrypted databases use.
+    #[arg(long, value_enum, default_value_t = CipherKind::Aes256Gcm, requires = "key_file")]
+    cipher: CipherKind,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the number of records and the size of the database files.
+    Stat,
+    /// Print a single record.
+    Get { seqno: usize },
+    /// Print records in the given range.
+    Cat {
+        #[command(flatten)]
+        range: Range,
+    },
+    /// Print the last records and optionally wait for new ones.
+    Tail {
+        /// The number of records to print.
+        #[arg(short = 'n', long, default_value_t = 10)]
+        lines: usize,
+        /// Keep printing records as they are appended.
+        #[arg(short, long)]
+        follow: bool,
+        /// How often to check for new records in milliseconds.
+        #[arg(long, default_value_t = 500)]
+        interval: u64,
+    },
+    /// Append the content of every file as a separate record. Reads a single
+    /// record from stdin if no files are given.
+    Append {
+        files: Vec<PathBuf>,
+        /// Treat every line of the input as a separate record. Empty lines
+        /// are stored as empty records.
+        #[arg(short, long)]
+        lines: bool,
+        /// Skip empty lines. Requires `--lines`.
+        #[arg(long, requires = "lines")]
+        skip_empty: bool,
+    },
+    /// Check the integrity of the database.
+    Verify,
+    /// Write records to a portable stream.
+    Export {
+        #[command(flatten)]
+        range: Range,
+        #[command(flatten)]
+        format: StreamFormat,
+        /// Write to this file instead of stdout.
+        #[arg(short = 'O', long)]
+        out: Option<PathBuf>,
+    },
+    /// Append records from a portable stream.
+    Import {
+        #[command(flatten)]
+        format: StreamFormat,
+        /// Read from this file instead of stdin.
+        file: Option<PathBuf>,
+        /// The number of records written at once.
+        #[arg(long, default_value_t = 4096)]
+        batch_size: usize,
+    },
+    /// Leave only the given number of records.
+    Truncate { len: usize },
+    /// Restore the sequential number index from a framed data file.
+    RebuildIndex,
+}
+
+#[derive(clap::Args)]
+struct Range {
+    /// The first record to print.
+    #[arg(long, default_value_t = 0)]
+    from: usize,
+    /// The record after the last one to print.
+    #[arg(long)]
+    to: Option<usize>,
+}
+
+#[derive(clap::Args)]
+struct StreamFormat {
+    #[arg(short, long, value_enum, default_value_t = StreamKind::Binary)]
+    format: StreamKind,
+    /// How record payloads are encoded in text formats.
+    #[arg(long, value_enum, default_value_t = PayloadKind::Base64)]
+    payload: PayloadKind,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Output {
+    Raw,
+    Hex,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CipherKind {
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    #[value(name = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum StreamKind {
+    Binary,
+    Jsonl,
+    Csv,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PayloadKind {
+    Base64,
+    Utf8,
+}
+
+impl StreamFormat {
+    fn format(&self) -> Format {
+        let payload = match self.payload {
+            PayloadKind::Base64 => Payload::Base64,
+            PayloadKind::Utf8 => Payload::Utf8,
+        };
+        match self.format {
+            StreamKind::Binary => Format::Binary,
+            StreamKind::Jsonl => Format::JsonLines(payload),
+            StreamKind::Csv => Format::Csv(payload),
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    if let Err(err) = run(cli) {
+        eprintln!("error: {}", err);
+        let mut source = err.source();
+        while let Some(err) = source {
+            eprintln!("caused by: {}", err);
+            source = err.source();
+        }
+        process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    match cli.command {
+        Command::Truncate { len } => return Ok(Database::truncate(&cli.path, len)?),
+        Command::RebuildIndex => {
+            let count = Database::rebuild_index(&cli.path)?;
+            eprintln!("recovered {} records", count);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // Opening a database creates its files, which only writing commands
+    // may do.
+    let creates = matches!(cli.command, Command::Append { .. } | Command::Import { .. });
+    if !creates && !cli.path.join("data").is_file() {
+        return Err(format!("`{}` is not a database", cli.path.display()).into());
+    }
+
+    let options = options(&cli)?;
+    let db = match Database::file_with_options(&cli.path, options.clone()) {
+        Err(Error::NoKey) if cli.key_file.is_none() => {
+            return Err(format!(
+                "`{}` is encrypted: pass its key with --key-file",
+                cli.path.display()
+            )
+            .into());
+        }
+        db => db?,
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match cli.command {
+        Command::Stat => Ok(stat(&db, cli.output, &mut out)?),
+        Command::Get { seqno } => match db.get_by_seqno(seqno) {
+            Some(record) => Ok(print_record(&mut out, cli.output, seqno, &record)?),
+            None => Err(format!("record {} not found", seqno).into()),
+        },
+        Command::Cat { range } => {
+            let to = range.to.unwrap_or(usize::MAX);
+            print_records(&db, cli.output, &mut out, range.from, to)?;
+            Ok(())
+        }
+        Command::Tail {
+            lines,
+            follow,
+            interval,
+        } => {
+            let mut db = db;
+            let mut from = db.len().saturating_sub(lines);
+            let mut size = stored_size(&cli.path);
+            loop {
+                from = print_records(&db, cli.output, &mut out, from, usize::MAX)?;
+                if !follow {
+                    return Ok(());
+                }
+                thread::sleep(Duration::from_millis(interval));
+                // Reopen to pick up records appended by other processes.
+                // Opening an existing database does not change its files.
+                let new_size = stored_size(&cli.path);
+                if new_size != size {
+                    size = new_size;
+                    db = Database::file_with_options(&cli.path, options.clone())?;
+                }
+            }
+        }
+        Command::Append {
+            files,
+            lines,
+            skip_empty,
+        } => {
+            let mut inputs = Vec::with_capacity(files.len());
+            if files.is_empty() {
+                inputs.push(read_input(None)?);
+            }
+            for file in files.iter() {
+                inputs.push(read_input(Some(file))?);
+            }
+
+            let records: Vec<&[u8]> = if lines {
+                inputs
+                    .iter()
+                    .flat_map(|input| split_lines(input))
+                    .filter(|line| !(skip_empty && line.is_empty()))
+                    .collect()
+            } else {
+                inputs.iter().map(|input| input.as_ref()).collect()
+            };
+
+            if let Some(seqno) = db.append_get_seqno(&records)? {
+                eprintln!("appended records {}..{}", seqno, seqno + records.len());
+            }
+            Ok(())
+        }
+        Command::Verify => {
+            db.verify()?;
+            writeln!(out, "ok: {} records", db.len())?;
+            Ok(())
+        }
+        Command::Export {
+            range,
+            format,
+            out: path,
+        } => {
+            let to = range.to.unwrap_or(usize::MAX);
+            let count = match path {
+                Some(path) => {
+                    let file =
+                        File::create(&path).map_err(|err| Error::FileOpen(path.clone(), err))?;
+                    db.export(range.from..to, format.format(), io::BufWriter::new(file))?
+                }
+                None => db.export(range.from..to, format.format(), &mut out)?,
+            };
+            eprintln!("exported {} records", count);
+            Ok(())
+        }
+        Command::Import {
+            format,
+            file,
+            batch_size,
+        } => {
+            let count = match file {
+                Some(path) => {
+                    let file = File::open(&path).map_err(|err| Error::FileOpen(path, err))?;
+                    db.import(BufReader::new(file), format.format(), batch_size)?
+                }
+                None => db.import(io::stdin().lock(), format.format(), batch_size)?,
+            };
+            eprintln!("imported {} records", count);
+            Ok(())
+        }
+        Command::Truncate { .. } | Command::RebuildIndex => unreachable!(),
+    }
+}
+
+/// Options to open the database with.
+fn options(cli: &Cli) -> Result<Options, Box<dyn std::error::Error>> {
+    let path = match &cli.key_file {
+        Some(path) => path,
+        None => return Ok(Options::new()),
+    };
+    let key = fs::read(path).map_err(|err| Error::FileOpen(path.clone(), err))?;
+    let key = Key::try_from(key.as_slice())
+        .map_err(|_| format!("`{}` does not hold a 32-byte key", path.display()))?;
+    let cipher = match cli.cipher {
+        CipherKind::Aes256Gcm => Cipher::Aes256Gcm,
+        CipherKind::ChaCha20Poly1305 => Cipher::ChaCha20Poly1305,
+    };
+    Ok(Options::new().encryption(cipher, Arc::new(key)))
+}
+
+fn stat(db: &Database, output: Output, out: &mut impl Write) -> Result<(), Error> {
+    let stat = db.stat();
+    match output {
+        Output::Json => {
+            let stat = serde_json::json!({
+                "records": stat.records,
+                "deleted": stat.deleted,
+                "data_size": stat.data_size,
+                "index_size": stat.index_size,
+                "mappings": stat.mappings,
+            });
+            writeln!(out, "{}", stat)
+        }
+        Output::Raw | Output::Hex => writeln!(
+            out,
+            "records: {}\ndeleted: {}\ndata size: {}\nindex size: {}\nmappings: {}",
+            stat.records, stat.deleted, stat.data_size, stat.index_size, stat.mappings
+        ),
+    }
+    .map_err(Error::Write)
+}
+
+/// Print records starting from `from` and return the number of the record
+/// after the last printed one.
+fn print_records(
+    db: &Database,
+    output: Output,
+    out: &mut impl Write,
+    from: usize,
+    to: usize,
+) -> Result<usize, Error> {
+    let mut iter = db.iter_range(from..to);
+    loop {
+        let seqno = iter.seqno();
+        match iter.next() {
+            Some(record) => print_record(out, output, seqno, &record)?,
+            None => {
+                out.flush().map_err(Error::Write)?;
+                return Ok(seqno);
+            }
+        }
+    }
+}
+
+fn print_record(
+    out: &mut impl Write,
+    output: Output,
+    seqno: usize,
+    record: &SharedMmap,
+) -> Result<(), Error> {
+    let record = record.as_ref();
+    match output {
+        Output::Raw => out.write_all(record).and_then(|_| out.write_all(b"\n")),
+        Output::Hex => {
+            let hex: String = record.iter().map(|byte| format!("{:02x}", byte)).collect();
+            writeln!(out, "{}: {}", seqno, hex)
+        }
+        Output::Json => {
+            use base64::Engine;
+            let line = serde_json::json!({
+                "seqno": seqno,
+                "len": record.len(),
+                "data": base64::engine::general_purpose::STANDARD.encode(record),
+            });
+            writeln!(out, "{}", line)
+        }
+    }
+    .map_err(Error::Write)
+}
+
+fn read_input(path: Option<&Path>) -> Result<Vec<u8>, Error> {
+    let mut input = Vec::new();
+    match path {
+        Some(path) => File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut input))
+            .map_err(|err| Error::FileOpen(path.to_path_buf(), err))?,
+        None => io::stdin().read_to_end(&mut input).map_err(Error::Read)?,
+    };
+    Ok(input)
+}
+
+/// Split `input` into lines. A newline at the end does not start another
+/// line.
+fn split_lines(input: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let lines = if input.is_empty() { 0 } else { usize::MAX };
+    input
+        .strip_suffix(b"\n")
+        .unwrap_or(input)
+        .split(|byte| *byte == b'\n')
+        .take(lines)
+}
+
+/// The size of the files that grow when records are appended to the database
+/// at `path`.
+fn stored_size(path: &Path) -> u64 {
+    ["data", "seqno"]
+        .iter()
+        .filter_map(|name| path.join(name).metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
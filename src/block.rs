@@ -0,0 +1,812 @@
+//! Block compression. Records are packed into blocks that are compressed with
+//! zstd as a whole, so that small records compress well. Every block is a
+//! frame in the data file:
+//!
+//! * the dictionary id as a little-endian `u32` (`0` if none was used);
+//! * the number of records as a little-endian `u32`;
+//! * the size of the decompressed block as a little-endian `u64`;
+//! * the compressed content: the length of every record as a little-endian
+//!   `u64` followed by the records.
+//!
+//! The sequential number index holds the offset of the block shifted left by
+//! `POSITION_BITS` together with the position of the record within the block.
+//! Dictionaries are stored in `dictionary.<id>` files next to the data file.
+//!
+//! Records that do not fill a block yet are kept in the open block, which is
+//! written at the end of the data file once the next records do not fit into
+//! it. Their index values already point there. The open block is stored in
+//! the `block.open` file: its offset as a little-endian `u64` followed by its
+//! records, each prefixed with its length as a little-endian `u64`. Records
+//! of encrypted databases are stored there encrypted as if they were at the
+//! offset of the block plus their position.
+
+use crate::{appender::sync_dir, encryption::Encryption, Error, SharedMmap};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    mem::{self, size_of},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+};
+
+/// How many bits of an index value hold the position of a record in a block.
+const POSITION_BITS: u32 = 16;
+/// The maximum number of records in a block.
+const MAX_BLOCK_RECORDS: usize = 1 << POSITION_BITS;
+/// The size of the uncompressed block header.
+const HEADER_SIZE: usize = 2 * size_of::<u32>() + size_of::<u64>();
+/// The size of a record length within a block.
+const LENGTH_SIZE: usize = size_of::<u64>();
+/// How many records are used at most to train a dictionary.
+const MAX_SAMPLES: usize = 16384;
+/// The file that holds the open block.
+const OPEN_FILE: &str = "block.open";
+
+/// Make the index value of the record at `position` in the block at `offset`.
+pub(crate) fn index_value(offset: usize, position: usize) -> Result<u64, Error> {
+    let offset = offset as u64;
+    if offset.leading_zeros() < POSITION_BITS {
+        return Err(Error::IndexOverflow);
+    }
+    Ok((offset << POSITION_BITS) | position as u64)
+}
+
+/// Split an index value into the offset of the block and the position of the
+/// record in it.
+pub(crate) fn locate(value: u64) -> (usize, usize) {
+    let position = value & (MAX_BLOCK_RECORDS as u64 - 1);
+    ((value >> POSITION_BITS) as usize, position as usize)
+}
+
+/// The number of records in a stored block. Does not decompress the block.
+pub(crate) fn count(block: &[u8]) -> Option<usize> {
+    Header::parse(block).map(|header| header.count)
+}
+
+struct Header {
+    dictionary: usize,
+    count: usize,
+    len: usize,
+}
+
+impl Header {
+    fn parse(block: &[u8]) -> Option<Self> {
+        if block.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let mut u32_bytes = [0u8; 4];
+        let mut u64_bytes = [0u8; 8];
+        u32_bytes.copy_from_slice(&block[0..4]);
+        let dictionary = u32::from_le_bytes(u32_bytes) as usize;
+        u32_bytes.copy_from_slice(&block[4..8]);
+        let count = u32::from_le_bytes(u32_bytes) as usize;
+        u64_bytes.copy_from_slice(&block[8..16]);
+        let len = u64::from_le_bytes(u64_bytes) as usize;
+
+        Some(Self {
+            dictionary,
+            count,
+            len,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.dictionary as u32).to_le_bytes());
+        out.extend_from_slice(&(self.count as u32).to_le_bytes());
+        out.extend_from_slice(&(self.len as u64).to_le_bytes());
+    }
+}
+
+/// A decompressed block.
+pub(crate) struct Block {
+    records: Vec<SharedMmap>,
+}
+
+impl Block {
+    fn parse(data: Vec<u8>, count: usize) -> Option<Self> {
+        let data_start = count.checked_mul(LENGTH_SIZE)?;
+        if data.len() < data_start {
+            return None;
+        }
+
+        let mut bounds = Vec::with_capacity(count);
+        let mut start = data_start;
+        for length in data[..data_start].chunks_exact(LENGTH_SIZE) {
+            let mut length_bytes = [0u8; LENGTH_SIZE];
+            length_bytes.copy_from_slice(length);
+            let end = start.checked_add(u64::from_le_bytes(length_bytes) as usize)?;
+            bounds.push(start..end);
+            start = end;
+        }
+
+        if start != data.len() {
+            return None;
+        }
+
+        let data = SharedMmap::from(data);
+        Some(Self {
+            records: bounds
+                .into_iter()
+                .map(|bounds| data.slice(bounds))
+                .collect(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn get(&self, position: usize) -> Option<SharedMmap> {
+        self.records.get(position).cloned()
+    }
+}
+
+/// Records that do not fill a block yet.
+struct Open {
+    /// Where the block is written once it is full: the end of the data file.
+    offset: usize,
+    block: Arc<Block>,
+    /// How many records are stored in the file or `None` if it holds
+    /// something else and has to be rewritten.
+    stored: Option<usize>,
+    /// The open block before the latest write. Its records are read from
+    /// here until the write adds them to the data file.
+    previous: Option<(usize, Arc<Block>)>,
+}
+
+/// Records of a write packed into blocks.
+pub(crate) struct Packed {
+    /// Compressed blocks that are full and the number of records in every one.
+    pub full: Vec<(Vec<u8>, usize)>,
+    /// Records left in the open block.
+    pub open: Vec<SharedMmap>,
+    /// How many of the records were in the open block before the write.
+    pub reused: usize,
+}
+
+/// Packs records into blocks and reads them back.
+pub(crate) struct Blocks {
+    block_size: usize,
+    dir: Option<PathBuf>,
+    /// Dictionary `id` is at `id - 1`.
+    dictionaries: RwLock<Vec<Arc<codec::Dictionary>>>,
+    cache: Mutex<Cache>,
+    open: RwLock<Open>,
+}
+
+impl Blocks {
+    /// Load dictionaries stored in `dir`, if any. The open block is loaded by
+    /// `load_open`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - the database directory or `None` for in-memory databases.
+    /// * `block_size` - how many bytes of records a block holds at most
+    ///   unless it has a single record.
+    /// * `cache_size` - how many decompressed blocks are kept in memory.
+    pub fn new(dir: Option<PathBuf>, block_size: usize, cache_size: usize) -> Result<Self, Error> {
+        let mut dictionaries = Vec::new();
+
+        if let Some(dir) = &dir {
+            loop {
+                let path = dictionary_path(dir, dictionaries.len() + 1);
+                match fs::read(&path) {
+                    Ok(raw) => dictionaries.push(Arc::new(codec::Dictionary::new(&raw))),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+                    Err(err) => return Err(Error::FileOpen(path, err)),
+                }
+            }
+        }
+
+        Ok(Self {
+            block_size,
+            dir,
+            dictionaries: RwLock::new(dictionaries),
+            cache: Mutex::new(Cache::new(cache_size)),
+            open: RwLock::new(Open {
+                offset: 0,
+                block: Arc::new(Block {
+                    records: Vec::new(),
+                }),
+                stored: None,
+                previous: None,
+            }),
+        })
+    }
+
+    /// Load the open block at `end`, the end of the data file. Only the first
+    /// `indexed` records stored for it are kept: the others were not added to
+    /// the index. A block stored for another offset was already written to
+    /// the data file or was cut off it.
+    pub fn load_open(
+        &self,
+        end: usize,
+        indexed: usize,
+        encryption: Option<&Encryption>,
+    ) -> Result<(), Error> {
+        let mut records = Vec::new();
+        let mut stored = None;
+
+        if let Some(dir) = &self.dir {
+            let content = read_open(dir)?;
+            if let Some((offset, entries, complete)) = parse_open(&content) {
+                if offset == end {
+                    if complete && entries.len() == indexed {
+                        stored = Some(indexed);
+                    }
+                    for (position, entry) in entries.into_iter().take(indexed).enumerate() {
+                        let record = match encryption {
+                            Some(encryption) => encryption.open(entry, false, end + position)?,
+                            None => entry.to_vec(),
+                        };
+                        records.push(SharedMmap::from(record));
+                    }
+                }
+            }
+        }
+
+        *self.open.write().unwrap_or_else(PoisonError::into_inner) = Open {
+            offset: end,
+            block: Arc::new(Block { records }),
+            stored,
+            previous: None,
+        };
+        Ok(())
+    }
+
+    /// Add `records` to the open block, which must be at `end`, the end of
+    /// the data file. The records that fill blocks are compressed with the
+    /// latest dictionary. Nothing changes until the records left over are
+    /// passed to `open`.
+    pub fn pack(&self, end: usize, records: &[&[u8]]) -> Result<Packed, Error> {
+        let mut all = match self.unwritten(end) {
+            Some(block) => block.records.clone(),
+            None => Vec::new(),
+        };
+        let reused = all.len();
+        all.extend(
+            records
+                .iter()
+                .map(|record| SharedMmap::from(record.to_vec())),
+        );
+
+        let mut full = Vec::new();
+        let mut start = 0;
+        loop {
+            let end = start + self.fill(&all[start..]);
+            if end == all.len() {
+                break;
+            }
+            full.push((self.compress(&all[start..end])?, end - start));
+            start = end;
+        }
+
+        Ok(Packed {
+            full,
+            open: all.split_off(start),
+            reused,
+        })
+    }
+
+    /// How many of `records` starting from the first one fit into a block.
+    /// Every block has at least one record.
+    fn fill(&self, records: &[SharedMmap]) -> usize {
+        let mut count = 0;
+        let mut size = 0;
+        for record in records.iter().take(MAX_BLOCK_RECORDS) {
+            if count > 0 && size + record.len() > self.block_size {
+                break;
+            }
+            size += record.len();
+            count += 1;
+        }
+        count
+    }
+
+    /// Compress records into a block with the latest dictionary.
+    fn compress(&self, records: &[SharedMmap]) -> Result<Vec<u8>, Error> {
+        let (dictionary, encoder) = {
+            let dictionaries = self
+                .dictionaries
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            (dictionaries.len(), dictionaries.last().cloned())
+        };
+
+        let size: usize = records.iter().map(|record| record.len()).sum();
+        let mut content = Vec::with_capacity(records.len() * LENGTH_SIZE + size);
+        for record in records {
+            content.extend_from_slice(&(record.len() as u64).to_le_bytes());
+        }
+        for record in records {
+            content.extend_from_slice(record);
+        }
+
+        let compressed = codec::compress(&content, encoder.as_deref())?;
+        let mut block = Vec::with_capacity(HEADER_SIZE + compressed.len());
+        let header = Header {
+            dictionary,
+            count: records.len(),
+            len: content.len(),
+        };
+        header.write(&mut block);
+        block.extend_from_slice(&compressed);
+        Ok(block)
+    }
+
+    /// Make `records` the open block at `offset` and store them. The previous
+    /// open block is still read until `finish` or `restore` is called. Must be
+    /// called after the blocks that got full are stored in the data file.
+    pub fn open(
+        &self,
+        offset: usize,
+        records: Vec<SharedMmap>,
+        encryption: Option<&Encryption>,
+    ) -> Result<(), Error> {
+        let mut open = self.open.write().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(dir) = &self.dir {
+            match open.stored {
+                // Only the new records have to be added.
+                Some(stored) if open.offset == offset => {
+                    open.stored = None;
+                    let entries = entries(offset, stored, &records[stored..], encryption)?;
+                    let path = dir.join(OPEN_FILE);
+                    let mut file = OpenOptions::new()
+                        .append(true)
+                        .open(&path)
+                        .map_err(|err| Error::FileOpen(path, err))?;
+                    file.write_all(&entries).map_err(Error::Write)?;
+                    file.sync_data().map_err(Error::Flush)?;
+                }
+                _ => store_open(dir, offset, &records, encryption)?,
+            }
+        }
+
+        let block = mem::replace(&mut open.block, Arc::new(Block { records }));
+        open.previous = Some((open.offset, block));
+        open.offset = offset;
+        open.stored = Some(open.block.len());
+        Ok(())
+    }
+
+    /// Forget the previous open block once the write added its records to
+    /// the data file.
+    pub fn finish(&self) {
+        self.open
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .previous = None;
+    }
+
+    /// Make the previous open block the open block again after a failed
+    /// write.
+    pub fn restore(&self, encryption: Option<&Encryption>) {
+        let mut open = self.open.write().unwrap_or_else(PoisonError::into_inner);
+        let (offset, block) = match open.previous.take() {
+            Some(previous) => previous,
+            None => return,
+        };
+
+        // The file is restored as well, so that the records are found if the
+        // process stops before the next write. If this fails, the next write
+        // rewrites the file.
+        open.stored = None;
+        if let Some(dir) = &self.dir {
+            if store_open(dir, offset, &block.records, encryption).is_ok() {
+                open.stored = Some(block.len());
+            }
+        }
+        open.offset = offset;
+        open.block = block;
+    }
+
+    /// The open block if it is at `offset`: its records are not in the data
+    /// file yet.
+    pub fn unwritten(&self, offset: usize) -> Option<Arc<Block>> {
+        let open = self.open.read().unwrap_or_else(PoisonError::into_inner);
+        if open.offset == offset {
+            return Some(open.block.clone());
+        }
+        open.previous
+            .as_ref()
+            .filter(|(previous, _)| *previous == offset)
+            .map(|(_, block)| block.clone())
+    }
+
+    /// Decompress the block stored at `offset` of the data file. `load`
+    /// reads the stored block unless it is cached.
+    pub fn get<B, F>(&self, offset: usize, load: F) -> Result<Arc<Block>, Error>
+    where
+        B: AsRef<[u8]>,
+        F: FnOnce() -> Result<B, Error>,
+    {
+        if let Some(block) = self.unwritten(offset) {
+            return Ok(block);
+        }
+
+        if let Some(block) = self
+            .cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(offset)
+        {
+            return Ok(block);
+        }
+
+        let block = load()?;
+        let block = block.as_ref();
+        let header = Header::parse(block).ok_or(Error::DataFileDamaged)?;
+        let dictionary = match header.dictionary {
+            0 => None,
+            id => Some(
+                self.dictionaries
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(id - 1)
+                    .cloned()
+                    .ok_or(Error::DataFileDamaged)?,
+            ),
+        };
+
+        let content = codec::decompress(&block[HEADER_SIZE..], header.len, dictionary.as_deref())
+            .filter(|content| content.len() == header.len)
+            .ok_or(Error::DataFileDamaged)?;
+        let block = Arc::new(Block::parse(content, header.count).ok_or(Error::DataFileDamaged)?);
+
+        self.cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(offset, block.clone());
+        Ok(block)
+    }
+
+    /// Train a dictionary of at most `max_size` bytes on `samples` and use it
+    /// for new blocks. Must be called while holding the write lock. Returns
+    /// the id of the dictionary.
+    pub fn train(&self, samples: &[SharedMmap], max_size: usize) -> Result<usize, Error> {
+        let raw = codec::train(samples, max_size)?;
+        let id = self
+            .dictionaries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+            + 1;
+
+        if let Some(dir) = &self.dir {
+            let path = dictionary_path(dir, id);
+            let new_path = dir.join(format!("dictionary.{}.new", id));
+            fs::write(&new_path, &raw).map_err(|err| Error::FileOpen(new_path.clone(), err))?;
+            fs::rename(&new_path, &path).map_err(Error::Replace)?;
+        }
+
+        self.dictionaries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::new(codec::Dictionary::new(&raw)));
+
+        Ok(id)
+    }
+}
+
+fn dictionary_path(dir: &std::path::Path, id: usize) -> PathBuf {
+    dir.join(format!("dictionary.{}", id))
+}
+
+/// The number of records stored in the open block at `end`, the end of the
+/// data file, in `dir`.
+pub(crate) fn open_records(dir: &Path, end: usize) -> Result<usize, Error> {
+    Ok(match parse_open(&read_open(dir)?) {
+        Some((offset, entries, _)) if offset == end => entries.len(),
+        _ => 0,
+    })
+}
+
+/// Read the `block.open` file in `dir`.
+fn read_open(dir: &Path) -> Result<Vec<u8>, Error> {
+    let path = dir.join(OPEN_FILE);
+    match fs::read(&path) {
+        Ok(content) => Ok(content),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(Error::FileOpen(path, err)),
+    }
+}
+
+/// Split the content of the `block.open` file into the offset of the block
+/// and the stored records. Also tells whether there is nothing after them,
+/// e.g. an incomplete record.
+fn parse_open(content: &[u8]) -> Option<(usize, Vec<&[u8]>, bool)> {
+    if content.len() < LENGTH_SIZE {
+        return None;
+    }
+    let (offset, mut rest) = content.split_at(LENGTH_SIZE);
+
+    let mut entries = Vec::new();
+    while rest.len() >= LENGTH_SIZE {
+        let (len, data) = rest.split_at(LENGTH_SIZE);
+        let len = u64_from_le(len) as usize;
+        if data.len() < len {
+            break;
+        }
+        let (entry, next) = data.split_at(len);
+        entries.push(entry);
+        rest = next;
+    }
+
+    Some((u64_from_le(offset) as usize, entries, rest.is_empty()))
+}
+
+/// The records of the open block at `offset` in the file format. `first` is
+/// the position of the first of them.
+fn entries(
+    offset: usize,
+    first: usize,
+    records: &[SharedMmap],
+    encryption: Option<&Encryption>,
+) -> Result<Vec<u8>, Error> {
+    let encrypted = match encryption {
+        Some(encryption) => {
+            let offsets: Vec<_> = (first..first + records.len())
+                .map(|position| offset + position)
+                .collect();
+            Some(encryption.seal(records, &vec![false; records.len()], &offsets)?)
+        }
+        None => None,
+    };
+
+    let mut entries = Vec::new();
+    for (index, record) in records.iter().enumerate() {
+        let stored = match &encrypted {
+            Some(encrypted) => encrypted[index].as_slice(),
+            None => record.as_ref(),
+        };
+        entries.extend_from_slice(&(stored.len() as u64).to_le_bytes());
+        entries.extend_from_slice(stored);
+    }
+    Ok(entries)
+}
+
+/// Replace the open block stored in `dir`.
+fn store_open(
+    dir: &Path,
+    offset: usize,
+    records: &[SharedMmap],
+    encryption: Option<&Encryption>,
+) -> Result<(), Error> {
+    let mut content = (offset as u64).to_le_bytes().to_vec();
+    content.extend_from_slice(&entries(offset, 0, records, encryption)?);
+
+    let new_path = dir.join("block.open.new");
+    File::create(&new_path)
+        .and_then(|mut file| {
+            file.write_all(&content)?;
+            file.sync_data()
+        })
+        .map_err(Error::Write)?;
+    fs::rename(&new_path, dir.join(OPEN_FILE)).map_err(Error::Replace)?;
+    sync_dir(dir)
+}
+
+fn u64_from_le(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(data);
+    u64::from_le_bytes(bytes)
+}
+
+/// Pick up to `MAX_SAMPLES` records evenly spread over `len` records.
+pub(crate) fn sample_seqnos(len: usize) -> impl Iterator<Item = usize> {
+    let step = len.div_ceil(MAX_SAMPLES).max(1);
+    (0..len).step_by(step)
+}
+
+/// Recently used decompressed blocks by their offsets. There are only a few of
+/// them, so a linear search is fast enough.
+struct Cache {
+    capacity: usize,
+    blocks: VecDeque<(usize, Arc<Block>)>,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn get(&mut self, offset: usize) -> Option<Arc<Block>> {
+        let index = self
+            .blocks
+            .iter()
+            .position(|(cached, _)| *cached == offset)?;
+        let entry = self.blocks.remove(index)?;
+        let block = entry.1.clone();
+        self.blocks.push_front(entry);
+        Some(block)
+    }
+
+    fn insert(&mut self, offset: usize, block: Arc<Block>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.blocks.truncate(self.capacity - 1);
+        self.blocks.push_front((offset, block));
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod codec {
+    use crate::{Error, SharedMmap};
+    use zstd::{
+        bulk::Compressor,
+        dict::{DecoderDictionary, EncoderDictionary},
+        stream::read::Decoder,
+    };
+
+    pub struct Dictionary {
+        encoder: EncoderDictionary<'static>,
+        decoder: DecoderDictionary<'static>,
+    }
+
+    impl Dictionary {
+        pub fn new(raw: &[u8]) -> Self {
+            Self {
+                encoder: EncoderDictionary::copy(raw, zstd::DEFAULT_COMPRESSION_LEVEL),
+                decoder: DecoderDictionary::copy(raw),
+            }
+        }
+    }
+
+    pub fn compress(data: &[u8], dictionary: Option<&Dictionary>) -> Result<Vec<u8>, Error> {
+        let compressor = match dictionary {
+            Some(dictionary) => Compressor::with_prepared_dictionary(&dictionary.encoder),
+            None => Compressor::new(zstd::DEFAULT_COMPRESSION_LEVEL),
+        };
+        compressor
+            .and_then(|mut compressor| compressor.compress(data))
+            .map_err(Error::Compress)
+    }
+
+    /// Decompress a block of `len` bytes. The length is read from the file,
+    /// so nothing is allocated for it upfront.
+    pub fn decompress(data: &[u8], len: usize, dictionary: Option<&Dictionary>) -> Option<Vec<u8>> {
+        let decoder = match dictionary {
+            Some(dictionary) => Decoder::with_prepared_dictionary(data, &dictionary.decoder),
+            None => Decoder::with_buffer(data),
+        };
+        crate::compression::read_at_most(decoder.ok()?, len)
+    }
+
+    pub fn train(samples: &[SharedMmap], max_size: usize) -> Result<Vec<u8>, Error> {
+        zstd::dict::from_samples(samples, max_size).map_err(Error::Compress)
+    }
+}
+
+/// Block compression is only available with the `zstd` feature. Databases
+/// that use it cannot be opened otherwise, so these are never called.
+#[cfg(not(feature = "zstd"))]
+mod codec {
+    use crate::{Error, SharedMmap};
+
+    pub struct Dictionary;
+
+    impl Dictionary {
+        pub fn new(_raw: &[u8]) -> Self {
+            Self
+        }
+    }
+
+    pub fn compress(_data: &[u8], _dictionary: Option<&Dictionary>) -> Result<Vec<u8>, Error> {
+        Err(Error::InvalidOptions("the `zstd` feature is disabled"))
+    }
+
+    pub fn decompress(
+        _data: &[u8],
+        _len: usize,
+        _dictionary: Option<&Dictionary>,
+    ) -> Option<Vec<u8>> {
+        None
+    }
+
+    pub fn train(_samples: &[SharedMmap], _max_size: usize) -> Result<Vec<u8>, Error> {
+        Err(Error::InvalidOptions("the `zstd` feature is disabled"))
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use super::{count, index_value, locate, open_records, Blocks};
+
+    #[quickcheck]
+    fn pack_and_get(records: Vec<Vec<u8>>, split: usize, block_size: u8) {
+        let blocks = Blocks::new(None, block_size as usize, 2).unwrap();
+        let raw_records: Vec<_> = records.iter().map(|x| x.as_ref()).collect();
+        let split = split % (raw_records.len() + 1);
+
+        // The data file holds the full blocks one after another.
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for batch in [&raw_records[..split], &raw_records[split..]] {
+            let packed = blocks.pack(data.len(), batch).unwrap();
+            for (block, records) in packed.full {
+                assert_eq!(Some(records), count(&block));
+                offsets.push((data.len(), block.len()));
+                data.extend_from_slice(&block);
+            }
+            blocks.open(data.len(), packed.open, None).unwrap();
+            blocks.finish();
+        }
+
+        let mut read = Vec::new();
+        for (offset, len) in offsets {
+            let decoded = blocks
+                .get(offset, || Ok(&data[offset..offset + len]))
+                .unwrap();
+            for position in 0..decoded.len() {
+                read.push(decoded.get(position).unwrap().to_vec());
+            }
+            assert!(decoded.get(decoded.len()).is_none());
+        }
+        let open = blocks.unwritten(data.len()).unwrap();
+        for position in 0..open.len() {
+            read.push(open.get(position).unwrap().to_vec());
+        }
+
+        assert_eq!(records, read);
+    }
+
+    #[test]
+    fn damaged_length() {
+        let blocks = Blocks::new(None, 1024, 2).unwrap();
+        let packed = blocks.pack(0, &[&[7u8; 1024][..], &[7u8; 1]]).unwrap();
+        let mut block = packed.full[0].0.clone();
+        block[8..16].copy_from_slice(&(1u64 << 60).to_le_bytes());
+        assert!(blocks.get(100, || Ok(&block)).is_err());
+    }
+
+    #[test]
+    fn store_open() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let records: Vec<_> = (0..10u8).map(|i| vec![i; 10]).collect();
+        let raw_records: Vec<_> = records.iter().map(|x| x.as_ref()).collect();
+
+        let blocks = Blocks::new(Some(dir.clone()), 4096, 2).unwrap();
+        blocks.load_open(0, 0, None).unwrap();
+        let packed = blocks.pack(0, &raw_records[..4]).unwrap();
+        blocks.open(0, packed.open, None).unwrap();
+        blocks.finish();
+        let packed = blocks.pack(0, &raw_records[4..]).unwrap();
+        assert_eq!(4, packed.reused);
+        blocks.open(0, packed.open, None).unwrap();
+        // The write failed.
+        blocks.restore(None);
+        assert_eq!(4, blocks.unwritten(0).unwrap().len());
+        assert_eq!(4, open_records(&dir, 0).unwrap());
+        let packed = blocks.pack(0, &raw_records[4..]).unwrap();
+        blocks.open(0, packed.open, None).unwrap();
+        blocks.finish();
+        assert_eq!(10, open_records(&dir, 0).unwrap());
+        assert_eq!(0, open_records(&dir, 1).unwrap());
+
+        // Only the first indexed records are kept.
+        let blocks = Blocks::new(Some(dir.clone()), 4096, 2).unwrap();
+        blocks.load_open(0, 7, None).unwrap();
+        let open = blocks.unwritten(0).unwrap();
+        assert_eq!(7, open.len());
+        for (position, record) in records.iter().take(open.len()).enumerate() {
+            assert_eq!(*record, open.get(position).unwrap().to_vec());
+        }
+        blocks.load_open(1, 7, None).unwrap();
+        assert_eq!(0, blocks.unwritten(1).unwrap().len());
+    }
+
+    #[test]
+    fn index_values() {
+        let value = index_value(12345, 678).unwrap();
+        assert_eq!((12345, 678), locate(value));
+        assert!(index_value(1 << 48, 0).is_err());
+    }
+}
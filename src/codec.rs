@@ -0,0 +1,149 @@
+use std::error;
+
+/// Conversion between values and records. Used by `TypedPile`.
+pub trait Codec<T> {
+    type Error: error::Error + Send + Sync + 'static;
+
+    /// Append the encoded `value` to `buf`.
+    fn encode(&self, value: &T, buf: &mut Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Restore a value from a record.
+    fn decode(&self, record: &[u8]) -> Result<T, Self::Error>;
+}
+
+/// Records are encoded with `bincode`.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl<T> Codec<T> for Bincode
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Error = bincode::Error;
+
+    fn encode(&self, value: &T, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        bincode::serialize_into(buf, value)
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<T, Self::Error> {
+        bincode::deserialize(record)
+    }
+}
+
+/// Records are encoded with `postcard`.
+#[cfg(feature = "postcard")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl<T> Codec<T> for Postcard
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Error = postcard::Error;
+
+    fn encode(&self, value: &T, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        let bytes = postcard::to_allocvec(value)?;
+        buf.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<T, Self::Error> {
+        postcard::from_bytes(record)
+    }
+}
+
+/// Records are JSON documents.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl<T> Codec<T> for Json
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Error = serde_json::Error;
+
+    fn encode(&self, value: &T, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        serde_json::to_writer(buf, value)
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<T, Self::Error> {
+        serde_json::from_slice(record)
+    }
+}
+
+/// Records are `rkyv` archives. Besides decoding, they can be accessed in
+/// place with `TypedPile::get_archived`.
+#[cfg(feature = "rkyv")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rkyv;
+
+#[cfg(feature = "rkyv")]
+pub(crate) mod rkyv_support {
+    pub use rkyv::{
+        api::high::{HighDeserializer, HighSerializer, HighValidator},
+        bytecheck::CheckBytes,
+        rancor,
+        ser::allocator::ArenaHandle,
+        util::AlignedVec,
+        Archive, Deserialize, Serialize,
+    };
+
+    /// Values that can be written to and read from `rkyv` archives.
+    pub trait Archivable:
+        Archive<
+            Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>
+                          + Deserialize<Self, HighDeserializer<rancor::Error>>,
+        > + for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, rancor::Error>>
+        + Sized
+    {
+    }
+
+    impl<T> Archivable for T where
+        T: Archive<
+                Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>
+                              + Deserialize<T, HighDeserializer<rancor::Error>>,
+            > + for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, rancor::Error>>
+    {
+    }
+
+    /// Whether `record` can be accessed in place.
+    pub fn is_aligned(record: &[u8]) -> bool {
+        (record.as_ptr() as usize).is_multiple_of(AlignedVec::<16>::ALIGNMENT)
+    }
+
+    /// Copy `record` to a buffer with the alignment `rkyv` expects.
+    pub fn aligned(record: &[u8]) -> AlignedVec {
+        let mut buf = AlignedVec::with_capacity(record.len());
+        buf.extend_from_slice(record);
+        buf
+    }
+}
+
+#[cfg(feature = "rkyv")]
+impl<T: rkyv_support::Archivable> Codec<T> for Rkyv {
+    type Error = rkyv::rancor::Error;
+
+    fn encode(&self, value: &T, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+        let bytes = rkyv::to_bytes(value)?;
+        buf.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn decode(&self, record: &[u8]) -> Result<T, Self::Error> {
+        use rkyv_support::{aligned, is_aligned};
+
+        if is_aligned(record) {
+            let archived = rkyv::access::<T::Archived, Self::Error>(record)?;
+            rkyv::deserialize(archived)
+        } else {
+            let record = aligned(record);
+            let archived = rkyv::access::<T::Archived, Self::Error>(&record)?;
+            rkyv::deserialize(archived)
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Helpers for rewriting a database without its deleted records. Old
+//! sequential numbers are mapped to new ones by the `seqno.map` file of the
+//! compacted database: a little-endian `u64` for every old record holding its
+//! new sequential number or `u64::MAX` if the record was dropped.
+
+use crate::{appender::sync_dir, Error};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+pub(crate) const MAP_FILE: &str = "seqno.map";
+const DROPPED: u64 = u64::MAX;
+
+/// Builds the content of the `seqno.map` file.
+#[derive(Default)]
+pub(crate) struct SeqNoMap {
+    data: Vec<u8>,
+}
+
+impl SeqNoMap {
+    pub fn push(&mut self, new_seqno: Option<usize>) {
+        let value = new_seqno.map_or(DROPPED, |seqno| seqno as u64);
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Store the map in the database directory `dir`. The map and the entries
+    /// of the directory are synced, so that they survive a crash.
+    pub fn store(&self, dir: &Path) -> Result<(), Error> {
+        File::create(dir.join(MAP_FILE))
+            .and_then(|mut file| {
+                file.write_all(&self.data)?;
+                file.sync_data()
+            })
+            .map_err(Error::Write)?;
+        sync_dir(dir)
+    }
+}
+
+/// Read the `seqno.map` file of the database at `dir`.
+pub(crate) fn load(dir: &Path) -> Result<Vec<Option<usize>>, Error> {
+    let path = dir.join(MAP_FILE);
+    let data = fs::read(&path).map_err(|err| Error::FileOpen(path, err))?;
+    if data.len() % 8 != 0 {
+        return Err(Error::DataFileDamaged);
+    }
+
+    Ok(data
+        .chunks_exact(8)
+        .map(|value| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(value);
+            match u64::from_le_bytes(bytes) {
+                DROPPED => None,
+                seqno => Some(seqno as usize),
+            }
+        })
+        .collect())
+}
+
+/// Swap the directories `a` and `b`. This is atomic on Linux.
+#[cfg(target_os = "linux")]
+pub(crate) fn exchange(a: &Path, b: &Path) -> Result<(), Error> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| Error::Replace(io::Error::new(io::ErrorKind::InvalidInput, err)))
+    };
+    let (a, b) = (path(a)?, path(b)?);
+
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(Error::Replace(io::Error::last_os_error()))
+    }
+}
+
+/// Swap the directories `a` and `b` with three renames. There is a moment
+/// when `a` does not exist.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn exchange(a: &Path, b: &Path) -> Result<(), Error> {
+    let mut temporary = a.as_os_str().to_owned();
+    temporary.push(".old");
+    let temporary = Path::new(&temporary);
+
+    fs::rename(a, temporary).map_err(Error::Replace)?;
+    if let Err(err) = fs::rename(b, a) {
+        let _ = fs::rename(temporary, a);
+        return Err(Error::Replace(err));
+    }
+    fs::rename(temporary, b).map_err(Error::Replace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exchange, load, SeqNoMap};
+
+    #[test]
+    fn map_and_exchange() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (a, b) = (tmp.path().join("a"), tmp.path().join("b"));
+        std::fs::create_dir(&a).unwrap();
+        std::fs::create_dir(&b).unwrap();
+
+        let mut map = SeqNoMap::default();
+        map.push(Some(0));
+        map.push(None);
+        map.push(Some(1));
+        map.store(&a).unwrap();
+        assert_eq!(vec![Some(0), None, Some(1)], load(&a).unwrap());
+
+        exchange(&a, &b).unwrap();
+        assert!(load(&a).is_err());
+        assert_eq!(3, load(&b).unwrap().len());
+    }
+}
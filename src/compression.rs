@@ -0,0 +1,200 @@
+use crate::Error;
+use std::mem::size_of;
+
+/// The size of the original length stored before compressed data.
+const LENGTH_SIZE: usize = size_of::<u64>();
+/// LZ4 cannot restore more than this many bytes from a compressed byte, so a
+/// larger stored length is damaged.
+#[cfg(feature = "lz4")]
+const LZ4_MAX_RATIO: usize = 255;
+
+/// How records are compressed in the data file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Records are stored as they are.
+    #[default]
+    None,
+    /// LZ4 block compression. Fast, but compresses less. Requires the `lz4`
+    /// feature.
+    Lz4,
+    /// zstd compression with the default level. Requires the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    /// Fail if support for this algorithm was not compiled in.
+    pub(crate) fn check(self) -> Result<(), Error> {
+        match self {
+            Compression::None => Ok(()),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(()),
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => Err(Error::InvalidOptions("the `lz4` feature is disabled")),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(()),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(Error::InvalidOptions("the `zstd` feature is disabled")),
+        }
+    }
+
+    /// Compress `record`. Returns `None` if the record does not get smaller
+    /// and should be stored as it is. The original length is stored before
+    /// the compressed data.
+    pub(crate) fn compress(self, record: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let compressed = match self {
+            Compression::None => return Ok(None),
+            Compression::Lz4 => lz4::compress(record)?,
+            Compression::Zstd => zstd::compress(record)?,
+        };
+
+        if LENGTH_SIZE + compressed.len() >= record.len() {
+            return Ok(None);
+        }
+
+        let mut stored = Vec::with_capacity(LENGTH_SIZE + compressed.len());
+        stored.extend_from_slice(&(record.len() as u64).to_le_bytes());
+        stored.extend_from_slice(&compressed);
+        Ok(Some(stored))
+    }
+
+    /// Restore a record compressed with `compress`. The stored length is not
+    /// trusted: nothing is allocated for it upfront.
+    pub(crate) fn decompress(self, stored: &[u8]) -> Result<Vec<u8>, Error> {
+        if stored.len() < LENGTH_SIZE {
+            return Err(Error::DataFileDamaged);
+        }
+
+        let (len, data) = stored.split_at(LENGTH_SIZE);
+        let mut len_bytes = [0u8; LENGTH_SIZE];
+        len_bytes.copy_from_slice(len);
+        let len = u64::from_le_bytes(len_bytes) as usize;
+
+        let record = match self {
+            Compression::None => None,
+            Compression::Lz4 => lz4::decompress(data, len),
+            Compression::Zstd => zstd::decompress(data, len),
+        };
+
+        match record {
+            Some(record) if record.len() == len => Ok(record),
+            _ => Err(Error::DataFileDamaged),
+        }
+    }
+}
+
+#[cfg(feature = "lz4")]
+mod lz4 {
+    use crate::Error;
+
+    pub fn compress(record: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(lz4_flex::block::compress(record))
+    }
+
+    pub fn decompress(data: &[u8], len: usize) -> Option<Vec<u8>> {
+        if len > data.len().saturating_mul(super::LZ4_MAX_RATIO) {
+            return None;
+        }
+        lz4_flex::block::decompress(data, len).ok()
+    }
+}
+
+#[cfg(feature = "zstd")]
+mod zstd {
+    use crate::Error;
+
+    pub fn compress(record: &[u8]) -> Result<Vec<u8>, Error> {
+        ::zstd::bulk::compress(record, ::zstd::DEFAULT_COMPRESSION_LEVEL).map_err(Error::Compress)
+    }
+
+    pub fn decompress(data: &[u8], len: usize) -> Option<Vec<u8>> {
+        let decoder = ::zstd::stream::read::Decoder::with_buffer(data).ok()?;
+        super::read_at_most(decoder, len)
+    }
+}
+
+/// Read everything from `reader` unless it holds more than `len` bytes. The
+/// buffer only grows as data is read.
+#[cfg(feature = "zstd")]
+pub(crate) fn read_at_most<R: std::io::Read>(reader: R, len: usize) -> Option<Vec<u8>> {
+    use std::io::Read;
+
+    let mut content = Vec::new();
+    reader.take(len as u64 + 1).read_to_end(&mut content).ok()?;
+    (content.len() <= len).then_some(content)
+}
+
+/// Stand-ins for algorithms that are not compiled in. Databases that use them
+/// cannot be opened, so these are never called.
+#[cfg(not(all(feature = "lz4", feature = "zstd")))]
+mod disabled {
+    use crate::Error;
+
+    pub fn compress(_record: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::InvalidOptions("compression algorithm is disabled"))
+    }
+
+    pub fn decompress(_data: &[u8], _len: usize) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+#[cfg(not(feature = "lz4"))]
+use disabled as lz4;
+#[cfg(not(feature = "zstd"))]
+use disabled as zstd;
+
+#[cfg(test)]
+mod tests {
+    use super::Compression;
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn round_trip(compression: Compression, record: &[u8]) {
+        if let Some(stored) = compression.compress(record).unwrap() {
+            assert!(stored.len() < record.len());
+            assert_eq!(record, compression.decompress(&stored).unwrap().as_slice());
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[quickcheck]
+    fn lz4(record: Vec<u8>, repeat: u8) {
+        round_trip(Compression::Lz4, &record);
+        round_trip(Compression::Lz4, &record.repeat(repeat as usize));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[quickcheck]
+    fn zstd(record: Vec<u8>, repeat: u8) {
+        round_trip(Compression::Zstd, &record);
+        round_trip(Compression::Zstd, &record.repeat(repeat as usize));
+    }
+
+    /// A damaged length must not make us allocate that much.
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn damaged_length(compression: Compression) {
+        let record = vec![7u8; 4096];
+        let mut stored = compression.compress(&record).unwrap().unwrap();
+        stored[..8].copy_from_slice(&(1u64 << 60).to_le_bytes());
+        assert!(compression.decompress(&stored).is_err());
+        stored[..8].copy_from_slice(&(record.len() as u64 - 1).to_le_bytes());
+        assert!(compression.decompress(&stored).is_err());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_damaged_length() {
+        damaged_length(Compression::Lz4);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_damaged_length() {
+        damaged_length(Compression::Zstd);
+    }
+
+    #[test]
+    fn none() {
+        assert_eq!(None, Compression::None.compress(&[0; 100]).unwrap());
+        assert!(Compression::None.decompress(&[0; 10]).is_err());
+    }
+}
@@ -1,20 +1,78 @@
-use crate::{flatfile::FlatFile, seqno::SeqNoIndex, Error, SeqNoIter, SharedMmap};
+#[cfg(feature = "dedup")]
+use crate::dedup::{self, Hash, Hashes};
+use crate::{
+    block::{self, Blocks},
+    compaction::{self, SeqNoMap},
+    encryption::Encryption,
+    flatfile::{FlatFile, Frame, Page},
+    layout::Layout,
+    portable::{self, Format},
+    producers::{self, Producers},
+    quota::{self, Quota, QuotaUsage},
+    record_reader::{self, RecordReader},
+    record_run::RecordRun,
+    seqno::SeqNoIndex,
+    tombstones::Tombstones,
+    Compression, Error, Options, SeqNoIter, SharedMmap, Stat,
+};
 use std::{
+    fs,
+    io::{self, BufRead, Read, Write},
+    iter,
+    ops::{Bound, Range, RangeBounds},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
 };
 
 /// Append-only database. Can be safely cloned and used from different threads.
 #[derive(Clone)]
 pub struct Database {
     flatfile: Arc<FlatFile>,
-    seqno_index: Arc<SeqNoIndex>,
+    index: Index,
+    /// Set if records are packed into compressed blocks.
+    blocks: Option<Arc<Blocks>>,
+    /// Deleted records.
+    tombstones: Arc<Tombstones>,
+    /// Overwrite deleted records with zeros.
+    zero_deleted: bool,
+    /// Records before this sequential number were evicted from a capped
+    /// database.
+    evicted: Arc<AtomicUsize>,
+    /// The options the database was opened with.
+    options: Arc<Options>,
+    /// Recent batches written with `append_idempotent`.
+    producers: Arc<Producers>,
+    /// Hashes of records written with `put_dedup`.
+    #[cfg(feature = "dedup")]
+    hashes: Arc<Hashes>,
     write_lock: Arc<Mutex<()>>,
 }
 
+/// Records are copied by `compact_into` in batches of at most this many
+/// records or at least this many bytes.
+const COMPACTION_BATCH_RECORDS: usize = 1024;
+const COMPACTION_BATCH_SIZE: usize = 16 * 1024 * 1024;
+
+/// How records are located in the data file.
+#[derive(Clone)]
+enum Index {
+    SeqNo(Arc<SeqNoIndex>),
+    /// All records have the same size, so there is no need for an index.
+    Fixed(usize),
+}
+
 impl Database {
     /// Open the database. Will create one if not exists.
     pub fn file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::file_with_options(path, Options::default())
+    }
+
+    /// Open the database with the given options. Will create one if not
+    /// exists.
+    pub fn file_with_options<P: AsRef<Path>>(path: P, mut options: Options) -> Result<Self, Error> {
         let path = path.as_ref();
 
         if !path.exists() {
@@ -27,27 +85,272 @@ impl Database {
 
         let flatfile_path = path.join("data");
         let seqno_index_path = path.join("seqno");
+        options.layout = Layout::load_or_store(path, options.layout)?;
+
+        Self::new(Some(flatfile_path), Some(seqno_index_path), options)
+    }
+
+    /// Leave only the first `len` records in the database at `path`. The
+    /// database must not be opened while doing this.
+    pub fn truncate<P: AsRef<Path>>(path: P, len: usize) -> Result<(), Error> {
+        let path = path.as_ref();
+
+        if !path.is_dir() {
+            return Err(Error::PathNotDir);
+        }
+
+        let layout = Layout::load(path)?;
+
+        Producers::truncate(path, len)?;
+
+        if let Some(record_size) = layout.record_size {
+            FlatFile::truncate(&path.join("data"), len * record_size)?;
+            return Tombstones::truncate(path, len);
+        }
+
+        let data_len = match SeqNoIndex::truncate(&path.join("seqno"), layout.index, len)? {
+            Some(value) if layout.block_size.is_some() => {
+                // Keep the whole block if some of its records remain. The
+                // records of the open block are dropped once it is loaded.
+                let (offset, position) = block::locate(value);
+                let data_len = fs::metadata(path.join("data")).map_or(0, |data| data.len());
+                if position == 0 || offset as u64 == data_len {
+                    offset
+                } else {
+                    FlatFile::new(Some(path.join("data")), true, Compression::None, None)?
+                        .get_frame_at_offset(offset)
+                        .map(|frame| offset + frame.stored_len())
+                        .ok_or(Error::DataFileDamaged)?
+                }
+            }
+            Some(value) => value as usize,
+            None => return Ok(()),
+        };
+
+        FlatFile::truncate(&path.join("data"), data_len)?;
+        Tombstones::truncate(path, len)
+    }
+
+    /// Restore the sequential number index of the database at `path` by
+    /// scanning its data file. Only works for databases created with
+    /// [`Options::framed`]. An incomplete record or zeros left by an
+    /// interrupted write at the end of the data file are removed. Fails with
+    /// [`Error::DataFileDamaged`] without changing anything if some other data
+    /// follows the last record that can be read. Returns the number of
+    /// recovered records. The database must not be opened while doing this.
+    pub fn rebuild_index<P: AsRef<Path>>(path: P) -> Result<usize, Error> {
+        let path = path.as_ref();
+
+        if !path.is_dir() {
+            return Err(Error::PathNotDir);
+        }
+
+        let layout = Layout::load(path)?;
+        if !layout.is_framed() {
+            return Err(Error::NotFramed);
+        }
+        // Counting records in a block requires decrypting it.
+        if layout.block_size.is_some() && layout.encryption.is_some() {
+            return Err(Error::InvalidOptions(
+                "cannot rebuild the index of encrypted blocks",
+            ));
+        }
+
+        let flatfile_path = path.join("data");
+        let flatfile = FlatFile::new(Some(flatfile_path.clone()), true, layout.compression, None)?;
+        // Records cannot be found past a hole.
+        if flatfile.has_holes() {
+            return Err(Error::InvalidOptions(
+                "cannot rebuild the index of a data file with holes",
+            ));
+        }
+        let (mut offsets, data_len) = flatfile.frames()?;
+
+        if layout.block_size.is_some() {
+            let mut values = Vec::new();
+            for offset in offsets {
+                let frame = flatfile
+                    .get_frame_at_offset(offset as usize)
+                    .ok_or(Error::DataFileDamaged)?;
+                let count = block::count(&frame.data).ok_or(Error::DataFileDamaged)?;
+                for position in 0..count {
+                    values.push(block::index_value(offset as usize, position)?);
+                }
+            }
+            for position in 0..block::open_records(path, data_len)? {
+                values.push(block::index_value(data_len, position)?);
+            }
+            offsets = values;
+        }
+
+        drop(flatfile);
+        FlatFile::truncate(&flatfile_path, data_len)?;
+
+        let seqno_index_path = path.join("seqno");
+        let new_index_path = path.join("seqno.new");
+        match fs::remove_file(&new_index_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(Error::FileOpen(new_index_path, err));
+            }
+            _ => {}
+        }
+
+        SeqNoIndex::new(Some(new_index_path.clone()), layout.index)?.append(&offsets)?;
+        fs::rename(&new_index_path, &seqno_index_path).map_err(Error::Replace)?;
+        Tombstones::truncate(path, offsets.len())?;
+
+        Ok(offsets.len())
+    }
+
+    /// Atomically replace the database at `path` with the one at `compacted`
+    /// created by [`Database::compact_into`]. Afterwards `compacted` holds the
+    /// old database, which can be removed. The directories are exchanged
+    /// atomically on Linux, elsewhere `path` briefly does not exist. Databases
+    /// opened at `path` keep using the old files and have to be reopened.
+    pub fn swap<P: AsRef<Path>, Q: AsRef<Path>>(path: P, compacted: Q) -> Result<(), Error> {
+        let (path, compacted) = (path.as_ref(), compacted.as_ref());
+        if !path.is_dir() || !compacted.is_dir() {
+            return Err(Error::PathNotDir);
+        }
+
+        compaction::exchange(path, compacted)
+    }
 
-        Self::new(Some(flatfile_path), Some(seqno_index_path))
+    /// Read the mapping of old sequential numbers to new ones stored by
+    /// [`Database::compact_into`] in the database at `path`. Deleted records
+    /// are mapped to `None`.
+    pub fn load_seqno_map<P: AsRef<Path>>(path: P) -> Result<Vec<Option<usize>>, Error> {
+        compaction::load(path.as_ref())
     }
 
     /// Open an in-memory database.
     pub fn memory() -> Result<Self, Error> {
-        Self::new(None, None)
+        Self::memory_with_options(Options::default())
+    }
+
+    /// Open an in-memory database with the given options.
+    pub fn memory_with_options(options: Options) -> Result<Self, Error> {
+        Self::new(None, None, options)
     }
 
     pub(crate) fn new(
         flatfile_path: Option<PathBuf>,
         seqno_index_path: Option<PathBuf>,
+        options: Options,
     ) -> Result<Self, Error> {
-        let flatfile = Arc::new(FlatFile::new(flatfile_path)?);
-        let seqno_index = Arc::new(SeqNoIndex::new(seqno_index_path)?);
+        let layout = options.layout;
+        layout.validate()?;
+
+        let encryption = match (layout.encryption, options.keys.clone()) {
+            (None, None) => None,
+            (None, Some(_)) => {
+                return Err(Error::InvalidOptions(
+                    "keys are given, but the database is not encrypted",
+                ))
+            }
+            (Some(cipher), Some(keys)) => Some(Encryption::new(cipher, keys)?),
+            (Some(_), None) => return Err(Error::NoKey),
+        };
+
+        let dir = flatfile_path
+            .as_ref()
+            .and_then(|path| path.parent())
+            .map(Path::to_path_buf);
+
+        let blocks = match layout.block_size {
+            Some(block_size) => Some(Arc::new(Blocks::new(
+                dir.clone(),
+                block_size,
+                options.block_cache,
+            )?)),
+            None => None,
+        };
+
+        // Blocks are compressed as a whole, not record by record.
+        let compression = match blocks {
+            Some(_) => Compression::None,
+            None => layout.compression,
+        };
+
+        let flatfile = Arc::new(
+            FlatFile::new(flatfile_path, layout.is_framed(), compression, encryption)?
+                .with_quota(options.max_data_size),
+        );
+        let index = match layout.record_size {
+            Some(record_size) => Index::Fixed(record_size),
+            None => Index::SeqNo(Arc::new(
+                SeqNoIndex::new(seqno_index_path, layout.index)?.with_quota(options.max_index_size),
+            )),
+        };
 
         let write_lock = Arc::new(Mutex::new(()));
 
+        if let Some(warning) = &options.quota_warning {
+            if !(1..=100).contains(&warning.percent()) {
+                return Err(Error::InvalidOptions(
+                    "quota warning level must be from 1 to 100 percent",
+                ));
+            }
+        }
+
+        if options.producer_window == 0 {
+            return Err(Error::InvalidOptions("producer window cannot be zero"));
+        }
+        let records = match &index {
+            Index::SeqNo(seqno_index) => seqno_index.size(),
+            Index::Fixed(record_size) => flatfile.len() / record_size,
+        };
+        if let (Some(blocks), Index::SeqNo(seqno_index)) = (&blocks, &index) {
+            // The records of the open block are at the end of the data file.
+            let data_len = flatfile.len();
+            let indexed = (0..records)
+                .rev()
+                .take_while(|seqno| {
+                    seqno_index
+                        .get_pointer_to_value(*seqno)
+                        .is_some_and(|value| block::locate(value).0 == data_len)
+                })
+                .count();
+            blocks.load_open(data_len, indexed, flatfile.encryption())?;
+        }
+        if options.zero_deleted && (dir.is_none() || blocks.is_some()) {
+            return Err(Error::InvalidOptions(
+                "only records in uncompressed data files can be zeroed",
+            ));
+        }
+        let capped = options.capped_records.is_some() || options.capped_bytes.is_some();
+        if options.capped_records == Some(0) || options.capped_bytes == Some(0) {
+            return Err(Error::InvalidOptions("capacity cannot be zero"));
+        }
+        if capped && blocks.is_some() {
+            return Err(Error::InvalidOptions(
+                "records cannot be evicted from compressed blocks",
+            ));
+        }
+        if capped && dir.is_some() && cfg!(not(target_os = "linux")) {
+            return Err(Error::InvalidOptions(
+                "capped databases in files are only supported on Linux",
+            ));
+        }
+        let tombstones = Arc::new(Tombstones::load(dir.clone(), records)?);
+        let evicted = if capped { tombstones.leading() } else { 0 };
+
+        let producers = Arc::new(Producers::new(dir.clone(), options.producer_window));
+
+        #[cfg(feature = "dedup")]
+        let hashes = Arc::new(Hashes::new(dir));
+
         Ok(Database {
             flatfile,
-            seqno_index,
+            index,
+            blocks,
+            tombstones,
+            zero_deleted: options.zero_deleted,
+            evicted: Arc::new(AtomicUsize::new(evicted)),
+            options: Arc::new(options),
+            producers,
+            #[cfg(feature = "dedup")]
+            hashes,
             write_lock,
         })
     }
@@ -65,170 +368,2391 @@ impl Database {
             return Ok(None);
         }
 
-        let _write_guard = self.write_lock.lock().unwrap();
+        let sizes: Vec<_> = records.iter().map(|record| record.len()).collect();
+        self.check_sizes(&sizes)?;
+
+        let _write_guard = self.lock_writes();
+        self.append_locked(records)
+    }
+
+    /// Write an array of records only if the database has `expected_len`
+    /// records, i.e. nobody else wrote to it since it was read. Otherwise
+    /// fails with [`Error::Conflict`] that holds the actual number of records.
+    /// This function will block if another write is still in progress.
+    pub fn append_if_len(
+        &self,
+        expected_len: usize,
+        records: &[&[u8]],
+    ) -> Result<Option<usize>, Error> {
+        let sizes: Vec<_> = records.iter().map(|record| record.len()).collect();
+        self.check_sizes(&sizes)?;
+
+        let _write_guard = self.lock_writes();
+
+        let actual_len = self.len();
+        if actual_len != expected_len {
+            return Err(Error::Conflict { actual_len });
+        }
+
+        if records.is_empty() {
+            return Ok(None);
+        }
+        self.append_locked(records)
+    }
 
-        let initial_size = self.flatfile.len();
+    /// Write a batch of records on behalf of a producer that may retry it.
+    /// Every batch of the producer must have a greater `sequence` than the
+    /// previous one. If the batch with this `sequence` was already written,
+    /// nothing is written again. Returns the sequential numbers of the
+    /// records of the batch. Only a few recent batches of every producer are
+    /// remembered (see [`Options::producer_window`]): retrying an older batch
+    /// fails with [`Error::StaleSequence`]. Producers are remembered in the
+    /// database directory. This function will block if another write is still
+    /// in progress.
+    pub fn append_idempotent(
+        &self,
+        producer_id: u64,
+        sequence: u64,
+        records: &[&[u8]],
+    ) -> Result<Range<usize>, Error> {
+        let sizes: Vec<_> = records.iter().map(|record| record.len()).collect();
+        self.check_sizes(&sizes)?;
 
-        let mut seqno_index_update = Vec::with_capacity(records.len());
-        let mut offset = initial_size;
+        let _write_guard = self.lock_writes();
+        let mut producers = self
+            .producers
+            .lock(self.len(), |written| self.checksum(written))?;
 
-        for record in records.iter() {
-            seqno_index_update.push(offset as u64);
-            offset += record.len();
+        if let Some(written) = producers.applied(producer_id, sequence)? {
+            return Ok(written);
         }
 
-        let seqno = self.seqno_index.append(&seqno_index_update)?;
-        self.flatfile.append(records)?;
+        let start = self.len();
+        let written = start..start + records.len();
+        let checksum = producers::checksum(records.iter().copied());
+        producers.record(producer_id, sequence, written.clone(), checksum)?;
 
-        Ok(seqno)
+        if !records.is_empty() {
+            match self.append_locked(records) {
+                Ok(_) => {}
+                // The records were written.
+                Err(err @ Error::Evict { .. }) => return Err(err),
+                Err(err) => {
+                    // An entry left in the file is dropped when the file is
+                    // read again because its records are not there.
+                    let _ = producers.forget(producer_id);
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(written)
     }
 
-    /// Put a single record (not recommended).
-    pub fn put(&self, record: &[u8]) -> Result<(), Error> {
-        self.append(&[record])
+    /// The checksum of the records in `range` or `None` if some of them are
+    /// deleted.
+    fn checksum(&self, range: Range<usize>) -> Result<Option<u64>, Error> {
+        let mut records = Vec::with_capacity(range.len());
+        for seqno in range {
+            match self.try_get_by_seqno(seqno)? {
+                Some(record) => records.push(record),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(producers::checksum(
+            records.iter().map(|record| record.as_ref()),
+        )))
     }
 
-    /// Get a record by its sequential number.
-    pub fn get_by_seqno(&self, seqno: usize) -> Option<SharedMmap> {
-        let offset = self.seqno_index.get_pointer_to_value(seqno)? as usize;
-        let next_offset = self
-            .seqno_index
-            .get_pointer_to_value(seqno + 1)
-            .map(|value| value as usize)
-            .unwrap_or_else(|| self.flatfile.len());
-        let length = next_offset - offset;
-        self.flatfile.get_record_at_offset(offset, length)
+    /// Block other writes. A write that panicked gives back the space it
+    /// reserved and does not leave anything behind that is visible to
+    /// readers, so the lock is taken even if it is poisoned.
+    fn lock_writes(&self) -> MutexGuard<'_, ()> {
+        self.write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
     }
 
-    /// Iterate records in the order they were added starting form the given
-    /// sequential number.
-    pub fn iter_from_seqno(&self, seqno: usize) -> Option<SeqNoIter> {
-        Some(SeqNoIter::new(
-            self.flatfile.clone(),
-            self.seqno_index.clone(),
-            seqno,
-        ))
+    /// Write a non-empty array of records. Must be called while holding the
+    /// write lock.
+    fn append_locked(&self, records: &[&[u8]]) -> Result<Option<usize>, Error> {
+        self.check_records(records.len())?;
+
+        if let Some(blocks) = &self.blocks {
+            return self.append_blocks(blocks, records);
+        }
+
+        let page = self.flatfile.append(records)?;
+        self.commit(page)
     }
 
-    pub fn last(&self) -> Option<SharedMmap> {
-        self.get_by_seqno(self.len().saturating_sub(1))
+    /// Write records directly into the database without copying them from
+    /// intermediate buffers. Space for records of the given `sizes` is
+    /// reserved and `f` gets a buffer for each of them to fill. If `f`
+    /// succeeds, all records are added at once. If it fails, nothing is added
+    /// and the error is returned. This function will block if another write is
+    /// still in progress.
+    pub fn append_with<F, E>(&self, sizes: &[usize], f: F) -> Result<Option<usize>, E>
+    where
+        F: FnOnce(&mut [&mut [u8]]) -> Result<(), E>,
+        E: From<Error>,
+    {
+        if sizes.is_empty() {
+            return Ok(None);
+        }
+
+        self.check_sizes(sizes)?;
+
+        let _write_guard = self.lock_writes();
+        self.check_records(sizes.len())?;
+
+        if let Some(blocks) = &self.blocks {
+            let mut records: Vec<_> = sizes.iter().map(|size| vec![0u8; *size]).collect();
+            let mut buffers: Vec<_> = records.iter_mut().map(Vec::as_mut_slice).collect();
+            f(&mut buffers)?;
+            let records: Vec<_> = records.iter().map(Vec::as_slice).collect();
+            return Ok(self.append_blocks(blocks, &records)?);
+        }
+
+        let page = self.flatfile.write(sizes, f)?;
+        Ok(self.commit(page)?)
     }
 
-    pub fn len(&self) -> usize {
-        self.seqno_index.size()
+    /// Write a single record read from `reader` without loading it into memory
+    /// first. If the length of the record is known, it should be passed in
+    /// `len_hint` so that the space is reserved at once. Otherwise the space
+    /// grows as the record is read. The record is added only after `reader`
+    /// is exhausted, and it is empty if `reader` has no data. Returns the
+    /// sequential number of the record. This function will block if another
+    /// write is still in progress.
+    pub fn put_from_reader<R: Read>(
+        &self,
+        mut reader: R,
+        len_hint: Option<usize>,
+    ) -> Result<usize, Error> {
+        let _write_guard = self.lock_writes();
+        self.check_records(1)?;
+
+        if let Some(blocks) = &self.blocks {
+            let mut record = Vec::with_capacity(len_hint.unwrap_or(0));
+            reader.read_to_end(&mut record).map_err(Error::Read)?;
+            return Ok(self
+                .append_blocks(blocks, &[&record])?
+                .expect("one record was written"));
+        }
+
+        let (page, len) = self.flatfile.write_from_reader(reader, len_hint)?;
+
+        if let Err(err) = self.check_sizes(&[len]) {
+            self.flatfile.abort(page)?;
+            return Err(err);
+        }
+
+        Ok(self.commit(page)?.expect("one record was written"))
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    fn check_sizes(&self, sizes: &[usize]) -> Result<(), Error> {
+        if let Index::Fixed(record_size) = self.index {
+            for (index, size) in sizes.iter().enumerate() {
+                if *size != record_size {
+                    return Err(Error::RecordSize {
+                        index,
+                        expected: record_size,
+                        actual: *size,
+                    });
+                }
+            }
+        }
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::Database;
+    /// Fail if writing `records` more records would exceed
+    /// [`Options::max_records`]. Must be called while holding the write lock.
+    fn check_records(&self, records: usize) -> Result<(), Error> {
+        quota::check(
+            Quota::Records,
+            self.len() + records,
+            self.options.max_records,
+        )
+    }
 
-    fn read_write(db: Database, data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
-        let records1: Vec<_> = data1
+    /// The usage of every resource that may be limited.
+    fn quota_usage(&self) -> [(Quota, usize, Option<usize>); 3] {
+        let index_size = match &self.index {
+            Index::SeqNo(seqno_index) => seqno_index.memory_size(),
+            Index::Fixed(_) => 0,
+        };
+        [
+            (
+                Quota::DataSize,
+                self.flatfile.len(),
+                self.options.max_data_size,
+            ),
+            (Quota::Records, self.len(), self.options.max_records),
+            (Quota::IndexSize, index_size, self.options.max_index_size),
+        ]
+    }
+
+    /// Pack records into compressed blocks and write them. Must be called
+    /// while holding the write lock.
+    fn append_blocks(&self, blocks: &Blocks, records: &[&[u8]]) -> Result<Option<usize>, Error> {
+        let end = self.flatfile.len();
+        let packed = blocks.pack(end, records)?;
+        let raw: Vec<_> = packed
+            .full
             .iter()
-            .filter(|data| !data.is_empty())
-            .map(|data| data.as_ref())
+            .map(|(block, _)| block.as_slice())
             .collect();
-        let records2: Vec<_> = data2
+        let page = self.flatfile.append(&raw)?;
+
+        // The records of the open block before the write are already in the
+        // index: they stay at the same place, whether their block is full now
+        // or not. The records left in the open block go after the full ones.
+        let counts = packed
+            .full
             .iter()
-            .filter(|data| !data.is_empty())
-            .map(|data| data.as_ref())
-            .collect();
+            .map(|(_, count)| *count)
+            .zip(page.lens.iter().copied())
+            .chain(iter::once((packed.open.len(), 0)));
+        let mut seqno_index_update = Vec::with_capacity(packed.reused + records.len());
+        let mut offset = end;
+        for (count, len) in counts {
+            for position in 0..count {
+                match block::index_value(offset, position) {
+                    Ok(value) => seqno_index_update.push(value),
+                    Err(err) => {
+                        self.flatfile.abort(page)?;
+                        return Err(err);
+                    }
+                }
+            }
+            offset += len;
+        }
 
-        if data1.is_empty() || data2.is_empty() {
-            return;
+        let encryption = self.flatfile.encryption();
+        let result = self.commit_with(page, &seqno_index_update[packed.reused..], || {
+            blocks.open(offset, packed.open, encryption)
+        });
+        match result {
+            Ok(_) => blocks.finish(),
+            Err(_) => blocks.restore(encryption),
         }
+        result
+    }
 
-        db.append(&records1).unwrap();
+    /// Add written records to the index and make them visible. Must be called
+    /// while holding the write lock.
+    fn commit(&self, page: Page) -> Result<Option<usize>, Error> {
+        let mut seqno_index_update = Vec::with_capacity(page.lens.len());
+        if let Index::SeqNo(_) = self.index {
+            let mut offset = self.flatfile.len();
+            for len in page.lens.iter() {
+                seqno_index_update.push(offset as u64);
+                offset += len;
+            }
+        }
 
-        for (i, original_record) in records1.iter().enumerate() {
-            let record = db.get_by_seqno(i).unwrap();
-            assert_eq!(*original_record, record.as_ref());
+        self.commit_with(page, &seqno_index_update, || Ok(()))
+    }
+
+    /// Add the given index values and make written records visible. `prepare`
+    /// is called once the records are stored, before they are added to the
+    /// index. Must be called while holding the write lock.
+    fn commit_with(
+        &self,
+        page: Page,
+        seqno_index_update: &[u64],
+        prepare: impl FnOnce() -> Result<(), Error>,
+    ) -> Result<Option<usize>, Error> {
+        let (len, records) = (self.len(), page.lens.len());
+        // Records are stored before they are added to the index, so that a
+        // failure to write them does not leave index entries behind. Making
+        // them visible afterwards cannot fail.
+        let sealed = self.flatfile.seal(page)?;
+        let before = self.quota_usage();
+
+        // The error that stopped the write is reported even if the records
+        // cannot be discarded either.
+        if let Err(err) = prepare() {
+            let _ = self.flatfile.discard(sealed);
+            return Err(err);
         }
 
-        assert_eq!(*records1.last().unwrap(), db.last().unwrap().as_ref());
-        assert_eq!(records1.len(), db.len());
+        let seqno = match &self.index {
+            Index::SeqNo(seqno_index) => match seqno_index.append(seqno_index_update) {
+                Ok(seqno) => seqno,
+                Err(err) => {
+                    let _ = self.flatfile.discard(sealed);
+                    return Err(err);
+                }
+            },
+            Index::Fixed(_) => Some(self.len()),
+        };
 
-        let iter = db.iter_from_seqno(0).unwrap();
-        let mut count = 0;
+        self.flatfile.publish(sealed);
 
-        for record in iter {
-            assert_eq!(records1[count], record.as_ref());
-            count += 1;
+        // Old records are only evicted once the new ones are written, so that
+        // a failed write does not lose them. If eviction fails, the write is
+        // still done: the records are evicted by the next write.
+        let evicted = self.evict(len, records);
+
+        if let Some(warning) = &self.options.quota_warning {
+            for ((quota, before, _), (_, used, max)) in before.iter().zip(self.quota_usage()) {
+                if let Some(max) = max {
+                    warning.notify(
+                        *before,
+                        QuotaUsage {
+                            quota: *quota,
+                            used,
+                            max,
+                        },
+                    );
+                }
+            }
         }
-        assert_eq!(count, records1.len());
 
-        assert_eq!(
-            db.append_get_seqno(&records2).unwrap().unwrap(),
-            records1.len()
-        );
+        evicted.map_err(|err| Error::Evict {
+            seqno,
+            source: Box::new(err),
+        })?;
+        Ok(seqno)
+    }
 
-        for i in records1.len()..(records1.len() + records2.len()) {
-            let record = db.get_by_seqno(i).unwrap();
-            assert_eq!(records2[i - records1.len()], record.as_ref());
+    /// Evict the oldest records of a capped database after `records` new
+    /// records were written to the database of `len` records, so that it
+    /// fits into its capacity again. The new records are never evicted. Must
+    /// be called while holding the write lock.
+    fn evict(&self, len: usize, records: usize) -> Result<(), Error> {
+        let (max_records, max_bytes) = (self.options.capped_records, self.options.capped_bytes);
+        if max_records.is_none() && max_bytes.is_none() {
+            return Ok(());
         }
 
-        assert_eq!(*records2.last().unwrap(), db.last().unwrap().as_ref());
-        assert_eq!(records1.len() + records2.len(), db.len());
+        let data_len = self.flatfile.len();
+        let first = self.evicted.load(Ordering::Acquire);
+        let mut last = match max_records {
+            Some(max) => (len + records).saturating_sub(max).clamp(first, len),
+            None => first,
+        };
+        if let Some(max) = max_bytes {
+            while last < len && data_len - self.record_offset(last)? > max {
+                last += 1;
+            }
+        }
+
+        if last > first {
+            self.punch_locked(first, last)?;
+            self.evicted.store(last, Ordering::Release);
+        }
+        Ok(())
     }
 
-    #[quickcheck]
-    fn read_write_memory(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
-        let db = Database::memory().unwrap();
-        read_write(db, data1, data2);
+    /// Put a single record (not recommended).
+    pub fn put(&self, record: &[u8]) -> Result<(), Error> {
+        self.append(&[record])
     }
 
-    #[quickcheck]
-    fn read_write_storage(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
-        let tmp = tempfile::tempdir().unwrap();
-        let db = Database::file(tmp.path()).unwrap();
-        read_write(db, data1, data2);
+    /// Write a record unless the same record was already written with this
+    /// function. Records are identified by their BLAKE3 hashes, which are
+    /// stored in the database directory. Returns the sequential number of the
+    /// new or the existing record. This function will block if another write
+    /// is still in progress.
+    #[cfg(feature = "dedup")]
+    pub fn put_dedup(&self, record: &[u8]) -> Result<usize, Error> {
+        let hash = dedup::content_hash(record);
+        let mut hashes = self.hashes.lock()?;
+
+        if let Some(seqno) = hashes.get(&hash) {
+            if self.get_by_seqno(seqno).as_deref() == Some(record) {
+                return Ok(seqno);
+            }
+        }
+
+        let seqno = self
+            .append_get_seqno(&[record])?
+            .expect("one record was written");
+        hashes.insert(hash, seqno)?;
+        Ok(seqno)
     }
 
-    fn parallel_read_write(db: Database, data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
-        let data1: Vec<_> = data1.into_iter().filter(|data| !data.is_empty()).collect();
-        let data2: Vec<_> = data2.into_iter().filter(|data| !data.is_empty()).collect();
+    /// Get a record written with `put_dedup` by its hash, which can be
+    /// computed with [`content_hash`](crate::content_hash).
+    #[cfg(feature = "dedup")]
+    pub fn get_by_hash(&self, hash: &Hash) -> Result<Option<(usize, SharedMmap)>, Error> {
+        let seqno = match self.hashes.lock()?.get(hash) {
+            Some(seqno) => seqno,
+            None => return Ok(None),
+        };
 
-        if data1.is_empty() || data2.is_empty() {
-            return;
+        // The entry is stale if the database was truncated after writing it.
+        Ok(self
+            .get_by_seqno(seqno)
+            .filter(|record| dedup::content_hash(record) == *hash)
+            .map(|record| (seqno, record)))
+    }
+
+    /// Get a record by its sequential number. Returns `None` if there is no
+    /// such record, but also if it cannot be decompressed or decrypted, e.g.
+    /// because it is damaged: use [`Database::try_get_by_seqno`] to tell these
+    /// cases apart.
+    pub fn get_by_seqno(&self, seqno: usize) -> Option<SharedMmap> {
+        self.get_at(seqno, None).map(|(record, _)| record)
+    }
+
+    /// Get a record by its sequential number. Unlike `get_by_seqno`, fails
+    /// if the record cannot be decompressed or decrypted, e.g. because it
+    /// does not pass authentication or its key is unknown.
+    pub fn try_get_by_seqno(&self, seqno: usize) -> Result<Option<SharedMmap>, Error> {
+        self.read_at(seqno, None)
+            .map(|found| found.map(|(record, _)| record))
+    }
+
+    /// Get records by their sequential numbers. Records that follow the
+    /// previous one are found with one index lookup instead of two.
+    pub fn get_many(&self, seqnos: &[usize]) -> Vec<Option<SharedMmap>> {
+        let mut next: Option<(usize, usize)> = None;
+        seqnos
+            .iter()
+            .map(|seqno| {
+                let offset = next
+                    .filter(|(next_seqno, _)| next_seqno == seqno)
+                    .map(|(_, offset)| offset);
+                let found = self.get_at(*seqno, offset);
+                next = found
+                    .as_ref()
+                    .map(|(_, next_offset)| (seqno + 1, *next_offset));
+                found.map(|(record, _)| record)
+            })
+            .collect()
+    }
+
+    /// Get records with sequential numbers within the given range at once.
+    /// If the records are stored one after another in a single memory
+    /// mapping, the run is a view into the data file. This is the case if the
+    /// data file is not framed, none of the records are deleted and they were
+    /// written in one batch or before the database was opened. Otherwise the
+    /// records are copied into a new buffer.
+    pub fn get_range(&self, range: impl RangeBounds<usize>) -> RecordRun {
+        let (start, end) = resolve(range, self.len());
+        let end = end.max(start);
+        if let Some(run) = self.map_range(start, end) {
+            return run;
         }
 
-        let records1: Vec<_> = data1.iter().map(|data| data.as_ref()).collect();
+        let mut data = Vec::new();
+        let mut bounds = Vec::with_capacity(end - start);
+        let mut offset = None;
+        for seqno in start..end {
+            match self.get_at(seqno, offset) {
+                Some((record, next_offset)) => {
+                    bounds.push(Some(data.len()..data.len() + record.len()));
+                    data.extend_from_slice(&record);
+                    offset = Some(next_offset);
+                }
+                None => {
+                    bounds.push(None);
+                    offset = None;
+                }
+            }
+        }
 
-        db.append(&records1).unwrap();
+        RecordRun::new(start, SharedMmap::from(data), bounds, false)
+    }
 
-        let write_db = db.clone();
+    /// Get records from `start` to `end` as a view into the data file if they
+    /// are stored one after another in a single mapping.
+    fn map_range(&self, start: usize, end: usize) -> Option<RecordRun> {
+        if self.flatfile.is_framed() || self.blocks.is_some() {
+            return None;
+        }
 
-        let write_thread = std::thread::spawn(move || {
-            let records2: Vec<_> = data2.iter().map(|data| data.as_ref()).collect();
+        let first = self.record_offset(start).ok()?;
+        let mut bounds = Vec::with_capacity(end - start);
+        let mut offset = first;
+        // Empty records are not stored in any mapping, so the mapping is
+        // only looked up for the first record that is not empty. The walk
+        // stops at its end.
+        let mut mapping = None;
+        for seqno in start..end {
+            if self.tombstones.is_deleted(seqno) {
+                return None;
+            }
+            let next_offset = self.record_offset(seqno + 1).ok()?;
+            if next_offset > offset {
+                let mapping = match &mapping {
+                    Some(mapping) => mapping,
+                    None => mapping.insert(self.flatfile.get_mapping_at_offset(first)?),
+                };
+                if next_offset - first > mapping.len() {
+                    return None;
+                }
+            }
+            bounds.push(Some(offset - first..next_offset - first));
+            offset = next_offset;
+        }
 
-            write_db.append(&records2).unwrap();
+        Some(match mapping {
+            Some(mapping) => RecordRun::new(start, mapping.slice(..offset - first), bounds, true),
+            None => RecordRun::new(start, SharedMmap::from(Vec::new()), bounds, false),
+        })
+    }
 
-            data2
-        });
+    /// Get a record as it is stored in the data file, i.e. without
+    /// decompressing or decrypting it. For databases without compression or
+    /// encryption this is the same as `get_by_seqno`. With block compression
+    /// this is the whole block the record belongs to, or the record itself if
+    /// its block is not full yet.
+    pub fn get_raw_by_seqno(&self, seqno: usize) -> Option<SharedMmap> {
+        if self.tombstones.is_deleted(seqno) {
+            return None;
+        }
+        if let Some(blocks) = &self.blocks {
+            let (offset, position) = block::locate(self.index_value(seqno)?);
+            if let Some(block) = blocks.unwritten(offset) {
+                return block.get(position);
+            }
+        }
+        self.locate(seqno, None).map(|(frame, _)| frame.data)
+    }
 
-        for (i, original_record) in records1.iter().enumerate() {
-            let record = db.get_by_seqno(i).unwrap();
-            assert_eq!(*original_record, record.as_ref());
+    /// Mark the record with the given sequential number as deleted. It is
+    /// not returned by `get_by_seqno` and is skipped by iterators, while other
+    /// records keep their sequential numbers. The content of the record stays
+    /// in the data file unless [`Options::zero_deleted`] is set. While
+    /// records read from the same part of the data file are held, zeroing is
+    /// deferred: it is done by a later deletion or when the database is
+    /// opened again. Deletions are stored in the database directory. Returns `false` if there is no
+    /// such record or it was already deleted. This function will block if a
+    /// write or [`Database::compact_into`] is in progress.
+    pub fn delete(&self, seqno: usize) -> Result<bool, Error> {
+        let _write_guard = self.lock_writes();
+
+        if seqno >= self.len() || self.tombstones.is_deleted(seqno) {
+            return Ok(false);
         }
 
-        let data2 = write_thread.join().unwrap();
+        // The record is located before it is deleted, because deleted records
+        // are not found.
+        let zeroed = match self.zero_deleted {
+            true => self.locate(seqno, None),
+            false => None,
+        };
+        let deleted = self.tombstones.delete(seqno)?;
+        if let Some((frame, next_offset)) = zeroed {
+            let len = frame.data.len();
+            drop(frame);
+            self.flatfile.zero(next_offset - len, len)?;
+        }
+        Ok(deleted)
+    }
 
-        for i in data1.len()..(data1.len() + data2.len()) {
-            let record = db.get_by_seqno(i).unwrap();
-            let i = i - data1.len();
-            assert_eq!(data2[i], record.as_ref());
+    /// Copy records that are not deleted into a new database at `path`, which
+    /// must not exist. The new database stores records the same way and uses
+    /// the same keys, other options are the defaults. It has sequential
+    /// numbers without gaps. How old sequential numbers map to new ones is
+    /// stored in its `seqno.map` file, see [`Database::load_seqno_map`].
+    /// Records written with `put_dedup` and `append_idempotent` are not
+    /// remembered by the new database. Writes and deletions are blocked while
+    /// copying, reads are not. If copying fails, `path` is removed. Returns
+    /// the number of copied records. Use [`Database::swap`] to replace this
+    /// database with the new one.
+    pub fn compact_into<P: AsRef<Path>>(&self, path: P) -> Result<usize, Error> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(Error::FileOpen(
+                path.to_path_buf(),
+                io::ErrorKind::AlreadyExists.into(),
+            ));
+        }
+
+        let _write_guard = self.lock_writes();
+
+        let copied = self.copy_into(path);
+        if copied.is_err() {
+            // The path did not exist, so everything there was created by the
+            // copy.
+            let _ = fs::remove_dir_all(path);
         }
+        copied
     }
 
-    #[quickcheck]
-    fn parallel_read_write_memory(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
-        let db = Database::memory().unwrap();
-        parallel_read_write(db, data1, data2);
+    /// Copy records for `compact_into`. Must be called while holding the
+    /// write lock.
+    fn copy_into(&self, path: &Path) -> Result<usize, Error> {
+        let options = Options {
+            layout: self.options.layout,
+            keys: self.options.keys.clone(),
+            ..Options::default()
+        };
+        let compacted = Database::file_with_options(path, options)?;
+
+        let mut map = SeqNoMap::default();
+        let mut batch = Vec::new();
+        let mut batch_size = 0;
+        for seqno in 0..self.len() {
+            let record = match self.try_get_by_seqno(seqno)? {
+                Some(record) => record,
+                None => {
+                    map.push(None);
+                    continue;
+                }
+            };
+
+            map.push(Some(compacted.len() + batch.len()));
+            batch_size += record.len();
+            batch.push(record);
+
+            if batch.len() == COMPACTION_BATCH_RECORDS || batch_size >= COMPACTION_BATCH_SIZE {
+                let records: Vec<&[u8]> = batch.iter().map(|record| record.as_ref()).collect();
+                compacted.append(&records)?;
+                batch.clear();
+                batch_size = 0;
+            }
+        }
+
+        let records: Vec<&[u8]> = batch.iter().map(|record| record.as_ref()).collect();
+        compacted.append(&records)?;
+        // Appended records are already synced, the new files are synced to
+        // the directory along with the map.
+        map.store(path)?;
+
+        Ok(compacted.len())
+    }
+
+    /// Delete records with sequential numbers in `range` and give the space
+    /// they take in the data file back to the filesystem with
+    /// `fallocate(FALLOC_FL_PUNCH_HOLE)`. The size of the data file and the
+    /// sequential numbers of other records do not change, so this can be used
+    /// to drop old records from the front of the database. Only works for
+    /// databases in files on Linux and in-memory databases, which free the
+    /// memory instead, without block compression. Fails without deleting
+    /// anything if the filesystem cannot punch holes. The index of a data file
+    /// with holes cannot be rebuilt. Records read earlier do not
+    /// change: while records read from the same part of the data file are
+    /// held, the space is given back by a later call. Returns the number of
+    /// bytes to be given back. This function will block if a write is in
+    /// progress.
+    pub fn punch(&self, range: impl RangeBounds<usize>) -> Result<usize, Error> {
+        if self.blocks.is_some() {
+            return Err(Error::InvalidOptions(
+                "holes cannot be punched in compressed blocks",
+            ));
+        }
+
+        let _write_guard = self.lock_writes();
+
+        let (start, end) = resolve(range, self.len());
+        if start >= end {
+            return Ok(0);
+        }
+
+        self.punch_locked(start, end)
+    }
+
+    /// Delete records with sequential numbers from `start` to `end` and give
+    /// back their space. Must be called while holding the write lock.
+    fn punch_locked(&self, start: usize, end: usize) -> Result<usize, Error> {
+        let (first, last) = (self.record_offset(start)?, self.record_offset(end)?);
+
+        // Nothing is changed if holes cannot be punched.
+        if last > first {
+            self.flatfile.check_punch()?;
+        }
+        self.tombstones.delete_range(start..end)?;
+        // Empty records take no space, and a hole of no bytes is rejected.
+        if last > first {
+            self.flatfile.punch(first, last - first)?;
+        }
+        Ok(last - first)
+    }
+
+    /// Where the record with the given sequential number starts in the data
+    /// file. The end of the data file for the next record to be written.
+    fn record_offset(&self, seqno: usize) -> Result<usize, Error> {
+        // Read before the length, see `locate`.
+        let data_len = self.flatfile.len();
+        if seqno == self.len() {
+            return Ok(data_len);
+        }
+        match &self.index {
+            Index::SeqNo(seqno_index) => seqno_index
+                .get_pointer_to_value(seqno)
+                .map(|value| value as usize)
+                .ok_or(Error::SeqNoIndexDamaged),
+            Index::Fixed(record_size) => Ok(seqno * record_size),
+        }
+    }
+
+    /// Whether the record with the given sequential number was deleted.
+    pub fn is_deleted(&self, seqno: usize) -> bool {
+        self.tombstones.is_deleted(seqno)
+    }
+
+    /// Get a record by its sequential number. If the location of the record
+    /// is already known, it can be provided in `offset` to skip the index
+    /// lookup. Returns the record and the location of the next record.
+    /// Records that fail to decompress or decrypt are treated as missing:
+    /// `verify` and `try_get_by_seqno` report them.
+    pub(crate) fn get_at(
+        &self,
+        seqno: usize,
+        offset: Option<usize>,
+    ) -> Option<(SharedMmap, usize)> {
+        self.read_at(seqno, offset).ok().flatten()
+    }
+
+    /// Like `get_at`, but fails if the record cannot be decoded.
+    pub(crate) fn read_at(
+        &self,
+        seqno: usize,
+        offset: Option<usize>,
+    ) -> Result<Option<(SharedMmap, usize)>, Error> {
+        if self.tombstones.is_deleted(seqno) {
+            return Ok(None);
+        }
+
+        if let Some(blocks) = &self.blocks {
+            return self.get_from_block(blocks, seqno);
+        }
+
+        match self.locate(seqno, offset) {
+            Some((frame, next_offset)) => Ok(Some((self.flatfile.decode(frame)?, next_offset))),
+            None => Ok(None),
+        }
+    }
+
+    /// Get a record from a compressed block. The location of the next record
+    /// is not returned because it is not worth caching: the index is always
+    /// consulted.
+    fn get_from_block(
+        &self,
+        blocks: &Blocks,
+        seqno: usize,
+    ) -> Result<Option<(SharedMmap, usize)>, Error> {
+        let (offset, position) = match self.index_value(seqno) {
+            Some(value) => block::locate(value),
+            None => return Ok(None),
+        };
+        let block = blocks.get(offset, || self.load_block(offset))?;
+        Ok(block.get(position).map(|record| (record, 0)))
+    }
+
+    /// Read the block stored at `offset` and decrypt it if needed.
+    fn load_block(&self, offset: usize) -> Result<SharedMmap, Error> {
+        let frame = self
+            .flatfile
+            .get_frame_at_offset(offset)
+            .ok_or(Error::DataFileDamaged)?;
+        self.flatfile.decode(frame)
+    }
+
+    fn index_value(&self, seqno: usize) -> Option<u64> {
+        match &self.index {
+            Index::SeqNo(seqno_index) => seqno_index.get_pointer_to_value(seqno),
+            Index::Fixed(_) => None,
+        }
+    }
+
+    /// Find the stored record with the given sequential number and the
+    /// location of the next record.
+    fn locate(&self, seqno: usize, offset: Option<usize>) -> Option<(Frame, usize)> {
+        let seqno_index = match &self.index {
+            Index::SeqNo(seqno_index) => seqno_index,
+            Index::Fixed(record_size) => {
+                let record_size = *record_size;
+                let offset = seqno.checked_mul(record_size)?;
+                let record = self.flatfile.get_record_at_offset(offset, record_size)?;
+                return Some((Frame::raw(record, offset), offset + record_size));
+            }
+        };
+        // An empty record would be found at the end of the data file.
+        if seqno >= seqno_index.size() {
+            return None;
+        }
+
+        let offset = match offset {
+            Some(offset) => offset,
+            None if self.blocks.is_some() => {
+                block::locate(seqno_index.get_pointer_to_value(seqno)?).0
+            }
+            None => seqno_index.get_pointer_to_value(seqno)? as usize,
+        };
+
+        if self.flatfile.is_framed() {
+            let frame = self.flatfile.get_frame_at_offset(offset)?;
+            let next_offset = offset + frame.stored_len();
+            return Some((frame, next_offset));
+        }
+
+        // The data length is read first: records are added to the index
+        // before they are published, so a record missing from the index is
+        // not counted in the length either.
+        let data_len = self.flatfile.len();
+        let next_offset = seqno_index
+            .get_pointer_to_value(seqno + 1)
+            .map_or(data_len, |value| value as usize);
+        let length = next_offset.checked_sub(offset)?;
+        let record = self.flatfile.get_record_at_offset(offset, length)?;
+        Some((Frame::raw(record, offset), next_offset))
+    }
+
+    /// Get a reader over a record by its sequential number.
+    pub fn reader(&self, seqno: usize) -> Option<RecordReader> {
+        self.get_by_seqno(seqno).map(RecordReader::new)
+    }
+
+    /// Write a record to `out`, which is usually a file or a socket. On Linux
+    /// the kernel copies the data from the data file with `copy_file_range` or
+    /// `sendfile`, so that the record is not faulted into memory. Returns the
+    /// length of the record or `None` if there is no such record.
+    #[cfg(unix)]
+    pub fn send_record<W>(&self, seqno: usize, out: &mut W) -> Result<Option<usize>, Error>
+    where
+        W: Write + std::os::unix::io::AsRawFd,
+    {
+        if self.blocks.is_some() || self.tombstones.is_deleted(seqno) {
+            let record = match self.get_by_seqno(seqno) {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+            record_reader::send(None, 0, &record, out)?;
+            return Ok(Some(record.len()));
+        }
+
+        let (frame, next_offset) = match self.locate(seqno, None) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        // Compressed and encrypted records have to go through memory.
+        let file = if self.flatfile.is_plain(&frame) {
+            self.flatfile.file()
+        } else {
+            None
+        };
+        let offset = next_offset - frame.data.len();
+        let record = self.flatfile.decode(frame)?;
+        record_reader::send(file, offset, &record, out)?;
+
+        Ok(Some(record.len()))
+    }
+
+    /// Iterate records in the order they were added starting form the given
+    /// sequential number.
+    pub fn iter_from_seqno(&self, seqno: usize) -> Option<SeqNoIter> {
+        Some(SeqNoIter::new(self.clone(), seqno, usize::MAX))
+    }
+
+    /// Iterate records with sequential numbers within the given range.
+    pub fn iter_range(&self, range: impl RangeBounds<usize>) -> SeqNoIter {
+        let start = match range.start_bound() {
+            Bound::Included(start) => *start,
+            Bound::Excluded(start) => start.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => end.saturating_add(1),
+            Bound::Excluded(end) => *end,
+            Bound::Unbounded => usize::MAX,
+        };
+        SeqNoIter::new(self.clone(), start, end)
+    }
+
+    /// Write records within the given range of sequential numbers to `writer`
+    /// in a portable format. Returns the number of exported records. Fails
+    /// if a record cannot be decompressed or decrypted.
+    pub fn export<W: Write>(
+        &self,
+        range: impl RangeBounds<usize>,
+        format: Format,
+        writer: W,
+    ) -> Result<usize, Error> {
+        portable::export(self.iter_range(range), format, writer)
+    }
+
+    /// Load records previously exported with [`Database::export`]. Records
+    /// are appended in batches of `batch_size` records and get new sequential
+    /// numbers. Returns the number of imported records. Malformed records are
+    /// reported with [`Error::ImportFormat`], which tells the number of the
+    /// record in the stream counting from zero. Blank lines of JSON lines
+    /// streams are skipped and not counted.
+    pub fn import<R: BufRead>(
+        &self,
+        reader: R,
+        format: Format,
+        batch_size: usize,
+    ) -> Result<usize, Error> {
+        portable::import(self, reader, format, batch_size)
+    }
+
+    pub fn last(&self) -> Option<SharedMmap> {
+        self.get_by_seqno(self.len().saturating_sub(1))
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.index {
+            Index::SeqNo(seqno_index) => seqno_index.size(),
+            Index::Fixed(record_size) => self.flatfile.len() / record_size,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the database statistics.
+    pub fn stat(&self) -> Stat {
+        let (index_size, index_mappings) = match &self.index {
+            Index::SeqNo(seqno_index) => (seqno_index.memory_size(), seqno_index.mappings()),
+            Index::Fixed(_) => (0, 0),
+        };
+
+        Stat {
+            records: self.len(),
+            deleted: self.tombstones.count(),
+            data_size: self.flatfile.len(),
+            index_size,
+            mappings: self.flatfile.mappings() + index_mappings,
+        }
+    }
+
+    /// Check that every record in the index points inside the data file,
+    /// that records do not overlap and that compressed records can be
+    /// decompressed.
+    pub fn verify(&self) -> Result<(), Error> {
+        let data_len = self.flatfile.len();
+        let mut previous = None;
+
+        let seqno_index = match &self.index {
+            Index::SeqNo(seqno_index) => seqno_index,
+            Index::Fixed(record_size) if data_len.is_multiple_of(*record_size) => return Ok(()),
+            Index::Fixed(_) => return Err(Error::DataFileDamaged),
+        };
+
+        if let Some(blocks) = &self.blocks {
+            return self.verify_blocks(blocks, seqno_index);
+        }
+
+        for seqno in 0..self.len() {
+            let offset = seqno_index
+                .get_pointer_to_value(seqno)
+                .ok_or(Error::SeqNoIndexDamaged)? as usize;
+
+            // Empty records start where the next record does.
+            let valid = match previous {
+                Some(previous) => previous <= offset,
+                None => offset == 0,
+            };
+            // Deleted records may be zeroed or punched out.
+            if self.flatfile.is_framed() && !self.tombstones.is_deleted(seqno) {
+                let frame = self
+                    .flatfile
+                    .get_frame_at_offset(offset)
+                    .ok_or(Error::DataFileDamaged)?;
+                let end = offset + frame.stored_len();
+                self.flatfile.decode(frame)?;
+                let next = seqno_index
+                    .get_pointer_to_value(seqno + 1)
+                    .map(|value| value as usize)
+                    .unwrap_or(data_len);
+                if end != next {
+                    return Err(Error::DataFileDamaged);
+                }
+            }
+            if !valid || offset > data_len || (offset == data_len && self.flatfile.is_framed()) {
+                return Err(Error::SeqNoIndexDamaged);
+            }
+
+            previous = Some(offset);
+        }
+
+        if previous.is_none() && data_len != 0 {
+            return Err(Error::DataFileDamaged);
+        }
+
+        Ok(())
+    }
+
+    /// Check that records in the index are located in consecutive blocks
+    /// that can be decompressed. The last block may hold more records than
+    /// the index if the database was truncated. The records of the open block
+    /// follow the last one.
+    fn verify_blocks(&self, blocks: &Blocks, seqno_index: &SeqNoIndex) -> Result<(), Error> {
+        let data_len = self.flatfile.len();
+        // The offset, the end and the number of records of the current block
+        // and the position of the previous record in it.
+        let mut current: Option<(usize, usize, usize, usize)> = None;
+
+        for seqno in 0..self.len() {
+            let value = seqno_index
+                .get_pointer_to_value(seqno)
+                .ok_or(Error::SeqNoIndexDamaged)?;
+            let (offset, position) = block::locate(value);
+
+            let (expected, next_block) = match current {
+                Some((block, _, _, previous)) if block == offset => (previous + 1, block),
+                Some((_, end, _, _)) => (0, end),
+                None => (0, 0),
+            };
+            if position != expected || offset != next_block {
+                return Err(Error::SeqNoIndexDamaged);
+            }
+
+            let (end, count) = match current {
+                Some((_, end, count, _)) if position > 0 => (end, count),
+                _ if offset == data_len => (
+                    data_len,
+                    blocks.unwritten(offset).map_or(0, |block| block.len()),
+                ),
+                _ => {
+                    let frame = self
+                        .flatfile
+                        .get_frame_at_offset(offset)
+                        .ok_or(Error::DataFileDamaged)?;
+                    let end = offset + frame.stored_len();
+                    (
+                        end,
+                        blocks.get(offset, || self.flatfile.decode(frame))?.len(),
+                    )
+                }
+            };
+            if position >= count {
+                return Err(Error::SeqNoIndexDamaged);
+            }
+
+            current = Some((offset, end, count, position));
+        }
+
+        match current {
+            Some((_, end, _, _)) if end == data_len => Ok(()),
+            None if data_len == 0 => Ok(()),
+            _ => Err(Error::DataFileDamaged),
+        }
+    }
+
+    /// Train a zstd dictionary of at most `max_size` bytes on a sample of the
+    /// records in the database and use it to compress new blocks. Only works
+    /// for databases with [`Options::block_compression`]. The dictionary is
+    /// stored in the database directory. Blocks written earlier keep using
+    /// the dictionaries they were compressed with. Returns the id of the new
+    /// dictionary. This function will block if a write is in progress.
+    pub fn train_dictionary(&self, max_size: usize) -> Result<usize, Error> {
+        let blocks = self.blocks.as_ref().ok_or(Error::InvalidOptions(
+            "dictionaries require block compression",
+        ))?;
+
+        let _write_guard = self.lock_writes();
+
+        let samples: Vec<_> = block::sample_seqnos(self.len())
+            .filter_map(|seqno| self.get_by_seqno(seqno))
+            .collect();
+        blocks.train(&samples, max_size)
+    }
+}
+
+/// The sequential numbers from the start to the end of `range` limited to
+/// `len`, the number of records.
+fn resolve(range: impl RangeBounds<usize>, len: usize) -> (usize, usize) {
+    let start = match range.start_bound() {
+        Bound::Included(start) => *start,
+        Bound::Excluded(start) => start.saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(end) => end.saturating_add(1),
+        Bound::Excluded(end) => *end,
+        Bound::Unbounded => len,
+    }
+    .min(len);
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Database;
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    use crate::Compression;
+    use crate::{Error, IndexEncoding, Options, Quota, QuotaUsage};
+    use std::sync::{Arc, Mutex};
+
+    fn read_write(db: Database, data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        let records1: Vec<_> = data1.iter().map(|data| data.as_ref()).collect();
+        let records2: Vec<_> = data2.iter().map(|data| data.as_ref()).collect();
+
+        if records1.is_empty() || records2.is_empty() {
+            return;
+        }
+
+        db.append(&records1).unwrap();
+
+        for (i, original_record) in records1.iter().enumerate() {
+            let record = db.get_by_seqno(i).unwrap();
+            assert_eq!(*original_record, record.as_ref());
+        }
+
+        assert_eq!(*records1.last().unwrap(), db.last().unwrap().as_ref());
+        assert_eq!(records1.len(), db.len());
+
+        let iter = db.iter_from_seqno(0).unwrap();
+        let mut count = 0;
+
+        for record in iter {
+            assert_eq!(records1[count], record.as_ref());
+            count += 1;
+        }
+        assert_eq!(count, records1.len());
+
+        assert_eq!(
+            db.append_get_seqno(&records2).unwrap().unwrap(),
+            records1.len()
+        );
+
+        for i in records1.len()..(records1.len() + records2.len()) {
+            let record = db.get_by_seqno(i).unwrap();
+            assert_eq!(records2[i - records1.len()], record.as_ref());
+        }
+
+        assert_eq!(*records2.last().unwrap(), db.last().unwrap().as_ref());
+        assert_eq!(records1.len() + records2.len(), db.len());
+    }
+
+    #[quickcheck]
+    fn read_write_memory(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        let db = Database::memory().unwrap();
+        read_write(db, data1, data2);
+    }
+
+    #[quickcheck]
+    fn read_write_storage(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::file(tmp.path()).unwrap();
+        read_write(db, data1, data2);
+    }
+
+    fn parallel_read_write(db: Database, data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        if data1.is_empty() || data2.is_empty() {
+            return;
+        }
+
+        let records1: Vec<_> = data1.iter().map(|data| data.as_ref()).collect();
+
+        db.append(&records1).unwrap();
+
+        let write_db = db.clone();
+
+        let write_thread = std::thread::spawn(move || {
+            let records2: Vec<_> = data2.iter().map(|data| data.as_ref()).collect();
+
+            write_db.append(&records2).unwrap();
+
+            data2
+        });
+
+        for (i, original_record) in records1.iter().enumerate() {
+            let record = db.get_by_seqno(i).unwrap();
+            assert_eq!(*original_record, record.as_ref());
+        }
+
+        let data2 = write_thread.join().unwrap();
+
+        for i in data1.len()..(data1.len() + data2.len()) {
+            let record = db.get_by_seqno(i).unwrap();
+            let i = i - data1.len();
+            assert_eq!(data2[i], record.as_ref());
+        }
+    }
+
+    #[quickcheck]
+    fn parallel_read_write_memory(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        let db = Database::memory().unwrap();
+        parallel_read_write(db, data1, data2);
+    }
+
+    #[test]
+    fn truncate() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        {
+            let db = Database::file(tmp.path()).unwrap();
+            db.append(&[b"first", b"second", b"third"]).unwrap();
+            db.verify().unwrap();
+        }
+
+        Database::truncate(tmp.path(), 2).unwrap();
+
+        let db = Database::file(tmp.path()).unwrap();
+        db.verify().unwrap();
+        assert_eq!(2, db.len());
+        assert_eq!(b"second", db.last().unwrap().as_ref());
+        assert_eq!(11, db.stat().data_size);
+    }
+
+    #[quickcheck]
+    fn read_write_framed(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let options = Options::new().framed(true);
+        let db = Database::file_with_options(tmp.path(), options).unwrap();
+        read_write(db, data1, data2);
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn compressions() -> Vec<Compression> {
+        vec![
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ]
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[quickcheck]
+    fn read_write_compressed(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        for compression in compressions() {
+            let options = Options::new().compression(compression);
+            let db = Database::memory_with_options(options).unwrap();
+            read_write(db, data1.clone(), data2.clone());
+        }
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn compressed() {
+        for compression in compressions() {
+            let tmp = tempfile::tempdir().unwrap();
+            let json = br#"{"key": "value", "key": "value", "key": "value"}"#;
+            let record = json.repeat(100);
+
+            {
+                let options = Options::new().compression(compression);
+                let db = Database::file_with_options(tmp.path(), options).unwrap();
+                db.append(&[&record, b"short"]).unwrap();
+                db.put_from_reader(&record[..], None).unwrap();
+                db.append_with(&[record.len()], |buffers| {
+                    buffers[0].copy_from_slice(&record);
+                    Ok::<(), Error>(())
+                })
+                .unwrap();
+                assert!(db.stat().data_size < record.len());
+            }
+
+            std::fs::remove_file(tmp.path().join("seqno")).unwrap();
+            assert_eq!(4, Database::rebuild_index(tmp.path()).unwrap());
+
+            let db = Database::file(tmp.path()).unwrap();
+            db.verify().unwrap();
+            for seqno in [0, 2, 3].iter() {
+                assert_eq!(record, db.get_by_seqno(*seqno).unwrap().as_ref());
+                assert!(db.get_raw_by_seqno(*seqno).unwrap().len() < record.len());
+            }
+            assert_eq!(b"short", db.get_by_seqno(1).unwrap().as_ref());
+            assert_eq!(b"short", db.get_raw_by_seqno(1).unwrap().as_ref());
+
+            let records: Vec<_> = db.iter_range(..).collect();
+            assert_eq!(4, records.len());
+            assert_eq!(record, records[3].as_ref());
+
+            #[cfg(unix)]
+            {
+                use std::io::{Read, Seek, SeekFrom};
+
+                let mut out = tempfile::tempfile().unwrap();
+                assert_eq!(Some(record.len()), db.send_record(0, &mut out).unwrap());
+                assert_eq!(Some(5), db.send_record(1, &mut out).unwrap());
+
+                let mut content = Vec::new();
+                out.seek(SeekFrom::Start(0)).unwrap();
+                out.read_to_end(&mut content).unwrap();
+                assert_eq!([&record[..], b"short"].concat(), content);
+            }
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[quickcheck]
+    fn read_write_blocks(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        let options = Options::new().block_compression(64).block_cache(2);
+        let db = Database::memory_with_options(options).unwrap();
+        read_write(db, data1, data2);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn blocks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let records: Vec<_> = (0..1000)
+            .map(|i| format!(r#"{{"id": {}, "name": "user {}", "active": true}}"#, i, i))
+            .collect();
+        let records: Vec<&[u8]> = records.iter().map(|record| record.as_bytes()).collect();
+        let (first, second) = records.split_at(500);
+
+        {
+            let options = Options::new().block_compression(4096);
+            let db = Database::file_with_options(tmp.path(), options).unwrap();
+            db.append(first).unwrap();
+            assert_eq!(1, db.train_dictionary(1024).unwrap());
+            for record in second.chunks(50) {
+                db.append(record).unwrap();
+            }
+            db.verify().unwrap();
+            assert!(db.stat().data_size < records.iter().map(|r| r.len()).sum::<usize>() / 4);
+        }
+
+        std::fs::remove_file(tmp.path().join("seqno")).unwrap();
+        assert_eq!(1000, Database::rebuild_index(tmp.path()).unwrap());
+
+        let db = Database::file(tmp.path()).unwrap();
+        db.verify().unwrap();
+        assert_eq!(records[999], db.last().unwrap().as_ref());
+        assert_eq!(records[123], db.get_by_seqno(123).unwrap().as_ref());
+        assert_eq!(records[777], db.get_by_seqno(777).unwrap().as_ref());
+        let read: Vec<_> = db.iter_range(490..510).map(|r| r.to_vec()).collect();
+        assert_eq!(&records[490..510], read.as_slice());
+
+        db.put_from_reader(&b"from reader"[..], None).unwrap();
+        assert_eq!(b"from reader", db.last().unwrap().as_ref());
+        assert_eq!(1001, db.put_from_reader(&[][..], None).unwrap());
+        assert!(db.last().unwrap().is_empty());
+        drop(db);
+
+        Database::truncate(tmp.path(), 510).unwrap();
+        let db = Database::file(tmp.path()).unwrap();
+        db.verify().unwrap();
+        assert_eq!(510, db.len());
+        db.put(b"after truncate").unwrap();
+        db.verify().unwrap();
+        assert_eq!(b"after truncate", db.get_by_seqno(510).unwrap().as_ref());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn blocks_across_writes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let records: Vec<_> = (0..1000)
+            .map(|i| format!(r#"{{"id": {}, "name": "user {}", "active": true}}"#, i, i))
+            .collect();
+        let size: usize = records.iter().map(|record| record.len()).sum();
+
+        {
+            let options = Options::new().block_compression(4096);
+            let db = Database::file_with_options(tmp.path(), options).unwrap();
+            for record in &records {
+                db.put(record.as_bytes()).unwrap();
+            }
+            db.verify().unwrap();
+            assert!(db.stat().data_size < size / 4);
+            // The last records are not written to the data file yet.
+            assert_eq!(
+                records[999].as_bytes(),
+                db.get_raw_by_seqno(999).unwrap().as_ref()
+            );
+        }
+
+        let db = Database::file(tmp.path()).unwrap();
+        db.verify().unwrap();
+        let read: Vec<_> = db.iter_range(..).map(|record| record.to_vec()).collect();
+        assert_eq!(records.len(), read.len());
+        for (record, read) in records.iter().zip(read) {
+            assert_eq!(record.as_bytes(), read.as_slice());
+        }
+        drop(db);
+
+        // Cut into the open block.
+        Database::truncate(tmp.path(), 999).unwrap();
+        let db = Database::file(tmp.path()).unwrap();
+        db.verify().unwrap();
+        assert_eq!(999, db.len());
+        db.put(b"after truncate").unwrap();
+        db.verify().unwrap();
+        assert_eq!(b"after truncate", db.get_by_seqno(999).unwrap().as_ref());
+        drop(db);
+
+        std::fs::remove_file(tmp.path().join("seqno")).unwrap();
+        assert_eq!(1000, Database::rebuild_index(tmp.path()).unwrap());
+        let db = Database::file(tmp.path()).unwrap();
+        db.verify().unwrap();
+        assert_eq!(
+            records[998].as_bytes(),
+            db.get_by_seqno(998).unwrap().as_ref()
+        );
+        assert_eq!(b"after truncate", db.last().unwrap().as_ref());
+    }
+
+    #[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+    fn ciphers() -> Vec<crate::Cipher> {
+        vec![
+            #[cfg(feature = "aes-gcm")]
+            crate::Cipher::Aes256Gcm,
+            #[cfg(feature = "chacha20poly1305")]
+            crate::Cipher::ChaCha20Poly1305,
+        ]
+    }
+
+    #[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+    #[quickcheck]
+    fn read_write_encrypted(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        for cipher in ciphers() {
+            let options = Options::new().encryption(cipher, std::sync::Arc::new([1; 32]));
+            let db = Database::memory_with_options(options).unwrap();
+            read_write(db, data1.clone(), data2.clone());
+        }
+    }
+
+    #[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+    #[test]
+    fn encrypted() {
+        use crate::Key;
+        use std::{collections::BTreeMap, fs, sync::Arc};
+
+        for cipher in ciphers() {
+            let tmp = tempfile::tempdir().unwrap();
+            let mut keys: BTreeMap<u32, Key> = BTreeMap::new();
+            keys.insert(1, [1; 32]);
+
+            {
+                let options = Options::new().encryption(cipher, Arc::new(keys.clone()));
+                let db = Database::file_with_options(tmp.path(), options).unwrap();
+                db.append(&[b"first secret", b"second secret"]).unwrap();
+            }
+            let data = fs::read(tmp.path().join("data")).unwrap();
+            assert!(!data.windows(6).any(|window| window == b"secret"));
+
+            assert!(matches!(Database::file(tmp.path()), Err(Error::NoKey)));
+
+            // Records of a plaintext database would not be encrypted.
+            let plain = tempfile::tempdir().unwrap();
+            Database::file(plain.path()).unwrap().put(b"plain").unwrap();
+            let options = Options::new().encryption(cipher, Arc::new(keys.clone()));
+            assert!(matches!(
+                Database::file_with_options(plain.path(), options),
+                Err(Error::InvalidOptions(_))
+            ));
+
+            // Rotate the key: old records are still readable.
+            keys.insert(2, [2; 32]);
+            {
+                let options = Options::new().encryption(cipher, Arc::new(keys.clone()));
+                let db = Database::file_with_options(tmp.path(), options).unwrap();
+                db.put(b"third secret").unwrap();
+                assert_eq!(b"first secret", db.get_by_seqno(0).unwrap().as_ref());
+                assert_eq!(b"third secret", db.get_by_seqno(2).unwrap().as_ref());
+                db.verify().unwrap();
+            }
+
+            fs::remove_file(tmp.path().join("seqno")).unwrap();
+            assert_eq!(3, Database::rebuild_index(tmp.path()).unwrap());
+
+            // Without the new key the newest record cannot be read.
+            let old_keys: BTreeMap<u32, Key> =
+                keys.range(..2).map(|(id, key)| (*id, *key)).collect();
+            let options = Options::new().encryption(cipher, Arc::new(old_keys));
+            let db = Database::file_with_options(tmp.path(), options).unwrap();
+            assert_eq!(b"second secret", db.get_by_seqno(1).unwrap().as_ref());
+            assert!(db.get_by_seqno(2).is_none());
+            assert!(matches!(db.try_get_by_seqno(2), Err(Error::UnknownKey(2))));
+            drop(db);
+
+            let mut data = fs::read(tmp.path().join("data")).unwrap();
+            *data.last_mut().unwrap() ^= 1;
+            fs::write(tmp.path().join("data"), data).unwrap();
+
+            let options = Options::new().encryption(cipher, Arc::new(keys.clone()));
+            let db = Database::file_with_options(tmp.path(), options).unwrap();
+            assert!(matches!(db.try_get_by_seqno(2), Err(Error::Authentication)));
+            assert!(matches!(db.verify(), Err(Error::Authentication)));
+            assert!(db.try_get_by_seqno(3).unwrap().is_none());
+
+            // Reading the damaged record in a loop fails instead of ending early.
+            let mut iter = db.iter_range(..);
+            assert_eq!(b"first secret", iter.try_next().unwrap().unwrap().as_ref());
+            iter.try_next().unwrap();
+            assert!(matches!(iter.try_next(), Err(Error::Authentication)));
+            assert!(iter.try_next().unwrap().is_none());
+            assert!(matches!(
+                db.export(.., crate::Format::Binary, Vec::new()),
+                Err(Error::Authentication)
+            ));
+
+            // Records swapped in the data file are rejected.
+            let tmp = tempfile::tempdir().unwrap();
+            {
+                let options = Options::new().encryption(cipher, Arc::new(keys.clone()));
+                let db = Database::file_with_options(tmp.path(), options).unwrap();
+                db.append(&[b"secret a", b"secret b"]).unwrap();
+            }
+            let data = fs::read(tmp.path().join("data")).unwrap();
+            let (first, second) = data.split_at(data.len() / 2);
+            fs::write(tmp.path().join("data"), [second, first].concat()).unwrap();
+            let options = Options::new().encryption(cipher, Arc::new(keys.clone()));
+            let db = Database::file_with_options(tmp.path(), options).unwrap();
+            assert!(matches!(db.try_get_by_seqno(0), Err(Error::Authentication)));
+            assert!(matches!(db.try_get_by_seqno(1), Err(Error::Authentication)));
+        }
+    }
+
+    #[cfg(all(feature = "zstd", feature = "aes-gcm"))]
+    #[test]
+    fn encrypted_blocks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let records: Vec<_> = (0..100)
+            .map(|i| format!("secret record {}", i).into_bytes())
+            .collect();
+        let records: Vec<&[u8]> = records.iter().map(Vec::as_slice).collect();
+        let options = || {
+            Options::new()
+                .block_compression(256)
+                .encryption(crate::Cipher::Aes256Gcm, std::sync::Arc::new([3; 32]))
+        };
+
+        {
+            let db = Database::file_with_options(tmp.path(), options()).unwrap();
+            db.append(&records).unwrap();
+            db.verify().unwrap();
+        }
+
+        let db = Database::file_with_options(tmp.path(), options()).unwrap();
+        db.verify().unwrap();
+        let read: Vec<_> = db.iter_range(..).map(|record| record.to_vec()).collect();
+        assert_eq!(records, read);
+        drop(db);
+
+        assert!(matches!(
+            Database::rebuild_index(tmp.path()),
+            Err(Error::InvalidOptions(_))
+        ));
+    }
+
+    #[cfg(feature = "dedup")]
+    #[test]
+    fn dedup() {
+        use crate::content_hash;
+
+        let tmp = tempfile::tempdir().unwrap();
+
+        {
+            let db = Database::file(tmp.path()).unwrap();
+            assert_eq!(0, db.put_dedup(b"blob").unwrap());
+            db.put(b"blob").unwrap();
+            assert_eq!(2, db.put_dedup(b"other blob").unwrap());
+            assert_eq!(0, db.put_dedup(b"blob").unwrap());
+            assert_eq!(3, db.len());
+        }
+
+        let db = Database::file(tmp.path()).unwrap();
+        assert_eq!(2, db.put_dedup(b"other blob").unwrap());
+        let (seqno, record) = db.get_by_hash(&content_hash(b"blob")).unwrap().unwrap();
+        assert_eq!((0, &b"blob"[..]), (seqno, record.as_ref()));
+        assert!(db.get_by_hash(&content_hash(b"missing")).unwrap().is_none());
+        drop(db);
+
+        // Hashes of truncated records are ignored.
+        Database::truncate(tmp.path(), 2).unwrap();
+        let db = Database::file(tmp.path()).unwrap();
+        db.put(b"replacement").unwrap();
+        assert!(db
+            .get_by_hash(&content_hash(b"other blob"))
+            .unwrap()
+            .is_none());
+        assert_eq!(3, db.put_dedup(b"other blob").unwrap());
+        assert_eq!(3, db.put_dedup(b"other blob").unwrap());
+
+        let db = Database::memory().unwrap();
+        assert_eq!(0, db.put_dedup(b"blob").unwrap());
+        assert_eq!(0, db.put_dedup(b"blob").unwrap());
+        assert_eq!(1, db.len());
+    }
+
+    #[test]
+    fn append_if_len() {
+        let db = Database::memory().unwrap();
+        assert_eq!(
+            Some(0),
+            db.append_if_len(0, &[b"first", b"second"]).unwrap()
+        );
+        assert!(matches!(
+            db.append_if_len(0, &[b"third"]),
+            Err(Error::Conflict { actual_len: 2 })
+        ));
+        assert!(matches!(
+            db.append_if_len(3, &[]),
+            Err(Error::Conflict { actual_len: 2 })
+        ));
+        assert_eq!(None, db.append_if_len(2, &[]).unwrap());
+        assert_eq!(Some(2), db.append_if_len(2, &[b"third"]).unwrap());
+        assert_eq!(b"third", db.last().unwrap().as_ref());
+    }
+
+    #[test]
+    fn append_idempotent() {
+        let tmp = tempfile::tempdir().unwrap();
+        let options = || Options::new().producer_window(2);
+
+        {
+            let db = Database::file_with_options(tmp.path(), options()).unwrap();
+            assert_eq!(0..2, db.append_idempotent(7, 1, &[b"a", b"b"]).unwrap());
+            db.put(b"unrelated").unwrap();
+            assert_eq!(0..2, db.append_idempotent(7, 1, &[b"a", b"b"]).unwrap());
+            assert_eq!(3..4, db.append_idempotent(8, 1, &[b"c"]).unwrap());
+            assert_eq!(4..5, db.append_idempotent(7, 2, &[b"d"]).unwrap());
+            assert_eq!(5, db.len());
+        }
+
+        let db = Database::file_with_options(tmp.path(), options()).unwrap();
+        assert_eq!(3..4, db.append_idempotent(8, 1, &[b"c"]).unwrap());
+        assert_eq!(5..6, db.append_idempotent(7, 5, &[b"e"]).unwrap());
+        assert!(matches!(
+            db.append_idempotent(7, 1, &[b"a", b"b"]),
+            Err(Error::StaleSequence {
+                producer_id: 7,
+                sequence: 1
+            })
+        ));
+        assert_eq!(4..5, db.append_idempotent(7, 2, &[b"d"]).unwrap());
+        assert_eq!(6, db.len());
+        assert_eq!(b"e", db.last().unwrap().as_ref());
+
+        let db = Database::memory().unwrap();
+        assert_eq!(0..1, db.append_idempotent(1, 1, &[b"a"]).unwrap());
+        assert_eq!(0..1, db.append_idempotent(1, 1, &[b"a"]).unwrap());
+        assert_eq!(1, db.len());
+    }
+
+    #[test]
+    fn append_idempotent_after_crash() {
+        use crate::producers::{self, Producers};
+
+        let tmp = tempfile::tempdir().unwrap();
+
+        // The process stopped after storing the entry of a batch and before
+        // writing its records. Other records were appended in their place.
+        {
+            let db = Database::file(tmp.path()).unwrap();
+            db.put(b"first").unwrap();
+            let producers = Producers::new(Some(tmp.path().to_path_buf()), 5);
+            let checksum = producers::checksum([&b"mine"[..]]);
+            let mut state = producers.lock(1, |_| Ok(None)).unwrap();
+            state.record(1, 1, 1..2, checksum).unwrap();
+        }
+        {
+            let db = Database::file(tmp.path()).unwrap();
+            db.put(b"other").unwrap();
+        }
+
+        let db = Database::file(tmp.path()).unwrap();
+        assert_eq!(2..3, db.append_idempotent(1, 1, &[b"mine"]).unwrap());
+        assert_eq!(2..3, db.append_idempotent(1, 1, &[b"mine"]).unwrap());
+        assert_eq!(b"other", db.get_by_seqno(1).unwrap().as_ref());
+        assert_eq!(b"mine", db.get_by_seqno(2).unwrap().as_ref());
+        drop(db);
+
+        // Truncated batches are written again, even if the same records were
+        // appended in their place.
+        Database::truncate(tmp.path(), 2).unwrap();
+        let db = Database::file(tmp.path()).unwrap();
+        db.put(b"mine").unwrap();
+        assert_eq!(3..4, db.append_idempotent(1, 1, &[b"mine"]).unwrap());
+    }
+
+    #[test]
+    fn delete() {
+        let tmp = tempfile::tempdir().unwrap();
+        let records: Vec<&[u8]> = vec![b"first", b"personal data", b"third", b"fourth", b"fifth"];
+
+        {
+            let options = Options::new().framed(true).zero_deleted(true);
+            let db = Database::file_with_options(tmp.path(), options).unwrap();
+            db.append(&records).unwrap();
+
+            // Records read earlier do not change.
+            let held = db.get_by_seqno(1).unwrap();
+            assert!(db.delete(1).unwrap());
+            assert_eq!(b"personal data", held.as_ref());
+            drop(held);
+            assert!(db.delete(4).unwrap());
+            assert!(!db.delete(1).unwrap());
+            assert!(!db.delete(5).unwrap());
+            assert!(db.is_deleted(1));
+            assert!(db.get_by_seqno(1).is_none());
+            assert!(db.get_raw_by_seqno(1).is_none());
+            assert_eq!(b"third", db.get_by_seqno(2).unwrap().as_ref());
+            assert_eq!(2, db.stat().deleted);
+        }
+
+        let data = std::fs::read(tmp.path().join("data")).unwrap();
+        assert!(!data.windows(8).any(|window| window == b"personal"));
+        assert!(!data.windows(5).any(|window| window == b"fifth"));
+
+        let db = Database::file(tmp.path()).unwrap();
+        assert!(db.get_by_seqno(1).is_none());
+        db.verify().unwrap();
+        assert!(db.delete(0).unwrap());
+        let read: Vec<_> = db.iter_range(..).map(|record| record.to_vec()).collect();
+        assert_eq!(&records[2..4], read.as_slice());
+        let mut iter = db.iter_range(..);
+        assert_eq!(2, iter.seqno());
+        iter.next();
+        assert_eq!(3, iter.seqno());
+        drop((db, iter));
+
+        // Deletions of truncated records are forgotten.
+        Database::truncate(tmp.path(), 1).unwrap();
+        let db = Database::file(tmp.path()).unwrap();
+        db.append(&[b"second", b"third"]).unwrap();
+        assert_eq!(b"second", db.get_by_seqno(1).unwrap().as_ref());
+        assert!(db.get_by_seqno(0).is_none());
+        drop(db);
+        let db = Database::file(tmp.path()).unwrap();
+        assert_eq!(b"third", db.get_by_seqno(2).unwrap().as_ref());
+
+        let db = Database::memory().unwrap();
+        db.append(&records).unwrap();
+        assert!(db.delete(4).unwrap());
+        assert_eq!(4, db.iter_range(..).count());
+        assert!(db.last().is_none());
+        assert!(Database::memory_with_options(Options::new().zero_deleted(true)).is_err());
+
+        // Zeroing deferred by a held record is done by the next open.
+        let tmp = tempfile::tempdir().unwrap();
+        let options = Options::new().framed(true).zero_deleted(true);
+        let db = Database::file_with_options(tmp.path(), options).unwrap();
+        db.append(&records).unwrap();
+        let held = db.get_by_seqno(1).unwrap();
+        assert!(db.delete(1).unwrap());
+        drop((db, held));
+        let data = std::fs::read(tmp.path().join("data")).unwrap();
+        assert!(data.windows(8).any(|window| window == b"personal"));
+
+        let db = Database::file(tmp.path()).unwrap();
+        let data = std::fs::read(tmp.path().join("data")).unwrap();
+        assert!(!data.windows(8).any(|window| window == b"personal"));
+        assert_eq!(0, tmp.path().join("data.zero").metadata().unwrap().len());
+        assert_eq!(b"third", db.get_by_seqno(2).unwrap().as_ref());
+    }
+
+    #[test]
+    fn compact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (path, compacted) = (tmp.path().join("db"), tmp.path().join("compacted"));
+        let records: Vec<_> = (0..3000).map(|i| format!("record {}", i)).collect();
+        let records: Vec<&[u8]> = records.iter().map(|record| record.as_bytes()).collect();
+
+        let db = Database::file_with_options(&path, Options::new().framed(true)).unwrap();
+        db.append(&records).unwrap();
+        for seqno in (0..records.len()).step_by(3) {
+            db.delete(seqno).unwrap();
+        }
+
+        assert_eq!(2000, db.compact_into(&compacted).unwrap());
+        assert!(db.compact_into(&compacted).is_err());
+        let map = Database::load_seqno_map(&compacted).unwrap();
+        assert_eq!(records.len(), map.len());
+        assert_eq!(
+            vec![None, Some(0), Some(1), None, Some(2)],
+            map[..5].to_vec()
+        );
+
+        Database::swap(&path, &compacted).unwrap();
+        drop(db);
+        let db = Database::file(&path).unwrap();
+        db.verify().unwrap();
+        assert_eq!(2000, db.len());
+        assert_eq!(0, db.stat().deleted);
+        for (old, new) in map.iter().enumerate() {
+            if let Some(new) = new {
+                assert_eq!(records[old], db.get_by_seqno(*new).unwrap().as_ref());
+            }
+        }
+        assert_eq!(3000, Database::file(&compacted).unwrap().len());
+
+        // Deletions wait for the copy to finish, so they are not lost.
+        let write_guard = db.lock_writes();
+        let deleter = {
+            let db = db.clone();
+            std::thread::spawn(move || db.delete(1))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(db.get_by_seqno(1).is_some());
+        drop(write_guard);
+        assert!(deleter.join().unwrap().unwrap());
+        assert!(db.get_by_seqno(1).is_none());
+    }
+
+    #[cfg(feature = "aes-gcm")]
+    #[test]
+    fn compact_damaged() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (path, compacted) = (tmp.path().join("db"), tmp.path().join("compacted"));
+        let options =
+            || Options::new().encryption(crate::Cipher::Aes256Gcm, std::sync::Arc::new([5; 32]));
+
+        {
+            let db = Database::file_with_options(&path, options()).unwrap();
+            db.append(&[b"first", b"second"]).unwrap();
+        }
+        let mut data = std::fs::read(path.join("data")).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        std::fs::write(path.join("data"), data).unwrap();
+
+        // A failed copy does not leave a partial database behind.
+        let db = Database::file_with_options(&path, options()).unwrap();
+        assert!(matches!(
+            db.compact_into(&compacted),
+            Err(Error::Authentication)
+        ));
+        assert!(!compacted.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn punch() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let record = vec![7u8; 1000];
+        let blocks = || tmp.path().join("data").metadata().unwrap().blocks();
+
+        {
+            let options = Options::new().framed(true);
+            let db = Database::file_with_options(tmp.path(), options).unwrap();
+            db.append(&vec![record.as_slice(); 1000]).unwrap();
+            let size = db.stat().data_size;
+            let allocated = blocks();
+
+            // The space of records that are still held is given back later.
+            let held = db.get_by_seqno(0).unwrap();
+            assert_eq!(1008, db.punch(..1).unwrap());
+            assert!(db.get_by_seqno(0).is_none());
+            assert_eq!(allocated, blocks());
+            assert_eq!(record, held.as_ref());
+            drop(held);
+
+            assert_eq!(499 * 1008, db.punch(1..500).unwrap());
+            assert_eq!(0, db.punch(2000..).unwrap());
+            assert_eq!(size, db.stat().data_size);
+            assert!(blocks() < allocated);
+            assert!(db.get_by_seqno(499).is_none());
+            assert_eq!(record, db.get_by_seqno(500).unwrap().as_ref());
+            assert_eq!(500, db.iter_range(..).count());
+            db.verify().unwrap();
+        }
+
+        let db = Database::file(tmp.path()).unwrap();
+        assert!(db.get_raw_by_seqno(0).is_none());
+        assert_eq!(500, db.stat().deleted);
+        db.put(b"after punch").unwrap();
+        db.verify().unwrap();
+        drop(db);
+
+        assert!(matches!(
+            Database::rebuild_index(tmp.path()),
+            Err(Error::InvalidOptions(_))
+        ));
+        let db = Database::memory().unwrap();
+        db.put(b"in memory").unwrap();
+        db.put(b"kept").unwrap();
+        assert_eq!(9, db.punch(..1).unwrap());
+        assert!(db.get_by_seqno(0).is_none());
+        assert_eq!(b"kept", db.get_by_seqno(1).unwrap().as_ref());
+
+        let db = Database::file(tmp.path().join("empty")).unwrap();
+        db.append(&[b"", b"", b"kept"]).unwrap();
+        assert_eq!(0, db.punch(..2).unwrap());
+        assert!(db.get_by_seqno(1).is_none());
+        assert_eq!(b"kept", db.get_by_seqno(2).unwrap().as_ref());
+        assert_eq!(1, db.iter_range(..).count());
+        db.verify().unwrap();
+    }
+
+    #[test]
+    fn capped() {
+        let record = vec![3u8; 5000];
+        let records = vec![record.as_slice(); 10];
+
+        let db = Database::memory_with_options(Options::new().capped_records(25)).unwrap();
+        for _ in 0..5 {
+            db.append(&records).unwrap();
+        }
+        assert_eq!(50, db.len());
+        assert!(db.get_by_seqno(24).is_none());
+        assert_eq!(record, db.get_by_seqno(25).unwrap().as_ref());
+        assert_eq!(25, db.iter_range(..).count());
+        assert_eq!(25, db.iter_from_seqno(0).unwrap().seqno());
+
+        // Batches bigger than the capacity are kept whole.
+        let db = Database::memory_with_options(Options::new().capped_bytes(12000)).unwrap();
+        db.append(&records[..3]).unwrap();
+        assert_eq!(3, db.iter_range(..).count());
+        db.put(&record).unwrap();
+        assert!(db.get_by_seqno(1).is_none());
+        assert_eq!(record, db.get_by_seqno(2).unwrap().as_ref());
+        db.put(&record).unwrap();
+        assert!(db.get_by_seqno(2).is_none());
+        assert_eq!(Some(5), db.append_get_seqno(&[b"small"]).unwrap());
+        assert_eq!(record, db.get_by_seqno(3).unwrap().as_ref());
+        assert_eq!(3, db.iter_range(..).count());
+
+        // Records read earlier do not change when they are evicted.
+        let db = Database::memory_with_options(Options::new().capped_records(1)).unwrap();
+        db.put(&record).unwrap();
+        let held = db.get_by_seqno(0).unwrap();
+        db.put(b"second").unwrap();
+        assert!(db.get_by_seqno(0).is_none());
+        assert_eq!(record, held.as_ref());
+
+        // Evicted records are unmapped, so the mappings do not pile up.
+        let db = Database::memory_with_options(Options::new().capped_records(10)).unwrap();
+        for _ in 0..20000 {
+            db.put(&record[..1024]).unwrap();
+        }
+        assert!(db.stat().mappings < 50);
+        assert_eq!(10, db.iter_range(..).count());
+        assert_eq!(&record[..1024], db.last().unwrap().as_ref());
+
+        // A failed write does not evict records.
+        let options = Options::new().capped_records(2).max_index_size(16);
+        let db = Database::memory_with_options(options).unwrap();
+        db.append(&[b"first", b"second"]).unwrap();
+        assert!(matches!(db.put(b"third"), Err(Error::QuotaExceeded(_))));
+        assert_eq!(b"first", db.get_by_seqno(0).unwrap().as_ref());
+        assert_eq!(2, db.len());
+
+        assert!(matches!(
+            Database::memory_with_options(Options::new().capped_records(0)),
+            Err(Error::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn empty_records() {
+        let tmp = tempfile::tempdir().unwrap();
+        let options = || {
+            vec![
+                Options::new(),
+                Options::new().framed(true),
+                Options::new().index_encoding(IndexEncoding::Blocked),
+            ]
+        };
+
+        for (number, options) in options().into_iter().enumerate() {
+            let path = tmp.path().join(number.to_string());
+            {
+                let db = Database::file_with_options(&path, options.clone()).unwrap();
+                db.put(b"").unwrap();
+                db.append(&[b"", b""]).unwrap();
+                db.append(&[b"first", b"", b"second", b""]).unwrap();
+                assert_eq!(
+                    Some(7),
+                    db.append_with(&[0, 5], |buffers| {
+                        buffers[1].copy_from_slice(b"third");
+                        Ok::<_, Error>(())
+                    })
+                    .unwrap()
+                );
+                db.put(b"").unwrap();
+                assert_eq!(b"", db.get_by_seqno(0).unwrap().as_ref());
+                assert_eq!(b"", db.get_by_seqno(7).unwrap().as_ref());
+                assert_eq!(b"", db.get_by_seqno(9).unwrap().as_ref());
+                assert!(db.get_by_seqno(10).is_none());
+            }
+
+            let db = Database::file_with_options(&path, options).unwrap();
+            let records: Vec<_> = db.iter_range(..).map(|record| record.to_vec()).collect();
+            let expected: [&[u8]; 10] = [
+                b"", b"", b"", b"first", b"", b"second", b"", b"", b"third", b"",
+            ];
+            assert_eq!(expected.to_vec(), records);
+            db.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn get_range() {
+        let tmp = tempfile::tempdir().unwrap();
+        for framed in [false, true] {
+            let path = tmp.path().join(framed.to_string());
+            {
+                let db = Database::file_with_options(&path, Options::new().framed(framed)).unwrap();
+                db.append(&[b"zero", b"one", b"", b"three"]).unwrap();
+                // Mappings that are viewed are not merged.
+                let held = db.get_by_seqno(0).unwrap();
+                db.append(&[b"four", b"five"]).unwrap();
+                drop(held);
+
+                let run = db.get_range(1..4);
+                assert_eq!(!framed, run.is_mapped());
+                assert_eq!((1, 3), (run.start(), run.len()));
+                assert_eq!(b"onethree", run.data().as_ref());
+                assert_eq!(Some(3..3), run.bounds(2));
+                assert_eq!(b"three", run.get(3).unwrap().as_ref());
+                assert!(run.get(4).is_none());
+
+                let run = db.get_range(2..=4);
+                assert!(!run.is_mapped());
+                assert_eq!(b"threefour", run.data().as_ref());
+
+                // Empty records are not stored in the data file.
+                let run = db.get_range(2..3);
+                assert!(!run.is_mapped());
+                assert_eq!(Some(0..0), run.bounds(2));
+
+                assert_eq!(
+                    vec![
+                        Some(b"five".to_vec()),
+                        None,
+                        Some(b"four".to_vec()),
+                        Some(b"zero".to_vec())
+                    ],
+                    db.get_many(&[5, 6, 4, 0])
+                        .into_iter()
+                        .map(|record| record.map(|record| record.to_vec()))
+                        .collect::<Vec<_>>()
+                );
+            }
+
+            let db = Database::file_with_options(&path, Options::new().framed(framed)).unwrap();
+            let run = db.get_range(..);
+            assert_eq!(!framed, run.is_mapped());
+            assert_eq!(b"zeroonethreefourfive", run.data().as_ref());
+            assert_eq!(6, run.len());
+
+            db.delete(1).unwrap();
+            let run = db.get_range(..10);
+            assert!(!run.is_mapped());
+            assert!(run.get(1).is_none());
+            let records: Vec<_> = run
+                .iter()
+                .map(|(seqno, record)| (seqno, record.to_vec()))
+                .collect();
+            assert_eq!(
+                vec![
+                    (0, b"zero".to_vec()),
+                    (2, b"".to_vec()),
+                    (3, b"three".to_vec()),
+                    (4, b"four".to_vec()),
+                    (5, b"five".to_vec())
+                ],
+                records
+            );
+            assert!(db.get_range(7..).is_empty());
+        }
+    }
+
+    #[test]
+    fn poisoned_write_lock() {
+        let db = Database::memory().unwrap();
+
+        // A write that panics does not break the database.
+        let writer = db.clone();
+        std::thread::spawn(move || {
+            let _write_guard = writer.lock_writes();
+            panic!("the writer failed");
+        })
+        .join()
+        .unwrap_err();
+        assert!(db.write_lock.is_poisoned());
+        db.put(b"after panic").unwrap();
+        assert_eq!(b"after panic", db.get_by_seqno(0).unwrap().as_ref());
+
+        let tmp = tempfile::tempdir().unwrap();
+        {
+            let db = Database::file(tmp.path()).unwrap();
+            db.put(b"abc").unwrap();
+            let writer = db.clone();
+            std::thread::spawn(move || {
+                writer.append_with(&[100], |_| -> Result<(), Error> {
+                    panic!("the callback failed")
+                })
+            })
+            .join()
+            .unwrap_err();
+            assert_eq!(3, db.stat().data_size);
+        }
+
+        let db = Database::file(tmp.path()).unwrap();
+        assert_eq!(b"abc", db.get_by_seqno(0).unwrap().as_ref());
+        assert_eq!(1, db.len());
+        db.verify().unwrap();
+    }
+
+    #[test]
+    fn no_space() {
+        use crate::growable_mmap::tests::run_out_of_space;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::file(tmp.path()).unwrap();
+        db.append(&[b"first"]).unwrap();
+        let data_size = tmp.path().join("data").metadata().unwrap().len();
+        let index_size = tmp.path().join("seqno").metadata().unwrap().len();
+
+        // Running out of space for the records and for their index entries.
+        for allocations in 0..2 {
+            let result = run_out_of_space(allocations, || db.append(&[b"second", b"third"]));
+            assert!(matches!(result, Err(Error::NoSpace(_))), "{:?}", result);
+
+            assert_eq!(1, db.len());
+            let data = tmp.path().join("data").metadata().unwrap().len();
+            assert_eq!(data_size, data);
+            let index = tmp.path().join("seqno").metadata().unwrap().len();
+            assert_eq!(index_size, index);
+            db.verify().unwrap();
+        }
+
+        db.append(&[b"second", b"third"]).unwrap();
+        assert_eq!(3, db.len());
+        assert_eq!(b"third", db.get_by_seqno(2).unwrap().as_ref());
+        db.verify().unwrap();
+        drop(db);
+
+        let db = Database::file(tmp.path()).unwrap();
+        assert_eq!(3, db.len());
+        db.verify().unwrap();
+    }
+
+    #[test]
+    fn quotas() {
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let options = {
+            let warnings = warnings.clone();
+            Options::new()
+                .max_records(10)
+                .max_data_size(1000)
+                .max_index_size(64)
+                .quota_warning(50, move |usage| warnings.lock().unwrap().push(usage))
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::file_with_options(tmp.path(), options).unwrap();
+
+        db.append(&[&[1; 300], &[2; 300]]).unwrap();
+        assert_eq!(
+            vec![QuotaUsage {
+                quota: Quota::DataSize,
+                used: 600,
+                max: 1000,
+            }],
+            *warnings.lock().unwrap()
+        );
+        assert!(matches!(
+            db.put(&[3; 500]),
+            Err(Error::QuotaExceeded(QuotaUsage {
+                quota: Quota::DataSize,
+                used: 1100,
+                max: 1000,
+            }))
+        ));
+        assert_eq!(600, tmp.path().join("data").metadata().unwrap().len());
+
+        let small = vec![&[4u8][..]; 9];
+        assert!(matches!(
+            db.append(&small),
+            Err(Error::QuotaExceeded(QuotaUsage {
+                quota: Quota::Records,
+                used: 11,
+                max: 10,
+            }))
+        ));
+        db.append(&small[..6]).unwrap();
+        assert!(matches!(
+            db.put(&[5]),
+            Err(Error::QuotaExceeded(QuotaUsage {
+                quota: Quota::IndexSize,
+                used: 72,
+                max: 64,
+            }))
+        ));
+        assert_eq!(8, db.len());
+        assert_eq!(3, warnings.lock().unwrap().len());
+        db.verify().unwrap();
+
+        // The limits do not apply to a compacted copy.
+        let compacted = tempfile::tempdir().unwrap();
+        assert_eq!(8, db.compact_into(compacted.path().join("db")).unwrap());
+        assert_eq!(3, warnings.lock().unwrap().len());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn capped_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let options = || Options::new().capped_records(3);
+
+        {
+            let db = Database::file_with_options(tmp.path(), options()).unwrap();
+            for i in 0..5u8 {
+                db.put(&[i; 100]).unwrap();
+            }
+            assert!(db.get_by_seqno(1).is_none());
+            assert_eq!([2; 100], db.get_by_seqno(2).unwrap().as_ref());
+        }
+
+        let db = Database::file_with_options(tmp.path(), options()).unwrap();
+        db.put(&[5; 100]).unwrap();
+        assert!(db.get_by_seqno(2).is_none());
+        let kept: Vec<_> = db.iter_range(..).map(|record| record[0]).collect();
+        assert_eq!(vec![3, 4, 5], kept);
+        db.verify().unwrap();
+
+        let db =
+            Database::file_with_options(tmp.path().join("empty"), Options::new().capped_records(1))
+                .unwrap();
+        db.put(b"").unwrap();
+        db.put(b"").unwrap();
+        db.put(b"last").unwrap();
+        assert!(db.get_by_seqno(1).is_none());
+        assert_eq!(b"last", db.get_by_seqno(2).unwrap().as_ref());
+        assert_eq!(1, db.iter_range(..).count());
+        db.verify().unwrap();
+    }
+
+    #[quickcheck]
+    fn read_write_compact_index(data1: Vec<Vec<u8>>, data2: Vec<Vec<u8>>) {
+        for encoding in [IndexEncoding::U32, IndexEncoding::Blocked].iter() {
+            let options = Options::new().index_encoding(*encoding);
+            let db = Database::memory_with_options(options).unwrap();
+            read_write(db, data1.clone(), data2.clone());
+        }
+    }
+
+    #[quickcheck]
+    fn read_write_fixed_size(data1: Vec<u64>, data2: Vec<u64>) {
+        let to_bytes = |data: Vec<u64>| -> Vec<Vec<u8>> {
+            data.iter()
+                .map(|item| item.to_le_bytes().to_vec())
+                .collect()
+        };
+
+        let tmp = tempfile::tempdir().unwrap();
+        let options = Options::new().fixed_record_size(8);
+        let db = Database::file_with_options(tmp.path(), options).unwrap();
+        read_write(db.clone(), to_bytes(data1), to_bytes(data2));
+        assert!(!tmp.path().join("seqno").exists());
+
+        match db.append(&[b"too long record"]) {
+            Err(Error::RecordSize {
+                index: 0,
+                expected: 8,
+                actual: 15,
+            }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_layout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let invalid = [
+            Options::new().fixed_record_size(0),
+            Options::new().block_compression(0),
+            Options::new()
+                .block_compression(4096)
+                .index_encoding(IndexEncoding::U32),
+        ];
+        for options in invalid {
+            assert!(matches!(
+                Database::file_with_options(tmp.path(), options),
+                Err(Error::InvalidOptions(_))
+            ));
+        }
+
+        // Nothing was stored, so the database can be created with other
+        // options.
+        let db = Database::file(tmp.path()).unwrap();
+        db.put(b"record").unwrap();
+        db.verify().unwrap();
+    }
+
+    #[test]
+    fn append_with() {
+        let tmp = tempfile::tempdir().unwrap();
+        let options = Options::new().framed(true);
+        let db = Database::file_with_options(tmp.path(), options).unwrap();
+
+        let seqno = db
+            .append_with(&[3, 5], |buffers| {
+                buffers[0].copy_from_slice(b"abc");
+                buffers[1].copy_from_slice(b"defgh");
+                Ok::<(), Error>(())
+            })
+            .unwrap();
+        assert_eq!(Some(0), seqno);
+
+        let result = db.append_with(&[4], |buffers| {
+            buffers[0].copy_from_slice(b"ijkl");
+            Err(Error::DataFileDamaged)
+        });
+        assert!(result.is_err());
+        let data_len = std::fs::metadata(tmp.path().join("data")).unwrap().len();
+        assert_eq!(db.stat().data_size as u64, data_len);
+
+        db.put(b"mnop").unwrap();
+
+        let records: Vec<_> = db.iter_from_seqno(0).unwrap().collect();
+        assert_eq!(3, records.len());
+        assert_eq!(b"abc", records[0].as_ref());
+        assert_eq!(b"defgh", records[1].as_ref());
+        assert_eq!(b"mnop", records[2].as_ref());
+    }
+
+    #[test]
+    fn put_from_reader() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::file(tmp.path()).unwrap();
+        let record: Vec<u8> = (0..3_000_000u32).map(|i| i as u8).collect();
+
+        db.put(b"first").unwrap();
+        assert_eq!(1, db.put_from_reader(&record[..], None).unwrap());
+        assert_eq!(2, db.put_from_reader(&record[..10], Some(10)).unwrap());
+        assert_eq!(3, db.put_from_reader(&[][..], None).unwrap());
+
+        assert_eq!(record, db.get_by_seqno(1).unwrap().as_ref());
+        assert_eq!(&record[..10], db.get_by_seqno(2).unwrap().as_ref());
+        assert!(db.get_by_seqno(3).unwrap().is_empty());
+        assert_eq!(4, db.len());
+        db.verify().unwrap();
+    }
+
+    #[test]
+    fn rebuild_index() {
+        let tmp = tempfile::tempdir().unwrap();
+        let records: [&[u8]; 3] = [b"first", b"second", b"third"];
+
+        {
+            let options = Options::new()
+                .framed(true)
+                .index_encoding(IndexEncoding::Blocked);
+            let db = Database::file_with_options(tmp.path(), options).unwrap();
+            db.append(&records).unwrap();
+        }
+
+        std::fs::remove_file(tmp.path().join("seqno")).unwrap();
+        assert_eq!(3, Database::rebuild_index(tmp.path()).unwrap());
+
+        let db = Database::file(tmp.path()).unwrap();
+        db.verify().unwrap();
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(*record, db.get_by_seqno(i).unwrap().as_ref());
+        }
+        let data_size = db.stat().data_size;
+        drop(db);
+
+        // A crash after the file was extended leaves zeros behind.
+        let data = tmp.path().join("data");
+        let file = std::fs::OpenOptions::new().write(true).open(&data).unwrap();
+        file.set_len(data_size as u64 + 64).unwrap();
+        assert_eq!(3, Database::rebuild_index(tmp.path()).unwrap());
+        assert_eq!(data_size as u64, data.metadata().unwrap().len());
+        Database::file(tmp.path()).unwrap().verify().unwrap();
+
+        // Records after a damaged one are not cut off. This clears the marker
+        // in the header of the second record.
+        let mut content = std::fs::read(&data).unwrap();
+        content[8 + records[0].len() + 7] = 0;
+        std::fs::write(&data, &content).unwrap();
+        assert!(matches!(
+            Database::rebuild_index(tmp.path()),
+            Err(Error::DataFileDamaged)
+        ));
+        assert_eq!(content, std::fs::read(&data).unwrap());
     }
 
     #[quickcheck]
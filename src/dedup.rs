@@ -0,0 +1,150 @@
+//! A map from BLAKE3 hashes of records to their sequential numbers used to
+//! avoid storing the same record twice. The map is persisted in the `hashes`
+//! file of the database directory as a sequence of 32-byte hashes followed by
+//! little-endian `u64` sequential numbers. Later entries take precedence.
+
+use crate::Error;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    mem::size_of,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// A BLAKE3 hash of a record.
+pub type Hash = [u8; 32];
+
+const ENTRY_SIZE: usize = size_of::<Hash>() + size_of::<u64>();
+
+/// The hash `Database::put_dedup` and `Database::get_by_hash` use.
+pub fn content_hash(record: &[u8]) -> Hash {
+    *blake3::hash(record).as_bytes()
+}
+
+/// The hash map and its file. The file is only read when the map is used for
+/// the first time, so databases that do not deduplicate records never touch
+/// it.
+pub(crate) struct Hashes {
+    path: Option<PathBuf>,
+    map: Mutex<Map>,
+}
+
+pub(crate) struct Map {
+    loaded: bool,
+    seqnos: HashMap<Hash, usize>,
+    file: Option<File>,
+}
+
+impl Hashes {
+    /// * `dir` - the database directory or `None` for in-memory databases.
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            path: dir.map(|dir| dir.join("hashes")),
+            map: Mutex::new(Map {
+                loaded: false,
+                seqnos: HashMap::new(),
+                file: None,
+            }),
+        }
+    }
+
+    /// Lock the map, loading it from the file if this was not done yet.
+    /// Writers that hold the lock are serialized.
+    pub fn lock(&self) -> Result<MutexGuard<'_, Map>, Error> {
+        let mut map = self.map.lock().unwrap_or_else(PoisonError::into_inner);
+        if map.loaded {
+            return Ok(map);
+        }
+
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)
+                .map_err(|err| Error::FileOpen(path.clone(), err))?;
+
+            let mut entries = Vec::new();
+            file.read_to_end(&mut entries).map_err(Error::Read)?;
+
+            // Drop an entry that was not written completely.
+            let len = entries.len() - entries.len() % ENTRY_SIZE;
+            if len != entries.len() {
+                file.set_len(len as u64).map_err(Error::Truncate)?;
+            }
+
+            for entry in entries[..len].chunks_exact(ENTRY_SIZE) {
+                let (hash, seqno) = entry.split_at(size_of::<Hash>());
+                let mut hash_bytes = Hash::default();
+                hash_bytes.copy_from_slice(hash);
+                let mut seqno_bytes = [0u8; size_of::<u64>()];
+                seqno_bytes.copy_from_slice(seqno);
+                map.seqnos
+                    .insert(hash_bytes, u64::from_le_bytes(seqno_bytes) as usize);
+            }
+
+            map.file = Some(file);
+        }
+
+        map.loaded = true;
+        Ok(map)
+    }
+}
+
+impl Map {
+    /// The sequential number of a record with the given hash. The entry may
+    /// be stale, e.g. if the database was truncated, so the record has to be
+    /// checked.
+    pub fn get(&self, hash: &Hash) -> Option<usize> {
+        self.seqnos.get(hash).copied()
+    }
+
+    pub fn insert(&mut self, hash: Hash, seqno: usize) -> Result<(), Error> {
+        if let Some(file) = &mut self.file {
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[..size_of::<Hash>()].copy_from_slice(&hash);
+            entry[size_of::<Hash>()..].copy_from_slice(&(seqno as u64).to_le_bytes());
+            file.write_all(&entry).map_err(Error::Write)?;
+        }
+
+        self.seqnos.insert(hash, seqno);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{content_hash as hash, Hashes};
+
+    #[test]
+    fn persist() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        {
+            let hashes = Hashes::new(Some(tmp.path().to_path_buf()));
+            let mut map = hashes.lock().unwrap();
+            assert_eq!(None, map.get(&hash(b"first")));
+            map.insert(hash(b"first"), 0).unwrap();
+            map.insert(hash(b"second"), 1).unwrap();
+            map.insert(hash(b"first"), 2).unwrap();
+        }
+
+        // Append half an entry as if writing it was interrupted.
+        let path = tmp.path().join("hashes");
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(&[0xff; 20]);
+        std::fs::write(&path, &data).unwrap();
+
+        let hashes = Hashes::new(Some(tmp.path().to_path_buf()));
+        let mut map = hashes.lock().unwrap();
+        assert_eq!(Some(2), map.get(&hash(b"first")));
+        assert_eq!(Some(1), map.get(&hash(b"second")));
+        map.insert(hash(b"third"), 3).unwrap();
+        drop(map);
+
+        let hashes = Hashes::new(Some(tmp.path().to_path_buf()));
+        assert_eq!(Some(3), hashes.lock().unwrap().get(&hash(b"third")));
+    }
+}
@@ -0,0 +1,362 @@
+//! Encryption of records at rest. Every stored record holds the id of the key
+//! it was encrypted with as a little-endian `u32`, the nonce and the
+//! ciphertext followed by the authentication tag. The offset of the record in
+//! the data file is authenticated along with it, so records cannot be swapped
+//! or replayed at other positions.
+
+use crate::Error;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    mem::size_of,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+/// A 256-bit encryption key.
+pub type Key = [u8; 32];
+
+/// The size of the key id stored before the nonce.
+const KEY_ID_SIZE: usize = size_of::<u32>();
+/// Nonces are 96 bits long and random for every record.
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// The key id, whether the record is compressed and its offset.
+const AAD_SIZE: usize = KEY_ID_SIZE + 1 + size_of::<u64>();
+
+/// Authenticated encryption algorithms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES-256 in Galois/Counter Mode. Requires the `aes-gcm` feature.
+    Aes256Gcm,
+    /// ChaCha20-Poly1305. Requires the `chacha20poly1305` feature.
+    ChaCha20Poly1305,
+}
+
+/// Supplies encryption keys. Every key has an id that is stored with the
+/// records encrypted with it. Rotating the key is done by returning a new key
+/// with a new id from `current_key`: records appended after that are
+/// encrypted with the new key, while older ones still need the old keys.
+pub trait KeyProvider: Send + Sync {
+    /// The key to encrypt new records with and its id or `None` if there
+    /// are no keys.
+    fn current_key(&self) -> Option<(u32, Key)>;
+
+    /// The key with the given id or `None` if it is not known.
+    fn key(&self, id: u32) -> Option<Key>;
+}
+
+/// A single key with id `0`.
+impl KeyProvider for Key {
+    fn current_key(&self) -> Option<(u32, Key)> {
+        Some((0, *self))
+    }
+
+    fn key(&self, id: u32) -> Option<Key> {
+        if id == 0 {
+            Some(*self)
+        } else {
+            None
+        }
+    }
+}
+
+/// Keys by their ids. The key with the highest id is used for new records.
+impl KeyProvider for BTreeMap<u32, Key> {
+    fn current_key(&self) -> Option<(u32, Key)> {
+        self.iter().next_back().map(|(id, key)| (*id, *key))
+    }
+
+    fn key(&self, id: u32) -> Option<Key> {
+        self.get(&id).copied()
+    }
+}
+
+/// Encrypts and decrypts records with keys from a `KeyProvider`.
+pub(crate) struct Encryption {
+    cipher: Cipher,
+    keys: Arc<dyn KeyProvider>,
+    /// Initialized ciphers by key ids.
+    instances: RwLock<HashMap<u32, Arc<codec::Instance>>>,
+}
+
+impl Encryption {
+    pub fn new(cipher: Cipher, keys: Arc<dyn KeyProvider>) -> Result<Self, Error> {
+        codec::check(cipher)?;
+
+        Ok(Self {
+            cipher,
+            keys,
+            instances: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// How many bytes a record takes after encryption in addition to its
+    /// length.
+    pub const OVERHEAD: usize = KEY_ID_SIZE + NONCE_SIZE + TAG_SIZE;
+
+    fn instance(&self, id: u32, key: Option<Key>) -> Result<Arc<codec::Instance>, Error> {
+        if let Some(instance) = self
+            .instances
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&id)
+        {
+            return Ok(instance.clone());
+        }
+
+        let key = match key {
+            Some(key) => key,
+            None => self.keys.key(id).ok_or(Error::UnknownKey(id))?,
+        };
+        let instance = Arc::new(codec::Instance::new(self.cipher, &key)?);
+        self.instances
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, instance.clone());
+        Ok(instance)
+    }
+
+    /// Encrypt a batch of records with the current key. `compressed` tells
+    /// which records are compressed and `offsets` where they are stored in
+    /// the data file: this is authenticated along with the records.
+    pub fn seal<R: AsRef<[u8]>>(
+        &self,
+        records: &[R],
+        compressed: &[bool],
+        offsets: &[usize],
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let (id, key) = self.keys.current_key().ok_or(Error::NoKey)?;
+        let instance = self.instance(id, Some(key))?;
+
+        records
+            .iter()
+            .zip(compressed.iter())
+            .zip(offsets.iter())
+            .map(|((record, compressed), offset)| {
+                let nonce = codec::nonce();
+                let ciphertext = instance
+                    .encrypt(&nonce, &aad(id, *compressed, *offset), record.as_ref())
+                    .ok_or(Error::Encrypt)?;
+
+                let mut stored = Vec::with_capacity(KEY_ID_SIZE + NONCE_SIZE + ciphertext.len());
+                stored.extend_from_slice(&id.to_le_bytes());
+                stored.extend_from_slice(&nonce);
+                stored.extend_from_slice(&ciphertext);
+                Ok(stored)
+            })
+            .collect()
+    }
+
+    /// Decrypt a record encrypted with `seal` that is stored at `offset`.
+    pub fn open(&self, stored: &[u8], compressed: bool, offset: usize) -> Result<Vec<u8>, Error> {
+        if stored.len() < Self::OVERHEAD {
+            return Err(Error::Authentication);
+        }
+
+        let (id, rest) = stored.split_at(KEY_ID_SIZE);
+        let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+        let mut id_bytes = [0u8; KEY_ID_SIZE];
+        id_bytes.copy_from_slice(id);
+        let id = u32::from_le_bytes(id_bytes);
+
+        self.instance(id, None)?
+            .decrypt(nonce, &aad(id, compressed, offset), ciphertext)
+            .ok_or(Error::Authentication)
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption")
+            .field("cipher", &self.cipher)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Data that is authenticated along with a record.
+fn aad(id: u32, compressed: bool, offset: usize) -> [u8; AAD_SIZE] {
+    let mut aad = [0u8; AAD_SIZE];
+    aad[..KEY_ID_SIZE].copy_from_slice(&id.to_le_bytes());
+    aad[KEY_ID_SIZE] = compressed as u8;
+    aad[(KEY_ID_SIZE + 1)..].copy_from_slice(&(offset as u64).to_le_bytes());
+    aad
+}
+
+#[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+mod codec {
+    use super::{Cipher, Key, NONCE_SIZE};
+    use crate::Error;
+
+    #[cfg(feature = "aes-gcm")]
+    use aes_gcm::aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload};
+    #[cfg(not(feature = "aes-gcm"))]
+    use chacha20poly1305::aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload};
+
+    pub enum Instance {
+        #[cfg(feature = "aes-gcm")]
+        Aes256Gcm(Box<aes_gcm::Aes256Gcm>),
+        #[cfg(feature = "chacha20poly1305")]
+        ChaCha20Poly1305(Box<chacha20poly1305::ChaCha20Poly1305>),
+    }
+
+    pub fn check(cipher: Cipher) -> Result<(), Error> {
+        match cipher {
+            #[cfg(feature = "aes-gcm")]
+            Cipher::Aes256Gcm => Ok(()),
+            #[cfg(feature = "chacha20poly1305")]
+            Cipher::ChaCha20Poly1305 => Ok(()),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::InvalidOptions("the cipher is disabled")),
+        }
+    }
+
+    pub fn nonce() -> [u8; NONCE_SIZE] {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        nonce
+    }
+
+    impl Instance {
+        pub fn new(cipher: Cipher, key: &Key) -> Result<Self, Error> {
+            match cipher {
+                #[cfg(feature = "aes-gcm")]
+                Cipher::Aes256Gcm => Ok(Instance::Aes256Gcm(Box::new(aes_gcm::Aes256Gcm::new(
+                    key.into(),
+                )))),
+                #[cfg(feature = "chacha20poly1305")]
+                Cipher::ChaCha20Poly1305 => Ok(Instance::ChaCha20Poly1305(Box::new(
+                    chacha20poly1305::ChaCha20Poly1305::new(key.into()),
+                ))),
+                #[allow(unreachable_patterns)]
+                _ => Err(Error::InvalidOptions("the cipher is disabled")),
+            }
+        }
+
+        pub fn encrypt(&self, nonce: &[u8], aad: &[u8], msg: &[u8]) -> Option<Vec<u8>> {
+            let payload = Payload { msg, aad };
+            match self {
+                #[cfg(feature = "aes-gcm")]
+                Instance::Aes256Gcm(cipher) => cipher.encrypt(nonce.into(), payload),
+                #[cfg(feature = "chacha20poly1305")]
+                Instance::ChaCha20Poly1305(cipher) => cipher.encrypt(nonce.into(), payload),
+            }
+            .ok()
+        }
+
+        pub fn decrypt(&self, nonce: &[u8], aad: &[u8], msg: &[u8]) -> Option<Vec<u8>> {
+            let payload = Payload { msg, aad };
+            match self {
+                #[cfg(feature = "aes-gcm")]
+                Instance::Aes256Gcm(cipher) => cipher.decrypt(nonce.into(), payload),
+                #[cfg(feature = "chacha20poly1305")]
+                Instance::ChaCha20Poly1305(cipher) => cipher.decrypt(nonce.into(), payload),
+            }
+            .ok()
+        }
+    }
+}
+
+/// Encryption is only available with the `aes-gcm` or `chacha20poly1305`
+/// features. Encrypted databases cannot be opened otherwise, so the cipher is
+/// never used.
+#[cfg(not(any(feature = "aes-gcm", feature = "chacha20poly1305")))]
+mod codec {
+    use super::{Cipher, Key, NONCE_SIZE};
+    use crate::Error;
+
+    pub enum Instance {}
+
+    pub fn check(_cipher: Cipher) -> Result<(), Error> {
+        Err(Error::InvalidOptions("the cipher is disabled"))
+    }
+
+    pub fn nonce() -> [u8; NONCE_SIZE] {
+        unreachable!("encryption is disabled")
+    }
+
+    impl Instance {
+        pub fn new(_cipher: Cipher, _key: &Key) -> Result<Self, Error> {
+            Err(Error::InvalidOptions("the cipher is disabled"))
+        }
+
+        pub fn encrypt(&self, _nonce: &[u8], _aad: &[u8], _msg: &[u8]) -> Option<Vec<u8>> {
+            match *self {}
+        }
+
+        pub fn decrypt(&self, _nonce: &[u8], _aad: &[u8], _msg: &[u8]) -> Option<Vec<u8>> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "aes-gcm", feature = "chacha20poly1305")))]
+mod tests {
+    use super::{Cipher, Encryption, Key, KeyProvider};
+    use crate::Error;
+    use std::{collections::BTreeMap, sync::Arc};
+
+    fn ciphers() -> Vec<Cipher> {
+        vec![
+            #[cfg(feature = "aes-gcm")]
+            Cipher::Aes256Gcm,
+            #[cfg(feature = "chacha20poly1305")]
+            Cipher::ChaCha20Poly1305,
+        ]
+    }
+
+    #[quickcheck]
+    fn seal_and_open(records: Vec<Vec<u8>>) {
+        for cipher in ciphers() {
+            let encryption = Encryption::new(cipher, Arc::new([7u8; 32])).unwrap();
+            let compressed: Vec<_> = (0..records.len()).map(|i| i % 2 == 0).collect();
+            let offsets: Vec<_> = (0..records.len()).map(|i| i * 100).collect();
+            let sealed = encryption.seal(&records, &compressed, &offsets).unwrap();
+
+            for (((record, stored), compressed), offset) in
+                records.iter().zip(sealed).zip(compressed).zip(offsets)
+            {
+                assert_eq!(Encryption::OVERHEAD + record.len(), stored.len());
+                assert_eq!(
+                    record,
+                    &encryption.open(&stored, compressed, offset).unwrap()
+                );
+                assert!(encryption.open(&stored, !compressed, offset).is_err());
+                // A record moved to another place is rejected.
+                assert!(encryption.open(&stored, compressed, offset + 1).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn tamper_and_rotate() {
+        for cipher in ciphers() {
+            let mut keys: BTreeMap<u32, Key> = BTreeMap::new();
+            keys.insert(1, [1u8; 32]);
+            let old = Encryption::new(cipher, Arc::new(keys.clone())).unwrap();
+            let sealed = old.seal(&[b"secret"], &[false], &[0]).unwrap().remove(0);
+
+            let mut tampered = sealed.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(matches!(
+                old.open(&tampered, false, 0),
+                Err(Error::Authentication)
+            ));
+
+            keys.insert(2, [2u8; 32]);
+            assert_eq!(2, keys.current_key().unwrap().0);
+            let new = Encryption::new(cipher, Arc::new(keys)).unwrap();
+            assert_eq!(b"secret", new.open(&sealed, false, 0).unwrap().as_slice());
+            let resealed = new.seal(&[b"secret"], &[false], &[0]).unwrap().remove(0);
+            assert!(matches!(
+                old.open(&resealed, false, 0),
+                Err(Error::UnknownKey(2))
+            ));
+
+            let empty = Encryption::new(cipher, Arc::new(BTreeMap::new())).unwrap();
+            assert!(matches!(
+                empty.seal(&[b"secret"], &[false], &[0]),
+                Err(Error::NoKey)
+            ));
+        }
+    }
+}
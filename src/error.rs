@@ -1,3 +1,4 @@
+use crate::QuotaUsage;
 use std::{error, fmt, io, path::PathBuf};
 
 /// Datbase error.
@@ -23,6 +24,68 @@ pub enum Error {
     Metadata(io::Error),
     /// Failed to make a memory mapping page immutable
     Protect(io::Error),
+    /// Failed to truncate a file
+    Truncate(io::Error),
+    /// The layout file is incorrect
+    LayoutDamaged,
+    /// Records in the data file are not prefixed with their lengths
+    NotFramed,
+    /// Failed to replace a database file with a new one
+    Replace(io::Error),
+    /// A record location does not fit into the sequential number index
+    IndexOverflow,
+    /// The record at this position in a batch has the wrong size
+    RecordSize {
+        index: usize,
+        expected: usize,
+        actual: usize,
+    },
+    /// Database options are inconsistent
+    InvalidOptions(&'static str),
+    /// Failed to write records
+    Write(io::Error),
+    /// Failed to read records
+    Read(io::Error),
+    /// The record with this number, counting from zero, in an import stream
+    /// is malformed
+    ImportFormat(usize),
+    /// The record with this sequential number is not valid UTF-8
+    NotUtf8(usize),
+    /// Failed to encode a value
+    Encode(Box<dyn error::Error + Send + Sync>),
+    /// Failed to decode the record with this sequential number
+    Decode(usize, Box<dyn error::Error + Send + Sync>),
+    /// Failed to compress a record
+    Compress(io::Error),
+    /// Failed to encrypt a record
+    Encrypt,
+    /// A record failed authentication: it was damaged or tampered with
+    Authentication,
+    /// The key provider does not know the key with this id
+    UnknownKey(u32),
+    /// The database is encrypted, but there is no key provider or it does
+    /// not have a key to encrypt new records with
+    NoKey,
+    /// The database does not have the expected number of records: somebody
+    /// else wrote to it
+    Conflict { actual_len: usize },
+    /// The batch is older than the batches remembered for its producer, so
+    /// it is unknown whether it was written
+    StaleSequence { producer_id: u64, sequence: u64 },
+    /// Failed to give a part of the data file back to the filesystem
+    PunchHole(io::Error),
+    /// A write would take the database over a limit set in the options
+    QuotaExceeded(QuotaUsage),
+    /// There is not enough space on the device. The write was rolled back,
+    /// so it can be retried once space is freed
+    NoSpace(io::Error),
+    /// The records were written starting from this sequential number, but
+    /// the oldest records of a capped database could not be evicted. They are
+    /// evicted by the next write
+    Evict {
+        seqno: Option<usize>,
+        source: Box<Error>,
+    },
 }
 
 impl error::Error for Error {
@@ -38,6 +101,30 @@ impl error::Error for Error {
             Error::Flush(source) => Some(source),
             Error::Metadata(source) => Some(source),
             Error::Protect(source) => Some(source),
+            Error::Truncate(source) => Some(source),
+            Error::LayoutDamaged => None,
+            Error::NotFramed => None,
+            Error::Replace(source) => Some(source),
+            Error::IndexOverflow => None,
+            Error::RecordSize { .. } => None,
+            Error::InvalidOptions(_) => None,
+            Error::Write(source) => Some(source),
+            Error::Read(source) => Some(source),
+            Error::ImportFormat(_) => None,
+            Error::NotUtf8(_) => None,
+            Error::Encode(source) => Some(source.as_ref()),
+            Error::Decode(_, source) => Some(source.as_ref()),
+            Error::Compress(source) => Some(source),
+            Error::Encrypt => None,
+            Error::Authentication => None,
+            Error::UnknownKey(_) => None,
+            Error::NoKey => None,
+            Error::Conflict { .. } => None,
+            Error::StaleSequence { .. } => None,
+            Error::PunchHole(source) => Some(source),
+            Error::QuotaExceeded(_) => None,
+            Error::NoSpace(source) => Some(source),
+            Error::Evict { source, .. } => Some(source.as_ref()),
         }
     }
 }
@@ -58,6 +145,63 @@ impl fmt::Display for Error {
             Error::Flush(_) => write!(f, "failed to flush database records to disk"),
             Error::Metadata(_) => write!(f, "failed to get file metadata"),
             Error::Protect(_) => write!(f, "failed to make a memory mapping page immutable"),
+            Error::Truncate(_) => write!(f, "failed to truncate a database file"),
+            Error::LayoutDamaged => write!(f, "layout file looks damaged"),
+            Error::NotFramed => write!(f, "data file does not hold record boundaries"),
+            Error::Replace(_) => write!(f, "failed to replace a database file"),
+            Error::IndexOverflow => write!(
+                f,
+                "record location does not fit into the sequential number index"
+            ),
+            Error::RecordSize {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "record #{} in the batch is {} bytes long instead of {}",
+                index, actual, expected
+            ),
+            Error::InvalidOptions(reason) => write!(f, "invalid options: {}", reason),
+            Error::Write(_) => write!(f, "failed to write records"),
+            Error::Read(_) => write!(f, "failed to read records"),
+            Error::ImportFormat(number) => {
+                write!(f, "record #{} in the import stream is malformed", number)
+            }
+            Error::NotUtf8(seqno) => write!(f, "record {} is not valid UTF-8", seqno),
+            Error::Encode(_) => write!(f, "failed to encode a value"),
+            Error::Decode(seqno, _) => write!(f, "failed to decode record {}", seqno),
+            Error::Compress(_) => write!(f, "failed to compress a record"),
+            Error::Encrypt => write!(f, "failed to encrypt a record"),
+            Error::Authentication => write!(f, "record failed authentication"),
+            Error::UnknownKey(id) => write!(f, "encryption key {} is unknown", id),
+            Error::NoKey => write!(f, "there is no encryption key"),
+            Error::Conflict { actual_len } => {
+                write!(
+                    f,
+                    "database has {} records, which is unexpected",
+                    actual_len
+                )
+            }
+            Error::StaleSequence {
+                producer_id,
+                sequence,
+            } => write!(
+                f,
+                "batch {} of producer {} is too old to be deduplicated",
+                sequence, producer_id
+            ),
+            Error::PunchHole(_) => write!(f, "failed to punch a hole in the data file"),
+            Error::QuotaExceeded(usage) => write!(
+                f,
+                "{} quota of {} exceeded: {} needed",
+                usage.quota, usage.max, usage.used
+            ),
+            Error::NoSpace(_) => write!(f, "not enough space on the device"),
+            Error::Evict { .. } => write!(
+                f,
+                "records were written, but old records could not be evicted"
+            ),
         }
     }
 }
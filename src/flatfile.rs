@@ -1,15 +1,129 @@
-use crate::{Appender, Error, SharedMmap};
-use std::{io::Write, path::PathBuf};
+use crate::{encryption::Encryption, quota::Quota, Appender, Compression, Error, SharedMmap};
+use memmap2::{Mmap, MmapMut};
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, Read, Write},
+    mem::{self, size_of},
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+};
+
+/// The size of the length prefix of a framed record.
+const FRAME_HEADER_SIZE: usize = size_of::<u64>();
+/// This bit of a frame header is set if the record is compressed.
+const COMPRESSED: u64 = 1 << 63;
+/// This bit is set in every frame header, so zeros left by an interrupted
+/// write are not read as empty records.
+const MARKER: u64 = 1 << 62;
+/// How much space is reserved at once when reading a record of unknown size.
+const READ_CHUNK_SIZE: usize = 1 << 20;
 
 /// Flatfiles are the main database files that hold all keys and data.
 ///
 /// Records are stored without any additional spaces. The file does not hold any
-/// additional data.
+/// additional data unless it is framed: in this case every record is prefixed
+/// with its length as a little-endian `u64`. If records are compressed, the
+/// highest bit of the length tells whether this particular record is. The
+/// next bit is always set.
+/// Encrypted records are compressed first.
 ///
 /// A flatfile is opened with `mmap` and we rely on OS's mechanisms for caching
 /// pages, etc.
 pub(crate) struct FlatFile {
     inner: Appender,
+    framed: bool,
+    compression: Compression,
+    encryption: Option<Encryption>,
+}
+
+/// Records written to a flatfile but not yet visible to readers.
+pub(crate) struct Page {
+    /// `None` if all records are empty: mappings cannot be empty.
+    mmap: Option<MmapMut>,
+    /// How many bytes every record takes in the file.
+    pub lens: Vec<usize>,
+}
+
+/// Records stored in a flatfile that are not yet visible to readers. Making
+/// them visible cannot fail anymore.
+pub(crate) struct Sealed {
+    mmap: Option<Mmap>,
+}
+
+/// A page reserved for records that gives the space back when it is dropped
+/// before it is handed out, e.g. because a callback that fills it panicked.
+struct Reserved<'a> {
+    appender: &'a Appender,
+    page: Option<MmapMut>,
+}
+
+impl<'a> Reserved<'a> {
+    fn new(appender: &'a Appender, size: usize) -> Result<Self, Error> {
+        let page = appender.reserve(size)?;
+        Ok(Self {
+            appender,
+            page: Some(page),
+        })
+    }
+
+    /// Hand out the page. It has to be committed or aborted from now on.
+    fn into_page(mut self) -> MmapMut {
+        self.page.take().unwrap()
+    }
+
+    fn abort(self) -> Result<(), Error> {
+        let appender = self.appender;
+        appender.abort(self.into_page())
+    }
+}
+
+impl Deref for Reserved<'_> {
+    type Target = MmapMut;
+
+    fn deref(&self) -> &MmapMut {
+        self.page.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Reserved<'_> {
+    fn deref_mut(&mut self) -> &mut MmapMut {
+        self.page.as_mut().unwrap()
+    }
+}
+
+impl Drop for Reserved<'_> {
+    fn drop(&mut self) {
+        if let Some(page) = self.page.take() {
+            // Nothing can be reported while unwinding. If the file cannot be
+            // shrunk, the next write truncates it anyway.
+            let _ = self.appender.abort(page);
+        }
+    }
+}
+
+/// A framed record as it is stored in the file.
+pub(crate) struct Frame {
+    pub data: SharedMmap,
+    pub compressed: bool,
+    /// Where the frame starts in the file.
+    pub offset: usize,
+}
+
+impl Frame {
+    /// A record at `offset` that is stored as it is.
+    pub fn raw(data: SharedMmap, offset: usize) -> Self {
+        Self {
+            data,
+            compressed: false,
+            offset,
+        }
+    }
+
+    /// The number of bytes the frame takes in the file.
+    pub fn stored_len(&self) -> usize {
+        FRAME_HEADER_SIZE + self.data.len()
+    }
 }
 
 /// Low-level interface to flatfiles.
@@ -19,38 +133,307 @@ impl FlatFile {
     /// # Arguments
     ///
     /// * `path` - the path to the file. It will be created if not exists.
-    pub fn new(path: Option<PathBuf>) -> Result<Self, Error> {
-        Appender::new(path).map(|inner| FlatFile { inner })
+    /// * `framed` - whether records are prefixed with their lengths.
+    /// * `compression` - how records are compressed. Requires `framed`.
+    /// * `encryption` - how records are encrypted. Requires `framed`.
+    pub fn new(
+        path: Option<PathBuf>,
+        framed: bool,
+        compression: Compression,
+        encryption: Option<Encryption>,
+    ) -> Result<Self, Error> {
+        Appender::new(path).map(|inner| FlatFile {
+            inner,
+            framed,
+            compression,
+            encryption,
+        })
     }
 
-    /// Write an array of records to the drive. This function will block if
-    /// another write is still in progress.
-    pub fn append(&self, records: &[&[u8]]) -> Result<(), Error> {
-        if records.is_empty() {
-            return Ok(());
+    /// Fail to grow the file over `max` bytes.
+    pub fn with_quota(self, max: Option<usize>) -> Self {
+        Self {
+            inner: self.inner.with_quota(Quota::DataSize, max),
+            ..self
         }
+    }
 
-        let size_inc: usize = records
-            .iter()
-            .map(|record| {
-                assert!(!record.is_empty(), "empty records are not supported");
-                record.len()
-            })
-            .sum();
+    /// Whether records are stored differently from how they are given.
+    fn is_transformed(&self) -> bool {
+        self.compression != Compression::None || self.encryption.is_some()
+    }
+
+    /// Whether the record in `frame` is stored as it is, so that it can be
+    /// copied from the file directly.
+    pub fn is_plain(&self, frame: &Frame) -> bool {
+        !frame.compressed && self.encryption.is_none()
+    }
+
+    pub fn is_framed(&self) -> bool {
+        self.framed
+    }
+
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
 
-        self.inner.append(size_inc, move |mut mmap| {
-            for record in records {
-                mmap.write_all(record).unwrap();
+    /// The number of bytes a record of the given length takes in the file.
+    pub fn stored_len(&self, len: usize) -> usize {
+        if self.framed {
+            FRAME_HEADER_SIZE + len
+        } else {
+            len
+        }
+    }
+
+    /// The header of a frame holding `len` bytes.
+    fn frame_header(&self, len: usize, compressed: bool) -> [u8; FRAME_HEADER_SIZE] {
+        let mut header = len as u64 | MARKER;
+        if compressed {
+            header |= COMPRESSED;
+        }
+        header.to_le_bytes()
+    }
+
+    /// Write an array of records to the drive. The records do not become
+    /// visible until the returned page is sealed and published. This function
+    /// will block if another write is still in progress.
+    pub fn append(&self, records: &[&[u8]]) -> Result<Page, Error> {
+        let mut compressed = Vec::with_capacity(records.len());
+        let mut stored = Vec::with_capacity(records.len());
+        for record in records {
+            match self.compression.compress(record)? {
+                Some(record) => {
+                    compressed.push(true);
+                    stored.push(Cow::Owned(record));
+                }
+                None => {
+                    compressed.push(false);
+                    stored.push(Cow::Borrowed(*record));
+                }
             }
+        }
+
+        if let Some(encryption) = &self.encryption {
+            // Records are written at the end of the file.
+            let mut offset = self.len();
+            let offsets: Vec<_> = stored
+                .iter()
+                .map(|record| {
+                    let start = offset;
+                    offset += self.stored_len(Encryption::OVERHEAD + record.len());
+                    start
+                })
+                .collect();
+            stored = encryption
+                .seal(&stored, &compressed, &offsets)?
+                .into_iter()
+                .map(Cow::Owned)
+                .collect();
+        }
+
+        let lens: Vec<_> = stored
+            .iter()
+            .map(|record| self.stored_len(record.len()))
+            .collect();
+
+        let size: usize = lens.iter().sum();
+        if size == 0 {
+            return Ok(Page { mmap: None, lens });
+        }
+        let mut page = self.inner.reserve(size)?;
+
+        let written = {
+            let mut mmap = page.as_mut();
+            stored
+                .iter()
+                .zip(compressed)
+                .try_for_each(|(record, compressed)| {
+                    if self.framed {
+                        mmap.write_all(&self.frame_header(record.len(), compressed))?;
+                    }
+                    mmap.write_all(record)
+                })
+        };
+        if let Err(err) = written {
+            self.inner.abort(page)?;
+            return Err(Error::Write(err));
+        }
+
+        Ok(Page {
+            mmap: Some(page),
+            lens,
         })
     }
 
+    /// Reserve space for records of the given sizes and give `f` a buffer for
+    /// each of them to fill. The records do not become visible until the
+    /// returned page is sealed and published. If `f` fails, the space is
+    /// released.
+    pub fn write<F, E>(&self, sizes: &[usize], f: F) -> Result<Page, E>
+    where
+        F: FnOnce(&mut [&mut [u8]]) -> Result<(), E>,
+        E: From<Error>,
+    {
+        // Records have to be compressed or encrypted before they are written,
+        // so they are collected in memory first.
+        if self.is_transformed() {
+            let mut records: Vec<_> = sizes.iter().map(|size| vec![0u8; *size]).collect();
+            let mut buffers: Vec<_> = records.iter_mut().map(Vec::as_mut_slice).collect();
+            f(&mut buffers)?;
+            let records: Vec<_> = records.iter().map(Vec::as_slice).collect();
+            return Ok(self.append(&records)?);
+        }
+
+        let size_inc: usize = sizes.iter().map(|size| self.stored_len(*size)).sum();
+        let lens = sizes.iter().map(|size| self.stored_len(*size)).collect();
+
+        if size_inc == 0 {
+            let mut buffers: Vec<&mut [u8]> = sizes.iter().map(|_| &mut [][..]).collect();
+            f(&mut buffers)?;
+            return Ok(Page { mmap: None, lens });
+        }
+
+        let mut page = Reserved::new(&self.inner, size_inc)?;
+
+        let result = {
+            let mut buffers = Vec::with_capacity(sizes.len());
+            let mut mmap = page.as_mut();
+            for size in sizes {
+                if self.framed {
+                    let (header, rest) = mem::take(&mut mmap).split_at_mut(FRAME_HEADER_SIZE);
+                    header.copy_from_slice(&self.frame_header(*size, false));
+                    mmap = rest;
+                }
+                let (buffer, rest) = mem::take(&mut mmap).split_at_mut(*size);
+                buffers.push(buffer);
+                mmap = rest;
+            }
+            f(&mut buffers)
+        };
+
+        match result {
+            Ok(()) => Ok(Page {
+                mmap: Some(page.into_page()),
+                lens,
+            }),
+            Err(err) => {
+                page.abort()?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Write a single record read from `reader`. If `len_hint` is given, that
+    /// much space is reserved upfront. The space grows as needed while reading.
+    /// Returns the page for `seal` and the length of the record, which is
+    /// empty if the reader did not return any data.
+    pub fn write_from_reader<R: Read>(
+        &self,
+        mut reader: R,
+        len_hint: Option<usize>,
+    ) -> Result<(Page, usize), Error> {
+        if self.is_transformed() {
+            let mut record = Vec::with_capacity(len_hint.unwrap_or(0));
+            reader.read_to_end(&mut record).map_err(Error::Read)?;
+            return Ok((self.append(&[&record])?, record.len()));
+        }
+
+        let header = self.stored_len(0);
+        let mut capacity = len_hint.unwrap_or(READ_CHUNK_SIZE).max(1);
+        let mut page = Reserved::new(&self.inner, header + capacity)?;
+        let mut len = 0;
+
+        let result = loop {
+            let read = if len < capacity {
+                read_some(&mut reader, &mut page[(header + len)..])
+            } else {
+                // Check if there is more data before growing the page: the
+                // length is usually known exactly.
+                let mut probe = [0u8; 4096];
+                read_some(&mut reader, &mut probe).and_then(|n| {
+                    if n > 0 {
+                        capacity = capacity.max(READ_CHUNK_SIZE) * 2;
+                        self.inner.resize(&mut page, header + capacity)?;
+                        page[(header + len)..(header + len + n)].copy_from_slice(&probe[..n]);
+                    }
+                    Ok(n)
+                })
+            };
+
+            match read {
+                Ok(0) if len == 0 => break Ok(false),
+                Ok(0) if len == capacity => break Ok(true),
+                Ok(0) => break self.inner.resize(&mut page, header + len).map(|_| true),
+                Ok(n) => len += n,
+                Err(err) => break Err(err),
+            }
+        };
+
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                page.abort()?;
+                return Ok((self.append(&[&[]])?, 0));
+            }
+            Err(err) => {
+                page.abort()?;
+                return Err(err);
+            }
+        }
+
+        if self.framed {
+            page[..FRAME_HEADER_SIZE].copy_from_slice(&self.frame_header(len, false));
+        }
+
+        let page = Page {
+            mmap: Some(page.into_page()),
+            lens: vec![self.stored_len(len)],
+        };
+        Ok((page, len))
+    }
+
+    /// Store records written with `append` or `write` in the file without
+    /// making them visible. If this fails, the records are dropped.
+    pub fn seal(&self, page: Page) -> Result<Sealed, Error> {
+        let mmap = page.mmap.map(|mmap| self.inner.seal(mmap)).transpose()?;
+        Ok(Sealed { mmap })
+    }
+
+    /// Make sealed records visible to readers.
+    pub fn publish(&self, sealed: Sealed) {
+        if let Some(mmap) = sealed.mmap {
+            self.inner.publish(mmap);
+        }
+    }
+
+    /// Drop sealed records.
+    pub fn discard(&self, sealed: Sealed) -> Result<(), Error> {
+        match sealed.mmap {
+            Some(mmap) => self.inner.discard(mmap),
+            None => Ok(()),
+        }
+    }
+
+    /// Drop records written with `append` or `write`.
+    pub fn abort(&self, page: Page) -> Result<(), Error> {
+        match page.mmap {
+            Some(mmap) => self.inner.abort(mmap),
+            None => Ok(()),
+        }
+    }
+
     /// Get the value at the given `offset`. If the `offset` is outside of the
     /// file boundaries, `None` is returned. Upon a successul read a key-value
     /// record is returned. Note that this function do not check if the given
     /// `offset` is the start of an actual record, so you should be careful when
     /// using it.
     pub fn get_record_at_offset(&self, offset: usize, length: usize) -> Option<SharedMmap> {
+        // Empty records may be at the end of the file, outside of mappings.
+        if length == 0 {
+            return (offset <= self.len()).then(|| SharedMmap::from(Vec::new()));
+        }
+
         self.inner.get_data(offset, move |mmap| {
             if mmap.len() < length {
                 return None;
@@ -60,31 +443,168 @@ impl FlatFile {
         })
     }
 
+    /// Get a view that starts at the given `offset` and ends at the end of
+    /// its mapping or at the next hole.
+    pub fn get_mapping_at_offset(&self, offset: usize) -> Option<SharedMmap> {
+        self.inner.get_data(offset, Some)
+    }
+
+    /// Get the framed record that starts at the given `offset`. `None` is
+    /// returned if the record does not fit into the file or its header lacks
+    /// the marker.
+    pub fn get_frame_at_offset(&self, offset: usize) -> Option<Frame> {
+        self.inner.get_data(offset, move |mmap| {
+            if mmap.len() < FRAME_HEADER_SIZE {
+                return None;
+            }
+
+            let mut length = [0u8; FRAME_HEADER_SIZE];
+            length.copy_from_slice(&mmap.as_ref()[..FRAME_HEADER_SIZE]);
+            let header = u64::from_le_bytes(length);
+            if header & MARKER == 0 {
+                return None;
+            }
+            let length = (header & !(COMPRESSED | MARKER)) as usize;
+
+            if mmap.len() - FRAME_HEADER_SIZE < length {
+                return None;
+            }
+
+            Some(Frame {
+                data: mmap.slice(FRAME_HEADER_SIZE..(FRAME_HEADER_SIZE + length)),
+                compressed: header & COMPRESSED != 0,
+                offset,
+            })
+        })
+    }
+
+    /// Get the content of a record stored in `frame`.
+    pub fn decode(&self, frame: Frame) -> Result<SharedMmap, Error> {
+        let data = match &self.encryption {
+            Some(encryption) => {
+                SharedMmap::from(encryption.open(&frame.data, frame.compressed, frame.offset)?)
+            }
+            None => frame.data,
+        };
+
+        if !frame.compressed {
+            return Ok(data);
+        }
+
+        self.compression.decompress(&data).map(SharedMmap::from)
+    }
+
+    /// Walk through the framed records from the beginning of the file. Returns
+    /// the locations of all complete records and the offset where the last of
+    /// them ends. Only an incomplete record or zeros left by an interrupted
+    /// write may follow it, anything else means that the file is damaged.
+    pub fn frames(&self) -> Result<(Vec<u64>, usize), Error> {
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+
+        while let Some(frame) = self.get_frame_at_offset(offset) {
+            offsets.push(offset as u64);
+            offset += frame.stored_len();
+        }
+
+        if offset < self.len() && !self.is_tail(offset) {
+            return Err(Error::DataFileDamaged);
+        }
+
+        Ok((offsets, offset))
+    }
+
+    /// Whether the data from `offset` to the end of the file was left by an
+    /// interrupted write: an incomplete header, a record that does not fit
+    /// into the file or zeros.
+    fn is_tail(&self, offset: usize) -> bool {
+        self.inner
+            .get_data(offset, |mmap| {
+                if mmap.len() < FRAME_HEADER_SIZE {
+                    return Some(true);
+                }
+
+                let mut header = [0u8; FRAME_HEADER_SIZE];
+                header.copy_from_slice(&mmap.as_ref()[..FRAME_HEADER_SIZE]);
+                let header = u64::from_le_bytes(header);
+                let length = (header & !(COMPRESSED | MARKER)) as usize;
+                let incomplete = header & MARKER != 0 && mmap.len() - FRAME_HEADER_SIZE < length;
+                Some(incomplete || mmap.iter().all(|byte| *byte == 0))
+            })
+            .unwrap_or(false)
+    }
+
     pub fn len(&self) -> usize {
         self.inner.size()
     }
+
+    pub fn mappings(&self) -> usize {
+        self.inner.mappings()
+    }
+
+    pub fn file(&self) -> Option<&File> {
+        self.inner.file()
+    }
+
+    /// Overwrite `len` bytes at `offset` with zeros. Only works for files.
+    pub fn zero(&self, offset: usize, len: usize) -> Result<(), Error> {
+        self.inner.zero(offset, len)
+    }
+
+    /// Give `len` bytes at `offset` back to the filesystem or free them in
+    /// memory. Only works for files on Linux and in-memory flatfiles.
+    pub fn punch(&self, offset: usize, len: usize) -> Result<(), Error> {
+        self.inner.punch(offset, len)
+    }
+
+    /// Fail if holes cannot be punched, e.g. on other systems than Linux.
+    pub fn check_punch(&self) -> Result<(), Error> {
+        self.inner.check_punch()
+    }
+
+    pub fn has_holes(&self) -> bool {
+        self.inner.has_holes()
+    }
+
+    /// Cut a closed flatfile to `len` bytes.
+    pub fn truncate(path: &Path, len: usize) -> Result<(), Error> {
+        Appender::truncate(path, len)
+    }
+}
+
+fn read_some<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, Error> {
+    loop {
+        match reader.read(buf) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result.map_err(Error::Read),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::FlatFile;
+    use crate::Compression;
 
     #[quickcheck]
     fn test_read_write(records: Vec<Vec<u8>>) {
-        if records.is_empty() {
-            return;
-        }
-
         let tmp = tempfile::NamedTempFile::new().unwrap();
 
-        let raw_records: Vec<_> = records
-            .iter()
-            .filter(|x| !x.is_empty())
-            .map(|x| x.as_ref())
-            .collect();
+        let raw_records: Vec<_> = records.iter().map(|x| x.as_ref()).collect();
+
+        if raw_records.is_empty() {
+            return;
+        }
 
-        let flatfile = FlatFile::new(Some(tmp.path().to_path_buf())).unwrap();
-        flatfile.append(&raw_records).unwrap();
+        let flatfile = FlatFile::new(
+            Some(tmp.path().to_path_buf()),
+            false,
+            Compression::None,
+            None,
+        )
+        .unwrap();
+        let page = flatfile.append(&raw_records).unwrap();
+        flatfile.publish(flatfile.seal(page).unwrap());
 
         let mut offset = 0;
         for record in raw_records.iter() {
@@ -93,4 +613,52 @@ mod tests {
             offset += drive_record.len();
         }
     }
+
+    #[quickcheck]
+    fn test_write_from_reader(record: Vec<u8>, len_hint: Option<u8>, framed: bool) {
+        let flatfile = FlatFile::new(None, framed, Compression::None, None).unwrap();
+        let len_hint = len_hint.map(usize::from);
+
+        let (page, len) = flatfile
+            .write_from_reader(record.as_slice(), len_hint)
+            .unwrap();
+        assert_eq!(record.len(), len);
+        flatfile.publish(flatfile.seal(page).unwrap());
+
+        let drive_record = if framed {
+            flatfile.get_frame_at_offset(0).unwrap().data
+        } else {
+            flatfile.get_record_at_offset(0, record.len()).unwrap()
+        };
+        assert_eq!(record, drive_record.as_ref());
+        assert_eq!(flatfile.stored_len(record.len()), flatfile.len());
+    }
+
+    #[quickcheck]
+    fn test_frames(records: Vec<Vec<u8>>) {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+
+        let raw_records: Vec<_> = records.iter().map(|x| x.as_ref()).collect();
+
+        let flatfile = FlatFile::new(
+            Some(tmp.path().to_path_buf()),
+            true,
+            Compression::None,
+            None,
+        )
+        .unwrap();
+        if !raw_records.is_empty() {
+            let page = flatfile.append(&raw_records).unwrap();
+            flatfile.publish(flatfile.seal(page).unwrap());
+        }
+
+        let (offsets, end) = flatfile.frames().unwrap();
+        assert_eq!(raw_records.len(), offsets.len());
+        assert_eq!(flatfile.len(), end);
+
+        for (record, offset) in raw_records.iter().zip(offsets) {
+            let drive_record = flatfile.get_frame_at_offset(offset as usize).unwrap().data;
+            assert_eq!(*record, drive_record.as_ref());
+        }
+    }
 }
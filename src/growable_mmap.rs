@@ -1,71 +1,559 @@
 use crate::{
+    appender::write_at,
+    holes::{self, Holes},
     page_index::{PageDescriptor, PageIndex},
     Error, SharedMmap,
 };
-use memmap2::{MmapMut, MmapOptions};
-use std::fs::File;
+use memmap2::{Mmap, MmapMut, MmapOptions};
+use std::{
+    fs::File,
+    io,
+    ops::Range,
+    sync::{
+        atomic::{fence, Ordering},
+        PoisonError, RwLock,
+    },
+};
 
 pub struct GrowableMmap {
+    pages: RwLock<Pages>,
+    file: Option<File>,
+    holes: Holes,
+    /// Ranges that are stored to be zeroed, so that this is done when the
+    /// file is opened again if views into them are held until it is closed.
+    zeroing: Holes,
+    /// Ranges to be zeroed or punched once there are no views into their
+    /// pages: data cannot change under a view. New views into them are not
+    /// handed out. Views are only created while holding the read lock, which
+    /// is taken before the lock of the pages.
+    pending: RwLock<Vec<(Range<usize>, Reclaim)>>,
+}
+
+/// The mapped pages. Pages at the front are dropped once they only hold
+/// holes, so their numbers in the index go down.
+struct Pages {
     index: PageIndex,
     maps: Vec<SharedMmap>,
-    file: Option<File>,
 }
 
-impl GrowableMmap {
-    pub fn new(file: Option<File>) -> Result<Self, Error> {
-        let index = PageIndex::new();
-        let maps = vec![];
+/// Pages are merged with the pages written after them up to this size, so
+/// that small writes do not take a mapping each.
+const MERGE_SIZE: usize = 1 << 20;
+
+/// How the space of a range is reclaimed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reclaim {
+    /// Overwrite the data with zeros.
+    Zero,
+    /// Punch a hole in the file or free the memory.
+    Punch,
+}
 
-        let mut growable_mmap = GrowableMmap { index, maps, file };
+impl GrowableMmap {
+    pub fn new(file: Option<File>, holes: Holes, zeroing: Holes) -> Result<Self, Error> {
+        let mut pages = Pages {
+            index: PageIndex::new(),
+            maps: vec![],
+        };
+        // Stored holes may not have been punched if the process stopped in
+        // between, so they are punched again with the next hole.
+        let pending = holes
+            .ranges()
+            .into_iter()
+            .map(|range| (range, Reclaim::Punch))
+            .collect();
 
-        if let Some(file) = &growable_mmap.file {
+        if let Some(file) = &file {
             if file.metadata().map_err(Error::Metadata)?.len() > 0 {
                 let mmap =
                     SharedMmap::new(unsafe { MmapOptions::new().map(file) }.map_err(Error::Mmap)?);
-                growable_mmap.index.add_page(mmap.len());
-                growable_mmap.maps.push(mmap);
+                pages.index.add_page(mmap.len());
+                pages.maps.push(mmap);
+            }
+
+            // There are no views yet, so the ranges left by the previous run
+            // are zeroed right away.
+            let ranges = zeroing.ranges();
+            for range in ranges.iter() {
+                zero(file, range.start, range.end - range.start)?;
+            }
+            if !ranges.is_empty() {
+                zeroing.clear()?;
             }
         }
 
-        Ok(growable_mmap)
+        Ok(GrowableMmap {
+            pages: RwLock::new(pages),
+            file,
+            holes,
+            zeroing,
+            pending: RwLock::new(pending),
+        })
+    }
+
+    /// The size of the mapped pages, including the pages that were dropped.
+    fn memory_size(&self) -> usize {
+        self.pages
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .index
+            .memory_size()
     }
 
     pub fn grow(&self, add: usize) -> Result<MmapMut, Error> {
-        assert_ne!(add, 0, "no grow in file size");
+        if add == 0 {
+            return Err(Error::Extend(no_growth()));
+        }
 
         if let Some(file) = &self.file {
-            let current_len = self.index.memory_size();
+            let current_len = self.memory_size();
 
             let new_len = current_len + add;
 
-            file.set_len(new_len as u64).map_err(Error::Extend)?;
+            file.set_len(new_len as u64).map_err(extend_error)?;
 
-            return unsafe { MmapOptions::new().offset(current_len as u64).map_mut(file) }
-                .map_err(Error::Mmap);
+            let page = allocate(file, current_len, add)
+                .map_err(extend_error)
+                .and_then(|()| {
+                    unsafe { MmapOptions::new().offset(current_len as u64).map_mut(file) }
+                        .map_err(Error::Mmap)
+                });
+            if page.is_err() {
+                rollback(file, current_len);
+            }
+            return page;
         }
 
         MmapOptions::new().len(add).map_anon().map_err(Error::Mmap)
     }
 
-    pub fn append_page(&mut self, page: MmapMut) -> Result<(), Error> {
-        let current_len = self.index.memory_size();
-        let new_len = current_len + page.len();
+    /// Change the size of a page created by `grow`. The data that fits into
+    /// the new size is preserved.
+    pub fn resize(&self, page: &mut MmapMut, new_len: usize) -> Result<(), Error> {
+        if new_len == 0 {
+            return Err(Error::Extend(no_growth()));
+        }
+
+        if let Some(file) = &self.file {
+            let current_len = self.memory_size();
+
+            file.set_len((current_len + new_len) as u64)
+                .map_err(extend_error)?;
 
-        page.flush().map_err(Error::Flush)?;
+            // Both mappings share the page cache, so the written data is
+            // already visible through the new one.
+            let new_page = new_len
+                .checked_sub(page.len())
+                .map_or(Ok(()), |add| allocate(file, current_len + page.len(), add))
+                .map_err(extend_error)
+                .and_then(|()| {
+                    unsafe { MmapOptions::new().offset(current_len as u64).map_mut(file) }
+                        .map_err(Error::Mmap)
+                });
+            match new_page {
+                Ok(new_page) => *page = new_page,
+                Err(err) => {
+                    rollback(file, current_len + page.len());
+                    return Err(err);
+                }
+            }
+
+            return Ok(());
+        }
 
-        let page = SharedMmap::new(page.make_read_only().map_err(Error::Protect)?);
-        self.maps.push(page);
-        self.index.add_page(new_len);
+        let mut new_page = MmapOptions::new()
+            .len(new_len)
+            .map_anon()
+            .map_err(Error::Mmap)?;
+        let len = std::cmp::min(page.len(), new_len);
+        new_page[..len].copy_from_slice(&page[..len]);
+        *page = new_page;
 
         Ok(())
     }
 
+    /// Drop a page created by `grow` without adding it to the mapping and
+    /// return the file to its previous size.
+    pub fn discard(&self, page: MmapMut) -> Result<(), Error> {
+        drop(page);
+        self.shrink()
+    }
+
+    /// Drop a page sealed by `seal` without adding it to the mapping and
+    /// return the file to its previous size.
+    pub fn discard_sealed(&self, page: Mmap) -> Result<(), Error> {
+        drop(page);
+        self.shrink()
+    }
+
+    /// Cut the file after the mapped pages.
+    fn shrink(&self) -> Result<(), Error> {
+        if let Some(file) = &self.file {
+            file.set_len(self.memory_size() as u64)
+                .map_err(Error::Truncate)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the data of a page created by `grow` to the file and make the
+    /// page read-only. If this fails, the page is dropped and the file returns
+    /// to its previous size.
+    pub fn seal(&self, page: MmapMut) -> Result<Mmap, Error> {
+        let sealed = page
+            .flush()
+            .map_err(flush_error)
+            .and_then(|()| page.make_read_only().map_err(Error::Protect));
+        if sealed.is_err() {
+            if let Some(file) = &self.file {
+                rollback(file, self.memory_size());
+            }
+        }
+        sealed
+    }
+
+    /// Make a page sealed by `seal` a part of the mapping. Small pages that
+    /// are not viewed are merged into one.
+    pub fn append_page(&self, page: Mmap) {
+        let mut pages = self.pages.write().unwrap_or_else(PoisonError::into_inner);
+        let new_len = pages.index.memory_size() + page.len();
+        pages.maps.push(SharedMmap::new(page));
+        pages.index.add_page(new_len);
+
+        while let [.., previous, last] = pages.maps.as_slice() {
+            let len = previous.len() + last.len();
+            if len > MERGE_SIZE
+                || previous.len() > 2 * last.len()
+                || previous.is_shared()
+                || last.is_shared()
+            {
+                break;
+            }
+
+            // Merging is only done to save mappings, so it is fine if it
+            // fails.
+            let offset = pages.index.memory_size() - len;
+            let merged = match &self.file {
+                Some(file) => unsafe {
+                    MmapOptions::new().offset(offset as u64).len(len).map(file)
+                },
+                None => MmapOptions::new()
+                    .len(len)
+                    .map_anon()
+                    .and_then(|mut merged| {
+                        merged[..previous.len()].copy_from_slice(previous);
+                        merged[previous.len()..].copy_from_slice(last);
+                        merged.make_read_only()
+                    }),
+            };
+            let merged = match merged {
+                Ok(merged) => SharedMmap::new(merged),
+                Err(_) => break,
+            };
+
+            pages.maps.pop();
+            *pages.maps.last_mut().expect("there are two pages") = merged;
+            pages.index.merge_last();
+        }
+    }
+
+    pub fn file(&self) -> Option<&File> {
+        self.file.as_ref()
+    }
+
+    /// The number of memory mappings backing this file.
+    pub fn mappings(&self) -> usize {
+        self.pages
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .maps
+            .len()
+    }
+
+    pub fn holes(&self) -> &Holes {
+        &self.holes
+    }
+
+    pub fn zeroing(&self) -> &Holes {
+        &self.zeroing
+    }
+
+    /// Fail if holes cannot be punched in the file. Memory of anonymous
+    /// mappings can always be freed.
+    pub fn check_punch(&self) -> Result<(), Error> {
+        match &self.file {
+            Some(file) => holes::check_punch(file),
+            None => Ok(()),
+        }
+    }
+
+    /// Free the memory of anonymous mappings that holds `len` bytes at
+    /// `offset`. Only whole memory pages are freed.
+    pub fn free(&self, offset: usize, len: usize) -> Result<(), Error> {
+        let pages = self.pages.read().unwrap_or_else(PoisonError::into_inner);
+        let end = offset + len;
+        let mut address = offset;
+        while let Some(page) = pages.index.find(address) {
+            let page_end = (page.offset + page.len).min(end);
+            holes::free(&pages.maps[page.number][address - page.offset..page_end - page.offset])?;
+            if page_end == end {
+                break;
+            }
+            address = page_end;
+        }
+        Ok(())
+    }
+
+    /// Get a view that starts at `address` and ends at the end of its page
+    /// or at the next hole. There are no views into holes and ranges that
+    /// are about to be reclaimed.
     pub fn get_ref(&self, address: usize) -> Option<SharedMmap> {
+        let pending = self.pending.read().unwrap_or_else(PoisonError::into_inner);
+        let pages = self.pages.read().unwrap_or_else(PoisonError::into_inner);
         let PageDescriptor {
             len,
             offset,
             number,
-        } = self.index.find(address)?;
-        Some(self.maps[number].slice((address - offset)..len))
+        } = pages.index.find(address)?;
+        let mut end = self.holes.limit(address, offset + len)?;
+        for (range, _) in pending.iter() {
+            if range.contains(&address) {
+                return None;
+            }
+            if range.start > address {
+                end = end.min(range.start);
+            }
+        }
+        Some(pages.maps[number].slice((address - offset)..(end - offset)))
+    }
+
+    /// Reclaim the space of `range` once there are no views into its pages.
+    /// Views into the range are not handed out from now on.
+    pub fn schedule(&self, range: Range<usize>, reclaim: Reclaim) -> Result<(), Error> {
+        self.pending
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((range, reclaim));
+        self.reclaim()
+    }
+
+    /// Reclaim the space of scheduled ranges that are not viewed anymore.
+    /// Ranges that fail do not keep others from being reclaimed. Ranges that
+    /// fail to be zeroed stay scheduled and are tried again next time. Holes
+    /// that fail to be punched are dropped: they are still not read, and
+    /// punching them is tried again when the file is opened again. Pages at
+    /// the front that only hold holes are unmapped. Returns the first
+    /// failure.
+    pub fn reclaim(&self) -> Result<(), Error> {
+        // No new views can be created while the lock is held.
+        let mut pending = self.pending.write().unwrap_or_else(PoisonError::into_inner);
+        let mut result = Ok(());
+        let mut index = 0;
+        while index < pending.len() {
+            let (range, reclaim) = pending[index].clone();
+            if self.is_viewed(range.clone()) {
+                index += 1;
+                continue;
+            }
+
+            // Synchronize with the release of the last view.
+            fence(Ordering::Acquire);
+            let len = range.end - range.start;
+            let reclaimed = match (reclaim, &self.file) {
+                (Reclaim::Zero, Some(file)) => zero(file, range.start, len),
+                (Reclaim::Punch, Some(file)) => holes::punch(file, range.start, len),
+                (_, None) => self.free(range.start, len),
+            };
+            match reclaimed {
+                Err(err) if reclaim == Reclaim::Zero => {
+                    result = result.and(Err(err));
+                    index += 1;
+                }
+                reclaimed => {
+                    result = result.and(reclaimed);
+                    pending.swap_remove(index);
+                }
+            }
+        }
+
+        if !self.zeroing.is_empty() && pending.iter().all(|(_, reclaim)| *reclaim != Reclaim::Zero)
+        {
+            result = result.and(self.zeroing.clear());
+        }
+
+        self.release();
+        result
+    }
+
+    /// Unmap the pages at the front that only hold holes and are not viewed.
+    /// Must be called while holding the write lock of the pending ranges.
+    fn release(&self) {
+        let mut pages = self.pages.write().unwrap_or_else(PoisonError::into_inner);
+        let mut count = 0;
+        while let Some(page) = pages.index.page(count) {
+            if pages.maps[count].is_shared()
+                || !self.holes.covers(page.offset..page.offset + page.len)
+            {
+                break;
+            }
+            count += 1;
+        }
+
+        if count > 0 {
+            pages.maps.drain(..count);
+            pages.index.drop_front(count);
+        }
+    }
+
+    /// Whether some views into the pages that hold `range` are held.
+    fn is_viewed(&self, range: Range<usize>) -> bool {
+        let pages = self.pages.read().unwrap_or_else(PoisonError::into_inner);
+        let mut address = range.start;
+        while address < range.end {
+            match pages.index.find(address) {
+                Some(page) if pages.maps[page.number].is_shared() => return true,
+                Some(page) => address = page.offset + page.len,
+                None => break,
+            }
+        }
+        false
+    }
+}
+
+/// How many zeros `zero` writes at once.
+const ZERO_CHUNK: usize = 64 * 1024;
+
+/// Overwrite `len` bytes of `file` at `offset` with zeros. Mappings see the
+/// change because they share the page cache with the file.
+fn zero(file: &File, offset: usize, len: usize) -> Result<(), Error> {
+    let zeros = vec![0u8; len.min(ZERO_CHUNK)];
+    let mut written = 0;
+    while written < len {
+        let chunk = (len - written).min(ZERO_CHUNK);
+        write_at(file, &zeros[..chunk], (offset + written) as u64).map_err(Error::Write)?;
+        written += chunk;
+    }
+    Ok(())
+}
+
+/// Return the file to `len` bytes after a failed write. If even this fails,
+/// the tail is overwritten by the next write, which starts at the end of the
+/// last page, but it is taken for data if the file is opened again before
+/// that.
+fn rollback(file: &File, len: usize) {
+    let _ = file.set_len(len as u64);
+}
+
+/// Allocate disk space for `len` bytes at `offset`, so that running out of
+/// space is reported here rather than by `SIGBUS` when the page is written.
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, offset: usize, len: usize) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    #[cfg(test)]
+    tests::allocation()?;
+
+    let result = unsafe {
+        libc::posix_fallocate(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t)
+    };
+    match result {
+        // The filesystem cannot allocate space in advance.
+        0 | libc::EOPNOTSUPP => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(_file: &File, _offset: usize, _len: usize) -> io::Result<()> {
+    #[cfg(test)]
+    tests::allocation()?;
+
+    Ok(())
+}
+
+/// Pages cannot be empty.
+fn no_growth() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "no grow in file size")
+}
+
+fn extend_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::StorageFull => Error::NoSpace(err),
+        _ => Error::Extend(err),
+    }
+}
+
+fn flush_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::StorageFull => Error::NoSpace(err),
+        _ => Error::Flush(err),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{GrowableMmap, Reclaim};
+    use crate::{holes::Holes, Error};
+    use std::{cell::Cell, fs::OpenOptions, io};
+
+    thread_local! {
+        /// How many allocations of disk space succeed on this thread before
+        /// the device runs out of space.
+        static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    /// Run out of space after `count` allocations made by `f`.
+    pub(crate) fn run_out_of_space<T>(count: usize, f: impl FnOnce() -> T) -> T {
+        ALLOCATIONS.with(|allocations| allocations.set(Some(count)));
+        let result = f();
+        ALLOCATIONS.with(|allocations| allocations.set(None));
+        result
+    }
+
+    pub(super) fn allocation() -> io::Result<()> {
+        ALLOCATIONS.with(|allocations| match allocations.get() {
+            Some(0) => Err(io::ErrorKind::StorageFull.into()),
+            count => {
+                allocations.set(count.map(|count| count - 1));
+                Ok(())
+            }
+        })
+    }
+
+    #[test]
+    fn rollback() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        // Files that cannot be read cannot be mapped.
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .unwrap();
+        let holes = || Holes::load(None, 0).unwrap();
+        let mmap = GrowableMmap::new(Some(file), holes(), holes()).unwrap();
+
+        assert!(matches!(mmap.grow(100), Err(Error::Mmap(_))));
+        assert_eq!(0, path.metadata().unwrap().len());
+    }
+
+    #[test]
+    fn failed_reclaim() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+        std::fs::write(&path, [7u8; 100]).unwrap();
+        // Nothing can be written to a file opened for reading only.
+        let file = OpenOptions::new().read(true).open(&path).unwrap();
+        let holes = || Holes::load(None, 0).unwrap();
+        let mmap = GrowableMmap::new(Some(file), holes(), holes()).unwrap();
+
+        assert!(mmap.schedule(0..10, Reclaim::Zero).is_err());
+        // The range that failed before does not keep this one from being
+        // tried.
+        assert!(mmap.schedule(20..30, Reclaim::Punch).is_err());
+        assert!(mmap.get_ref(5).is_none());
+        assert_eq!(&[7u8; 10][..], &mmap.get_ref(20).unwrap()[..10]);
+        assert_eq!(1, mmap.pending.read().unwrap().len());
     }
 }
@@ -0,0 +1,317 @@
+//! Byte ranges of a file that were given back to the filesystem or of
+//! anonymous mappings whose memory was freed. Reading them returns zeros, so
+//! views into them are not handed out. The ranges of a file are
+//! persisted next to the file in a file with the `.holes` suffix as pairs of
+//! little-endian `u64`s: the start and the end of every range. A range is
+//! stored before it is punched, so the file may hold ranges that still have
+//! to be punched. Ranges that still have to be overwritten with zeros are
+//! stored the same way in a file with the `.zero` suffix until all of them
+//! are zeroed.
+
+use crate::Error;
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{PoisonError, RwLock},
+};
+
+const ENTRY_SIZE: usize = 16;
+
+pub(crate) struct Holes {
+    path: Option<PathBuf>,
+    /// Sorted ranges that do not overlap or touch.
+    ranges: RwLock<Vec<Range<usize>>>,
+}
+
+impl Holes {
+    /// Load the holes of the file at `path`. Holes past `len`, the size of the
+    /// file, are ignored. The stored holes are not changed, because another
+    /// process may be writing to the file.
+    pub fn load(path: Option<&Path>, len: usize) -> Result<Self, Error> {
+        Self::load_from(path.map(|path| suffixed(path, ".holes")), len)
+    }
+
+    /// Load the ranges of the file at `path` that still have to be zeroed.
+    pub fn load_zeroing(path: Option<&Path>, len: usize) -> Result<Self, Error> {
+        Self::load_from(path.map(|path| suffixed(path, ".zero")), len)
+    }
+
+    fn load_from(path: Option<PathBuf>, len: usize) -> Result<Self, Error> {
+        let ranges = match &path {
+            Some(path) => read(path, len)?.0,
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            path,
+            ranges: RwLock::new(ranges),
+        })
+    }
+
+    /// Forget the holes and the ranges to be zeroed of the file at `path`
+    /// past `len` after the file was cut to this size, so that data written
+    /// there later is not taken for holes or zeroed. The file must not be
+    /// opened while doing this.
+    pub fn truncate(path: &Path, len: usize) -> Result<(), Error> {
+        for path in [suffixed(path, ".holes"), suffixed(path, ".zero")] {
+            let (ranges, data) = read(&path, len)?;
+            let entries = entries(&ranges);
+            if entries != data {
+                fs::write(&path, &entries).map_err(Error::Write)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All holes.
+    pub fn ranges(&self) -> Vec<Range<usize>> {
+        self.ranges
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+
+    /// Remember a new hole.
+    pub fn add(&self, range: Range<usize>) -> Result<(), Error> {
+        let mut ranges = self.ranges.write().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .map_err(|err| Error::FileOpen(path.clone(), err))?;
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[..ENTRY_SIZE / 2].copy_from_slice(&(range.start as u64).to_le_bytes());
+            entry[ENTRY_SIZE / 2..].copy_from_slice(&(range.end as u64).to_le_bytes());
+            file.write_all(&entry).map_err(Error::Write)?;
+        }
+
+        insert(&mut ranges, range);
+        Ok(())
+    }
+
+    /// Forget all ranges.
+    pub fn clear(&self) -> Result<(), Error> {
+        let mut ranges = self.ranges.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(path) = &self.path {
+            fs::write(path, []).map_err(Error::Write)?;
+        }
+        ranges.clear();
+        Ok(())
+    }
+
+    /// Whether all of `range` is in holes.
+    pub fn covers(&self, range: Range<usize>) -> bool {
+        let ranges = self.ranges.read().unwrap_or_else(PoisonError::into_inner);
+        let next = ranges.partition_point(|hole| hole.end <= range.start);
+        ranges
+            .get(next)
+            .is_some_and(|hole| hole.start <= range.start && range.end <= hole.end)
+    }
+
+    /// Where a view that starts at `start` and ends at `end` has to end to
+    /// stay out of holes or `None` if `start` is in a hole.
+    pub fn limit(&self, start: usize, end: usize) -> Option<usize> {
+        let ranges = self.ranges.read().unwrap_or_else(PoisonError::into_inner);
+        let next = ranges.partition_point(|range| range.end <= start);
+        match ranges.get(next) {
+            Some(range) if range.start <= start => None,
+            Some(range) => Some(end.min(range.start)),
+            None => Some(end),
+        }
+    }
+}
+
+/// Where the ranges of the file at `path` with the given suffix are stored.
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Read the holes stored at `path` that are within the first `len` bytes of
+/// the file. Returns them along with the stored data.
+fn read(path: &Path, len: usize) -> Result<(Vec<Range<usize>>, Vec<u8>), Error> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(Error::FileOpen(path.to_path_buf(), err)),
+    };
+
+    let mut ranges = Vec::new();
+    for entry in data.chunks_exact(ENTRY_SIZE) {
+        let (start, end) = entry.split_at(ENTRY_SIZE / 2);
+        let start = u64_from_le(start) as usize;
+        let end = (u64_from_le(end) as usize).min(len);
+        if start < end {
+            insert(&mut ranges, start..end);
+        }
+    }
+
+    Ok((ranges, data))
+}
+
+/// Add `range` to sorted `ranges` merging it with the ranges it overlaps or
+/// touches.
+fn insert(ranges: &mut Vec<Range<usize>>, mut range: Range<usize>) {
+    let first = ranges.partition_point(|other| other.end < range.start);
+    let last = ranges.partition_point(|other| other.start <= range.end);
+    if first < last {
+        range.start = range.start.min(ranges[first].start);
+        range.end = range.end.max(ranges[last - 1].end);
+    }
+    ranges.splice(first..last, std::iter::once(range));
+}
+
+fn entries(ranges: &[Range<usize>]) -> Vec<u8> {
+    let mut entries = Vec::with_capacity(ranges.len() * ENTRY_SIZE);
+    for range in ranges {
+        entries.extend_from_slice(&(range.start as u64).to_le_bytes());
+        entries.extend_from_slice(&(range.end as u64).to_le_bytes());
+    }
+    entries
+}
+
+fn u64_from_le(data: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(data);
+    u64::from_le_bytes(bytes)
+}
+
+/// Give the byte range of `file` back to the filesystem. The size of the file
+/// does not change.
+#[cfg(target_os = "linux")]
+pub(crate) fn punch(file: &File, offset: usize, len: usize) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(Error::PunchHole(io::Error::last_os_error()))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn punch(_file: &File, _offset: usize, _len: usize) -> Result<(), Error> {
+    Err(Error::InvalidOptions(
+        "hole punching is only supported on Linux",
+    ))
+}
+
+/// Fail if holes cannot be punched in `file`, e.g. because its filesystem
+/// does not support it. A hole past the end of the file is punched to find
+/// out, which changes nothing.
+pub(crate) fn check_punch(file: &File) -> Result<(), Error> {
+    let len = file.metadata().map_err(Error::Metadata)?.len() as usize;
+    punch(file, len, 1)
+}
+
+/// Give the memory of whole pages within `data`, a part of an anonymous
+/// mapping, back to the system. Reading them returns zeros afterwards.
+#[cfg(target_os = "linux")]
+pub(crate) fn free(data: &[u8]) -> Result<(), Error> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = (data.as_ptr() as usize).next_multiple_of(page_size);
+    let end = (data.as_ptr() as usize + data.len()) / page_size * page_size;
+    if start >= end {
+        return Ok(());
+    }
+
+    let result =
+        unsafe { libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_DONTNEED) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(Error::PunchHole(io::Error::last_os_error()))
+    }
+}
+
+/// Memory is only freed on Linux. Elsewhere holes in anonymous mappings are
+/// just not read.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn free(_data: &[u8]) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{insert, Holes};
+
+    #[quickcheck]
+    fn merge(ranges: Vec<(u8, u8)>) {
+        let mut merged = Vec::new();
+        let mut covered = [false; 256];
+        for (start, len) in ranges {
+            let (start, end) = (start as usize, start as usize + len as usize % 16);
+            if start < end {
+                insert(&mut merged, start..end);
+                covered[start..end.min(256)]
+                    .iter_mut()
+                    .for_each(|x| *x = true);
+            }
+        }
+
+        for pair in merged.windows(2) {
+            assert!(pair[0].end < pair[1].start);
+        }
+        for (address, covered) in covered.iter().enumerate() {
+            let found = merged.iter().any(|range| range.contains(&address));
+            assert_eq!(*covered, found);
+        }
+    }
+
+    #[test]
+    fn persist() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("data");
+
+        {
+            let holes = Holes::load(Some(&path), 1000).unwrap();
+            assert!(holes.is_empty());
+            holes.add(100..200).unwrap();
+            holes.add(500..600).unwrap();
+            holes.add(200..300).unwrap();
+            assert_eq!(Some(50), holes.limit(0, 50));
+            assert_eq!(Some(100), holes.limit(0, 150));
+            assert_eq!(None, holes.limit(250, 400));
+            assert_eq!(Some(500), holes.limit(300, 1000));
+        }
+
+        // The file was truncated in the middle of the last hole.
+        let holes = Holes::load(Some(&path), 550).unwrap();
+        assert_eq!(None, holes.limit(520, 550));
+        assert_eq!(
+            48,
+            std::fs::read(tmp.path().join("data.holes")).unwrap().len()
+        );
+        drop(holes);
+        Holes::truncate(&path, 550).unwrap();
+        let holes = Holes::load(Some(&path), 550).unwrap();
+        assert_eq!(None, holes.limit(100, 400));
+        assert_eq!(None, holes.limit(520, 550));
+        assert_eq!(Some(500), holes.limit(300, 550));
+        assert_eq!(
+            32,
+            std::fs::read(tmp.path().join("data.holes")).unwrap().len()
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! The description of how records are laid out on disk. It is chosen when a
+//! database is created and is stored next to the data in the `layout` file.
+
+use crate::{Cipher, Compression, Error, IndexEncoding};
+use std::{fs, io, path::Path};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Layout {
+    /// Every record in the flatfile is prefixed with its length.
+    pub framed: bool,
+    /// How the sequential number index is stored.
+    pub index: IndexEncoding,
+    /// All records have this size and there is no index.
+    pub record_size: Option<usize>,
+    /// How records are compressed. Compressed databases are always framed.
+    pub compression: Compression,
+    /// Records are packed into zstd-compressed blocks of this size.
+    pub block_size: Option<usize>,
+    /// Records are encrypted with this cipher. Encrypted databases are always
+    /// framed.
+    pub encryption: Option<Cipher>,
+}
+
+impl Layout {
+    /// Whether records in the data file are prefixed with their lengths.
+    pub fn is_framed(&self) -> bool {
+        self.record_size.is_none()
+            && (self.framed || self.compression != Compression::None || self.encryption.is_some())
+    }
+
+    /// Fail if records cannot be stored this way.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.record_size == Some(0) {
+            return Err(Error::InvalidOptions("record size cannot be zero"));
+        }
+
+        if self.record_size.is_some() && self.compression != Compression::None {
+            return Err(Error::InvalidOptions(
+                "fixed-size records cannot be compressed",
+            ));
+        }
+        self.compression.check()?;
+
+        if self.record_size.is_some() && self.encryption.is_some() {
+            return Err(Error::InvalidOptions(
+                "fixed-size records cannot be encrypted",
+            ));
+        }
+
+        match self.block_size {
+            Some(0) => Err(Error::InvalidOptions("block size cannot be zero")),
+            Some(_) if self.index != IndexEncoding::U64 => Err(Error::InvalidOptions(
+                "block compression requires the u64 index encoding",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the layout of the database at `path`. Databases created before
+    /// layouts were introduced do not have the file and use the default
+    /// layout.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        Ok(Self::load_existing(path)?.unwrap_or_default())
+    }
+
+    /// Read the layout of the database at `path`. If the database is new,
+    /// `layout` is validated, stored and returned.
+    pub fn load_or_store(path: &Path, layout: Layout) -> Result<Self, Error> {
+        let layout_path = path.join("layout");
+
+        match Self::load_existing(path)? {
+            Some(layout) => Ok(layout),
+            None => {
+                let is_new = match fs::metadata(path.join("data")) {
+                    Ok(metadata) => metadata.len() == 0,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => true,
+                    Err(err) => return Err(Error::Metadata(err)),
+                };
+
+                if !is_new {
+                    return Ok(Layout::default());
+                }
+
+                layout.validate()?;
+
+                fs::write(&layout_path, layout.to_string())
+                    .map_err(|err| Error::FileOpen(layout_path, err))?;
+
+                Ok(layout)
+            }
+        }
+    }
+
+    fn load_existing(path: &Path) -> Result<Option<Self>, Error> {
+        let layout_path = path.join("layout");
+
+        match fs::read_to_string(&layout_path) {
+            Ok(content) => Self::parse(&content).map(Some),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::FileOpen(layout_path, err)),
+        }
+    }
+
+    fn parse(content: &str) -> Result<Self, Error> {
+        let mut layout = Layout::default();
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or(Error::LayoutDamaged)?;
+            let value = value.trim();
+
+            match key.trim() {
+                "framed" => layout.framed = value.parse().map_err(|_| Error::LayoutDamaged)?,
+                "record_size" => {
+                    layout.record_size = Some(value.parse().map_err(|_| Error::LayoutDamaged)?)
+                }
+                "index" => {
+                    layout.index = match value {
+                        "u64" => IndexEncoding::U64,
+                        "u32" => IndexEncoding::U32,
+                        "blocked" => IndexEncoding::Blocked,
+                        _ => return Err(Error::LayoutDamaged),
+                    }
+                }
+                "block_size" => {
+                    layout.block_size = Some(value.parse().map_err(|_| Error::LayoutDamaged)?)
+                }
+                "encryption" => {
+                    layout.encryption = match value {
+                        "aes256gcm" => Some(Cipher::Aes256Gcm),
+                        "chacha20poly1305" => Some(Cipher::ChaCha20Poly1305),
+                        _ => return Err(Error::LayoutDamaged),
+                    }
+                }
+                "compression" => {
+                    layout.compression = match value {
+                        "none" => Compression::None,
+                        "lz4" => Compression::Lz4,
+                        "zstd" => Compression::Zstd,
+                        _ => return Err(Error::LayoutDamaged),
+                    }
+                }
+                _ => return Err(Error::LayoutDamaged),
+            }
+        }
+
+        Ok(layout)
+    }
+}
+
+impl std::fmt::Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let index = match self.index {
+            IndexEncoding::U64 => "u64",
+            IndexEncoding::U32 => "u32",
+            IndexEncoding::Blocked => "blocked",
+        };
+
+        writeln!(f, "framed = {}", self.framed)?;
+        writeln!(f, "index = {}", index)?;
+        if let Some(record_size) = self.record_size {
+            writeln!(f, "record_size = {}", record_size)?;
+        }
+        match self.compression {
+            Compression::None => {}
+            Compression::Lz4 => writeln!(f, "compression = lz4")?,
+            Compression::Zstd => writeln!(f, "compression = zstd")?,
+        }
+        if let Some(block_size) = self.block_size {
+            writeln!(f, "block_size = {}", block_size)?;
+        }
+        match self.encryption {
+            None => {}
+            Some(Cipher::Aes256Gcm) => writeln!(f, "encryption = aes256gcm")?,
+            Some(Cipher::ChaCha20Poly1305) => writeln!(f, "encryption = chacha20poly1305")?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Layout;
+    use crate::{Cipher, Compression, Error, IndexEncoding};
+
+    #[test]
+    fn store_and_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let layout = Layout {
+            framed: true,
+            index: IndexEncoding::Blocked,
+            record_size: Some(32),
+            ..Layout::default()
+        };
+
+        assert_eq!(layout, Layout::load_or_store(tmp.path(), layout).unwrap());
+        assert_eq!(
+            layout,
+            Layout::load_or_store(tmp.path(), Layout::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let layout = Layout {
+            framed: true,
+            index: IndexEncoding::U32,
+            record_size: Some(32),
+            compression: Compression::Zstd,
+            block_size: Some(4096),
+            encryption: Some(Cipher::ChaCha20Poly1305),
+        };
+
+        assert_eq!(layout, Layout::parse(&layout.to_string()).unwrap());
+    }
+
+    #[test]
+    fn invalid_not_stored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let layout = Layout {
+            record_size: Some(0),
+            ..Layout::default()
+        };
+
+        assert!(matches!(
+            Layout::load_or_store(tmp.path(), layout),
+            Err(Error::InvalidOptions(_))
+        ));
+        assert!(!tmp.path().join("layout").exists());
+    }
+}
@@ -23,17 +23,60 @@
 extern crate quickcheck_macros;
 
 mod appender;
+mod block;
+mod codec;
+mod compaction;
+mod compression;
 mod database;
+#[cfg(feature = "dedup")]
+mod dedup;
+mod encryption;
 mod error;
 mod flatfile;
 mod growable_mmap;
+mod holes;
+mod layout;
+mod options;
 mod page_index;
+mod portable;
+mod producers;
+mod quota;
+mod record_reader;
+mod record_run;
 mod seqno;
 mod seqno_iter;
 mod shared_mmap;
+mod stat;
+mod tombstones;
+mod typed_pile;
 
 use appender::Appender;
+#[cfg(feature = "bincode")]
+pub use codec::Bincode;
+pub use codec::Codec;
+#[cfg(feature = "json")]
+pub use codec::Json;
+#[cfg(feature = "postcard")]
+pub use codec::Postcard;
+#[cfg(feature = "rkyv")]
+pub use codec::Rkyv;
+pub use compression::Compression;
 pub use database::Database;
+#[cfg(feature = "dedup")]
+pub use dedup::{content_hash, Hash};
+pub use encryption::{Cipher, Key, KeyProvider};
 pub use error::Error;
+pub use options::Options;
+pub use portable::Format;
+#[cfg(any(feature = "json", feature = "csv"))]
+pub use portable::Payload;
+pub use quota::{Quota, QuotaUsage};
+pub use record_reader::RecordReader;
+pub use record_run::RecordRun;
+pub use seqno::IndexEncoding;
 pub use seqno_iter::SeqNoIter;
 pub use shared_mmap::SharedMmap;
+pub use stat::Stat;
+#[cfg(feature = "rkyv")]
+pub use typed_pile::ArchivedRecord;
+pub use typed_pile::{TypedIter, TypedPile};
@@ -0,0 +1,233 @@
+use crate::{
+    layout::Layout, quota::Warning, Cipher, Compression, IndexEncoding, KeyProvider, QuotaUsage,
+};
+use std::{fmt, sync::Arc};
+
+/// Database options. Options that describe how records are stored on disk only
+/// take effect when a database is created: an existing database always uses
+/// the layout it was created with.
+#[derive(Clone)]
+pub struct Options {
+    pub(crate) layout: Layout,
+    pub(crate) block_cache: usize,
+    pub(crate) producer_window: usize,
+    pub(crate) zero_deleted: bool,
+    pub(crate) capped_records: Option<usize>,
+    pub(crate) capped_bytes: Option<usize>,
+    pub(crate) max_data_size: Option<usize>,
+    pub(crate) max_records: Option<usize>,
+    pub(crate) max_index_size: Option<usize>,
+    pub(crate) quota_warning: Option<Warning>,
+    pub(crate) keys: Option<Arc<dyn KeyProvider>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            layout: Layout::default(),
+            block_cache: 64,
+            producer_window: 5,
+            zero_deleted: false,
+            capped_records: None,
+            capped_bytes: None,
+            max_data_size: None,
+            max_records: None,
+            max_index_size: None,
+            quota_warning: None,
+            keys: None,
+        }
+    }
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("layout", &self.layout)
+            .field("block_cache", &self.block_cache)
+            .field("producer_window", &self.producer_window)
+            .field("zero_deleted", &self.zero_deleted)
+            .field("capped_records", &self.capped_records)
+            .field("capped_bytes", &self.capped_bytes)
+            .field("max_data_size", &self.max_data_size)
+            .field("max_records", &self.max_records)
+            .field("max_index_size", &self.max_index_size)
+            .field(
+                "quota_warning",
+                &self.quota_warning.as_ref().map(Warning::percent),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prefix every record in the data file with its length. This makes the
+    /// data file self-describing so that the sequential number index can be
+    /// restored with [`Database::rebuild_index`] if it is lost or damaged. Each
+    /// record takes 8 more bytes.
+    ///
+    /// [`Database::rebuild_index`]: crate::Database::rebuild_index
+    pub fn framed(mut self, framed: bool) -> Self {
+        self.layout.framed = framed;
+        self
+    }
+
+    /// Set how the sequential number index is stored. See [`IndexEncoding`]
+    /// for the trade-offs.
+    pub fn index_encoding(mut self, encoding: IndexEncoding) -> Self {
+        self.layout.index = encoding;
+        self
+    }
+
+    /// Require all records to be exactly `size` bytes long. Records are then
+    /// located by their sequential numbers without the index, so the
+    /// database does not have the `seqno` file and the data file is never
+    /// framed. Appending a record of another size fails.
+    pub fn fixed_record_size(mut self, size: usize) -> Self {
+        self.layout.record_size = Some(size);
+        self
+    }
+
+    /// Compress records with the given algorithm. Each record is compressed
+    /// separately and is stored as it is if it does not get smaller. Records
+    /// are decompressed when read, so they are no longer views into the data
+    /// file: use [`Database::get_raw_by_seqno`] to get the stored bytes.
+    /// Compressed data files are always framed. Cannot be used with fixed-size
+    /// records.
+    ///
+    /// [`Database::get_raw_by_seqno`]: crate::Database::get_raw_by_seqno
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.layout.compression = compression;
+        self
+    }
+
+    /// Pack records into blocks of up to `block_size` bytes that are
+    /// compressed with zstd as a whole. This works much better than
+    /// [`Options::compression`] for small records. Records of several appends
+    /// share a block: until it is full, they are kept uncompressed in memory
+    /// and in the `block.open` file in the database directory.
+    /// Compression can be further improved with
+    /// [`Database::train_dictionary`]. Reading a record
+    /// decompresses the whole block: recently used blocks are cached, see
+    /// [`Options::block_cache`]. Requires the `zstd` feature and the default
+    /// index encoding. Cannot be used with fixed-size records.
+    ///
+    /// [`Database::train_dictionary`]: crate::Database::train_dictionary
+    pub fn block_compression(mut self, block_size: usize) -> Self {
+        self.layout.compression = Compression::Zstd;
+        self.layout.block_size = Some(block_size);
+        self
+    }
+
+    /// Keep up to `blocks` decompressed blocks in memory. Only affects
+    /// databases with [`Options::block_compression`]. The default is 64.
+    pub fn block_cache(mut self, blocks: usize) -> Self {
+        self.block_cache = blocks;
+        self
+    }
+
+    /// Set how many recent batches of every producer are remembered by
+    /// [`Database::append_idempotent`]. A producer may retry any of them. The
+    /// default is 5.
+    ///
+    /// [`Database::append_idempotent`]: crate::Database::append_idempotent
+    pub fn producer_window(mut self, batches: usize) -> Self {
+        self.producer_window = batches;
+        self
+    }
+
+    /// Overwrite the content of records with zeros when they are deleted with
+    /// [`Database::delete`], so that it cannot be recovered from the data
+    /// file. Records read earlier do not change: while records read from the
+    /// same part of the data file are held, the content is overwritten by a
+    /// later deletion or when the database is opened again. Does not work for
+    /// in-memory databases and block compression.
+    ///
+    /// [`Database::delete`]: crate::Database::delete
+    pub fn zero_deleted(mut self, zero: bool) -> Self {
+        self.zero_deleted = zero;
+        self
+    }
+
+    /// Keep at most `records` records. Appending more records evicts the
+    /// oldest ones: they are deleted and the space they take is given back
+    /// like with [`Database::punch`]. A batch of more than `records` records
+    /// is kept whole until the next append. Sequential numbers keep growing and
+    /// `get_by_seqno` returns `None` for evicted records. The index still
+    /// takes space for every record ever written. If old records cannot be
+    /// evicted, the write fails with [`Error::Evict`] although the records
+    /// were written. Capped databases in files are only supported on Linux.
+    /// Cannot be used with block compression.
+    ///
+    /// [`Database::punch`]: crate::Database::punch
+    /// [`Error::Evict`]: crate::Error::Evict
+    pub fn capped_records(mut self, records: usize) -> Self {
+        self.capped_records = Some(records);
+        self
+    }
+
+    /// Keep the records that take at most `bytes` bytes in the data file,
+    /// evicting the oldest ones like [`Options::capped_records`] does. A
+    /// batch that is larger than `bytes` is kept whole until the next append.
+    pub fn capped_bytes(mut self, bytes: usize) -> Self {
+        self.capped_bytes = Some(bytes);
+        self
+    }
+
+    /// Fail writes that would make the data file larger than `bytes` bytes
+    /// with [`Error::QuotaExceeded`] before the file is extended. The data
+    /// file does not shrink when records are deleted or evicted.
+    ///
+    /// [`Error::QuotaExceeded`]: crate::Error::QuotaExceeded
+    pub fn max_data_size(mut self, bytes: usize) -> Self {
+        self.max_data_size = Some(bytes);
+        self
+    }
+
+    /// Fail writes that would make the database hold more than `records`
+    /// records, counting deleted and evicted ones, with
+    /// [`Error::QuotaExceeded`].
+    ///
+    /// [`Error::QuotaExceeded`]: crate::Error::QuotaExceeded
+    pub fn max_records(mut self, records: usize) -> Self {
+        self.max_records = Some(records);
+        self
+    }
+
+    /// Fail writes that would make the sequential number index larger than
+    /// `bytes` bytes with [`Error::QuotaExceeded`] before the index is
+    /// extended.
+    ///
+    /// [`Error::QuotaExceeded`]: crate::Error::QuotaExceeded
+    pub fn max_index_size(mut self, bytes: usize) -> Self {
+        self.max_index_size = Some(bytes);
+        self
+    }
+
+    /// Call `callback` when a write brings the usage of a limit set with
+    /// [`Options::max_data_size`], [`Options::max_records`] or
+    /// [`Options::max_index_size`] to `percent` percent of the limit, so that
+    /// operators can act before writes start to fail. The callback is called
+    /// while writes are blocked, so it must not write to the database.
+    pub fn quota_warning<F>(mut self, percent: u8, callback: F) -> Self
+    where
+        F: Fn(QuotaUsage) + Send + Sync + 'static,
+    {
+        self.quota_warning = Some(Warning::new(percent, Arc::new(callback)));
+        self
+    }
+
+    /// Encrypt records with `cipher` and keys from `keys`. Records are
+    /// compressed before they are encrypted. Every record takes 32 more bytes.
+    /// Encrypted databases are always framed and have to be opened with the
+    /// same options. Opening a database that is not encrypted with this
+    /// option fails. Cannot be used with fixed-size records.
+    pub fn encryption(mut self, cipher: Cipher, keys: Arc<dyn KeyProvider>) -> Self {
+        self.layout.encryption = Some(cipher);
+        self.keys = Some(keys);
+        self
+    }
+}
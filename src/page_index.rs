@@ -29,6 +29,34 @@ impl PageIndex {
         find_impl(&self.bounds, 0, address)
     }
 
+    /// The page with the given number.
+    pub fn page(&self, number: usize) -> Option<PageDescriptor> {
+        let (start, end) = (*self.bounds.get(number)?, *self.bounds.get(number + 1)?);
+        Some(PageDescriptor {
+            len: end - start,
+            offset: start,
+            number,
+        })
+    }
+
+    /// The number of pages.
+    pub fn len(&self) -> usize {
+        self.bounds.len().saturating_sub(1)
+    }
+
+    /// Make the last two pages one page.
+    pub fn merge_last(&mut self) {
+        if self.len() >= 2 {
+            self.bounds.remove(self.bounds.len() - 2);
+        }
+    }
+
+    /// Forget the first `count` pages. The other pages keep their offsets,
+    /// but their numbers go down by `count`.
+    pub fn drop_front(&mut self, count: usize) {
+        self.bounds.drain(..count.min(self.len()));
+    }
+
     pub fn memory_size(&self) -> usize {
         self.bounds.last().copied().unwrap_or(0)
     }
@@ -110,5 +138,20 @@ mod tests {
         );
         assert_eq!(None, index.find(420));
         assert_eq!(None, index.find(1000));
+
+        index.merge_last();
+        index.drop_front(2);
+        assert_eq!(3, index.len());
+        assert_eq!(None, index.find(41));
+        assert_eq!(
+            Some(PageDescriptor {
+                len: 324,
+                offset: 96,
+                number: 2,
+            }),
+            index.find(103)
+        );
+        assert_eq!(index.find(50), index.page(0));
+        assert_eq!(420, index.memory_size());
     }
 }
@@ -0,0 +1,386 @@
+//! Export and import of records in portable stream formats.
+//!
+//! Sequential numbers are written to the text formats for reference only:
+//! imported records are appended and get new sequential numbers.
+
+#[cfg(any(feature = "json", feature = "csv"))]
+use crate::SharedMmap;
+use crate::{Database, Error, SeqNoIter};
+use std::{
+    io::{self, BufRead, Read, Write},
+    mem::size_of,
+};
+
+/// A portable stream format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Every record is prefixed with its length as a little-endian `u64`.
+    Binary,
+    /// One JSON object per line: `{"seqno":0,"data":"..."}`.
+    #[cfg(feature = "json")]
+    JsonLines(Payload),
+    /// A CSV table with the `seqno,data` header.
+    #[cfg(feature = "csv")]
+    Csv(Payload),
+}
+
+/// Representation of record payloads in text formats.
+#[cfg(any(feature = "json", feature = "csv"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Payload {
+    /// Standard base64 with padding. Works for any data.
+    Base64,
+    /// Records are written as is. Exporting a record that is not valid UTF-8
+    /// fails.
+    Utf8,
+}
+
+pub(crate) fn export<W: Write>(
+    records: SeqNoIter,
+    format: Format,
+    writer: W,
+) -> Result<usize, Error> {
+    match format {
+        Format::Binary => export_binary(records, writer),
+        #[cfg(feature = "json")]
+        Format::JsonLines(payload) => export_json_lines(records, payload, writer),
+        #[cfg(feature = "csv")]
+        Format::Csv(payload) => export_csv(records, payload, writer),
+    }
+}
+
+pub(crate) fn import<R: BufRead>(
+    db: &Database,
+    reader: R,
+    format: Format,
+    batch_size: usize,
+) -> Result<usize, Error> {
+    let mut batch = Batch::new(db, batch_size);
+
+    match format {
+        Format::Binary => import_binary(reader, &mut batch)?,
+        #[cfg(feature = "json")]
+        Format::JsonLines(payload) => import_json_lines(reader, payload, &mut batch)?,
+        #[cfg(feature = "csv")]
+        Format::Csv(payload) => import_csv(reader, payload, &mut batch)?,
+    }
+
+    batch.finish()
+}
+
+/// Accumulates imported records and appends them to the database once there
+/// are enough of them.
+struct Batch<'a> {
+    db: &'a Database,
+    records: Vec<Vec<u8>>,
+    batch_size: usize,
+    count: usize,
+}
+
+impl<'a> Batch<'a> {
+    fn new(db: &'a Database, batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        Self {
+            db,
+            records: Vec::with_capacity(batch_size),
+            batch_size,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, record: Vec<u8>) -> Result<(), Error> {
+        self.records.push(record);
+        if self.records.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let records: Vec<_> = self.records.iter().map(|record| record.as_ref()).collect();
+        self.db.append(&records)?;
+        self.count += records.len();
+        self.records.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<usize, Error> {
+        self.flush()?;
+        Ok(self.count)
+    }
+}
+
+fn export_binary<W: Write>(mut records: SeqNoIter, mut writer: W) -> Result<usize, Error> {
+    let mut count = 0;
+
+    while let Some(record) = records.try_next()? {
+        writer
+            .write_all(&(record.len() as u64).to_le_bytes())
+            .map_err(Error::Write)?;
+        writer.write_all(record.as_ref()).map_err(Error::Write)?;
+        count += 1;
+    }
+
+    writer.flush().map_err(Error::Write)?;
+
+    Ok(count)
+}
+
+fn import_binary<R: BufRead>(mut reader: R, batch: &mut Batch) -> Result<(), Error> {
+    let mut number = 0;
+
+    loop {
+        if reader.fill_buf().map_err(Error::Read)?.is_empty() {
+            return Ok(());
+        }
+
+        let mut length = [0u8; size_of::<u64>()];
+        read_exact(&mut reader, &mut length, number)?;
+        let length = u64::from_le_bytes(length);
+
+        // The length comes from the stream, so memory is only allocated for
+        // the data that is actually there.
+        let mut record = Vec::new();
+        reader
+            .by_ref()
+            .take(length)
+            .read_to_end(&mut record)
+            .map_err(Error::Read)?;
+        if record.len() as u64 != length {
+            return Err(Error::ImportFormat(number));
+        }
+        batch.push(record)?;
+
+        number += 1;
+    }
+}
+
+fn read_exact<R: BufRead>(reader: &mut R, buf: &mut [u8], number: usize) -> Result<(), Error> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => Error::ImportFormat(number),
+        _ => Error::Read(err),
+    })
+}
+
+#[cfg(feature = "json")]
+fn export_json_lines<W: Write>(
+    records: SeqNoIter,
+    payload: Payload,
+    mut writer: W,
+) -> Result<usize, Error> {
+    let mut count = 0;
+
+    for record in with_seqno(records) {
+        let (seqno, record) = record?;
+        let line = serde_json::json!({
+            "seqno": seqno,
+            "data": encode_payload(seqno, record.as_ref(), payload)?,
+        });
+        serde_json::to_writer(&mut writer, &line).map_err(|err| Error::Write(err.into()))?;
+        writer.write_all(b"\n").map_err(Error::Write)?;
+        count += 1;
+    }
+
+    writer.flush().map_err(Error::Write)?;
+
+    Ok(count)
+}
+
+#[cfg(feature = "json")]
+fn import_json_lines<R: BufRead>(
+    reader: R,
+    payload: Payload,
+    batch: &mut Batch,
+) -> Result<(), Error> {
+    // Records are numbered like in the other formats: blank lines are not
+    // counted.
+    let mut number = 0;
+    for line in reader.lines() {
+        let line = line.map_err(Error::Read)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let value: serde_json::Value =
+            serde_json::from_str(&line).map_err(|_| Error::ImportFormat(number))?;
+        let data = value
+            .get("data")
+            .and_then(|data| data.as_str())
+            .ok_or(Error::ImportFormat(number))?;
+
+        batch.push(decode_payload(number, data, payload)?)?;
+        number += 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "csv")]
+fn export_csv<W: Write>(records: SeqNoIter, payload: Payload, writer: W) -> Result<usize, Error> {
+    let mut writer = csv::Writer::from_writer(writer);
+    let mut count = 0;
+
+    writer
+        .write_record(["seqno", "data"])
+        .map_err(|err| Error::Write(err.into()))?;
+
+    for record in with_seqno(records) {
+        let (seqno, record) = record?;
+        let data = encode_payload(seqno, record.as_ref(), payload)?;
+        writer
+            .write_record([seqno.to_string(), data])
+            .map_err(|err| Error::Write(err.into()))?;
+        count += 1;
+    }
+
+    writer.flush().map_err(Error::Write)?;
+
+    Ok(count)
+}
+
+#[cfg(feature = "csv")]
+fn import_csv<R: BufRead>(reader: R, payload: Payload, batch: &mut Batch) -> Result<(), Error> {
+    let mut reader = csv::Reader::from_reader(reader);
+
+    for (number, row) in reader.byte_records().enumerate() {
+        let row = row.map_err(|err| match err.into_kind() {
+            csv::ErrorKind::Io(err) => Error::Read(err),
+            _ => Error::ImportFormat(number),
+        })?;
+        let data = row.get(1).ok_or(Error::ImportFormat(number))?;
+        let data = match payload {
+            Payload::Utf8 => data.to_vec(),
+            Payload::Base64 => {
+                let data = std::str::from_utf8(data).map_err(|_| Error::ImportFormat(number))?;
+                decode_payload(number, data, payload)?
+            }
+        };
+
+        batch.push(data)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(any(feature = "json", feature = "csv"))]
+fn with_seqno(mut records: SeqNoIter) -> impl Iterator<Item = Result<(usize, SharedMmap), Error>> {
+    std::iter::from_fn(move || {
+        let seqno = records.seqno();
+        records
+            .try_next()
+            .transpose()
+            .map(|record| record.map(|record| (seqno, record)))
+    })
+}
+
+#[cfg(any(feature = "json", feature = "csv"))]
+fn encode_payload(seqno: usize, record: &[u8], payload: Payload) -> Result<String, Error> {
+    use base64::Engine;
+
+    match payload {
+        Payload::Base64 => Ok(base64::engine::general_purpose::STANDARD.encode(record)),
+        Payload::Utf8 => std::str::from_utf8(record)
+            .map(ToOwned::to_owned)
+            .map_err(|_| Error::NotUtf8(seqno)),
+    }
+}
+
+#[cfg(any(feature = "json", feature = "csv"))]
+fn decode_payload(number: usize, data: &str, payload: Payload) -> Result<Vec<u8>, Error> {
+    use base64::Engine;
+
+    match payload {
+        Payload::Base64 => base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|_| Error::ImportFormat(number)),
+        Payload::Utf8 => Ok(data.as_bytes().to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Format;
+    use crate::{Database, Error};
+
+    fn export_import(format: Format, data: Vec<Vec<u8>>) {
+        let records: Vec<_> = data.iter().map(|data| data.as_ref()).collect();
+
+        if records.is_empty() {
+            return;
+        }
+
+        let src = Database::memory().unwrap();
+        src.append(&records).unwrap();
+
+        let skip = records.len() / 2;
+        let mut stream = Vec::new();
+        let exported = src.export(skip.., format, &mut stream).unwrap();
+        assert_eq!(records.len() - skip, exported);
+
+        let dst = Database::memory().unwrap();
+        let imported = dst.import(stream.as_slice(), format, 3).unwrap();
+        assert_eq!(exported, imported);
+
+        for (i, record) in dst.iter_from_seqno(0).unwrap().enumerate() {
+            assert_eq!(records[skip + i], record.as_ref());
+        }
+    }
+
+    #[quickcheck]
+    fn binary(data: Vec<Vec<u8>>) {
+        export_import(Format::Binary, data);
+    }
+
+    #[cfg(feature = "json")]
+    #[quickcheck]
+    fn json_lines(data: Vec<Vec<u8>>, text: Vec<String>) {
+        use super::Payload;
+
+        export_import(Format::JsonLines(Payload::Base64), data);
+        export_import(
+            Format::JsonLines(Payload::Utf8),
+            text.into_iter().map(String::into_bytes).collect(),
+        );
+    }
+
+    #[cfg(feature = "csv")]
+    #[quickcheck]
+    fn csv(data: Vec<Vec<u8>>, text: Vec<String>) {
+        use super::Payload;
+
+        export_import(Format::Csv(Payload::Base64), data);
+        export_import(
+            Format::Csv(Payload::Utf8),
+            text.into_iter().map(String::into_bytes).collect(),
+        );
+    }
+
+    #[test]
+    fn truncated_binary() {
+        let db = Database::memory().unwrap();
+        let stream = [5u8, 0, 0, 0, 0, 0, 0, 0, b'a', b'b'];
+        assert!(db.import(&stream[..], Format::Binary, 16).is_err());
+
+        let mut stream = (u64::MAX >> 1).to_le_bytes().to_vec();
+        stream.extend_from_slice(b"data");
+        assert!(matches!(
+            db.import(&stream[..], Format::Binary, 16),
+            Err(Error::ImportFormat(0))
+        ));
+        assert!(db.is_empty());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn malformed_json_lines() {
+        use super::Payload;
+
+        // Blank lines are not counted, like in the other formats.
+        let db = Database::memory().unwrap();
+        let stream = b"{\"data\":\"a\"}\n\n{\"data\":\"b\"}\n{\"data\":1}\n";
+        assert!(matches!(
+            db.import(&stream[..], Format::JsonLines(Payload::Utf8), 16),
+            Err(Error::ImportFormat(2))
+        ));
+    }
+}
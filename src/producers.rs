@@ -0,0 +1,463 @@
+//! State of idempotent producers: the most recent batches every producer
+//! wrote, so that retried batches are not written again. The state is
+//! persisted in the `producers` file of the database directory as a sequence
+//! of entries of five little-endian `u64`s: the producer id, the batch
+//! sequence number, the sequential number of the first record, the number of
+//! records and the checksum of the records. The file is only read when a
+//! producer writes for the first time, so opening a database does not change
+//! it. Once most of its entries are batches that were pushed out of the
+//! window, it is rewritten with the remembered batches only.
+
+use crate::{appender::sync_dir, Error};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    mem::size_of,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+const ENTRY_SIZE: usize = 5 * size_of::<u64>();
+/// The file is rewritten once it is this many times larger than the entries
+/// of the remembered batches.
+const COMPACT_RATIO: usize = 4;
+
+/// A batch written by a producer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Batch {
+    sequence: u64,
+    start: usize,
+    len: usize,
+    checksum: u64,
+}
+
+impl Batch {
+    fn parse(entry: &[u8]) -> (u64, Self) {
+        let mut fields = entry.chunks_exact(size_of::<u64>()).map(|field| {
+            let mut bytes = [0u8; size_of::<u64>()];
+            bytes.copy_from_slice(field);
+            u64::from_le_bytes(bytes)
+        });
+        let mut field = || fields.next().expect("an entry has five fields");
+
+        let producer_id = field();
+        let batch = Batch {
+            sequence: field(),
+            start: field() as usize,
+            len: field() as usize,
+            checksum: field(),
+        };
+        (producer_id, batch)
+    }
+
+    fn entry(&self, producer_id: u64) -> [u8; ENTRY_SIZE] {
+        let fields = [
+            producer_id,
+            self.sequence,
+            self.start as u64,
+            self.len as u64,
+            self.checksum,
+        ];
+        let mut entry = [0u8; ENTRY_SIZE];
+        for (chunk, field) in entry.chunks_exact_mut(size_of::<u64>()).zip(fields.iter()) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        entry
+    }
+
+    fn range(&self) -> Range<usize> {
+        self.start..self.start + self.len
+    }
+}
+
+/// The FNV-1a hash of the records of a batch. It tells whether the records
+/// stored where a batch was to be written are the records of the batch.
+pub fn checksum<'a>(records: impl IntoIterator<Item = &'a [u8]>) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for record in records {
+        let len = (record.len() as u64).to_le_bytes();
+        for byte in len.iter().chain(record) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// Read the entries stored in the `producers` file at `path`.
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    match fs::read(path) {
+        Ok(entries) => Ok(entries),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(Error::FileOpen(path.to_path_buf(), err)),
+    }
+}
+
+/// Replace the entries stored in `dir`.
+fn store(dir: &Path, entries: &[u8]) -> Result<(), Error> {
+    let new_path = dir.join("producers.new");
+    File::create(&new_path)
+        .and_then(|mut file| {
+            file.write_all(entries)?;
+            file.sync_data()
+        })
+        .map_err(Error::Write)?;
+    fs::rename(&new_path, dir.join("producers")).map_err(Error::Replace)?;
+    sync_dir(dir)
+}
+
+/// Open the `producers` file in `dir` for appending entries.
+fn open(dir: &Path) -> Result<File, Error> {
+    let path = dir.join("producers");
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .map_err(|err| Error::FileOpen(path, err))
+}
+
+pub(crate) struct Producers {
+    dir: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+pub(crate) struct State {
+    dir: Option<PathBuf>,
+    loaded: bool,
+    /// How many recent batches are remembered for every producer.
+    window: usize,
+    /// Recent batches by producer ids, the latest batch is at the back.
+    batches: HashMap<u64, VecDeque<Batch>>,
+    /// The batch the latest call to `record` pushed out of the window.
+    evicted: Option<Batch>,
+    file: Option<File>,
+    /// The length of the file that holds complete entries.
+    file_len: u64,
+}
+
+impl Producers {
+    /// # Arguments
+    ///
+    /// * `dir` - the database directory or `None` for in-memory databases.
+    /// * `window` - how many recent batches are remembered for every
+    ///   producer.
+    pub fn new(dir: Option<PathBuf>, window: usize) -> Self {
+        Self {
+            dir: dir.clone(),
+            state: Mutex::new(State {
+                dir,
+                loaded: false,
+                window,
+                batches: HashMap::new(),
+                evicted: None,
+                file: None,
+                file_len: 0,
+            }),
+        }
+    }
+
+    /// Forget the batches that end past `records` in the database directory
+    /// `dir`.
+    pub fn truncate(dir: &Path, records: usize) -> Result<(), Error> {
+        let entries = read(&dir.join("producers"))?;
+        let retained: Vec<u8> = entries
+            .chunks_exact(ENTRY_SIZE)
+            .filter(|entry| Batch::parse(entry).1.range().end <= records)
+            .flatten()
+            .copied()
+            .collect();
+        if retained.len() != entries.len() {
+            store(dir, &retained)?;
+        }
+        Ok(())
+    }
+
+    /// Lock the state, loading it from the file if this was not done yet.
+    /// Entries are stored before their records are committed, so batches
+    /// that end past `records`, the number of records in the database, were
+    /// not written and are forgotten. So are batches whose records have
+    /// another checksum: the process stopped before writing them and other
+    /// records were appended in their place. `checksum` gives the checksum of
+    /// the stored records in a range or `None` if some of them cannot be read
+    /// anymore, in which case the batch is kept. Must be called while holding
+    /// the write lock of the database.
+    pub fn lock<F>(&self, records: usize, mut checksum: F) -> Result<MutexGuard<'_, State>, Error>
+    where
+        F: FnMut(Range<usize>) -> Result<Option<u64>, Error>,
+    {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.loaded {
+            return Ok(state);
+        }
+
+        // A load that failed earlier may have left some batches behind.
+        state.batches.clear();
+        if let Some(dir) = &self.dir {
+            let path = dir.join("producers");
+            let entries = read(&path)?;
+
+            for entry in entries.chunks_exact(ENTRY_SIZE) {
+                let (producer_id, batch) = Batch::parse(entry);
+                if batch.range().end > records {
+                    continue;
+                }
+                if checksum(batch.range())?.is_none_or(|checksum| checksum == batch.checksum) {
+                    state.remember(producer_id, batch);
+                }
+            }
+
+            // Only keep the batches that are remembered. This also removes
+            // incomplete entries and batches that were not written, so that
+            // they are not mistaken for records appended later.
+            let retained = state.entries();
+            if retained.len() != entries.len() {
+                store(dir, &retained)?;
+            }
+
+            state.file_len = retained.len() as u64;
+            state.file = Some(open(dir)?);
+        }
+
+        state.evicted = None;
+        state.loaded = true;
+        Ok(state)
+    }
+}
+
+impl State {
+    /// The records of an earlier batch of the producer with the given
+    /// sequence number or `None` if the batch is new. Fails if the batch is
+    /// too old to tell whether it was written.
+    pub fn applied(&self, producer_id: u64, sequence: u64) -> Result<Option<Range<usize>>, Error> {
+        let batches = match self.batches.get(&producer_id) {
+            Some(batches) => batches,
+            None => return Ok(None),
+        };
+
+        if let Some(batch) = batches.iter().find(|batch| batch.sequence == sequence) {
+            return Ok(Some(batch.range()));
+        }
+
+        match batches.back() {
+            Some(last) if sequence < last.sequence => Err(Error::StaleSequence {
+                producer_id,
+                sequence,
+            }),
+            _ => Ok(None),
+        }
+    }
+
+    /// Remember a new batch of records with the given checksum. Must be
+    /// called before the records are committed, so that they are not written
+    /// twice if the process stops in between. The entry is synced to the
+    /// disk, so it survives as long as the records do.
+    pub fn record(
+        &mut self,
+        producer_id: u64,
+        sequence: u64,
+        records: Range<usize>,
+        checksum: u64,
+    ) -> Result<(), Error> {
+        let batch = Batch {
+            sequence,
+            start: records.start,
+            len: records.len(),
+            checksum,
+        };
+
+        // The file is compacted before the entry is added, so that `forget`
+        // can still remove it from the end.
+        self.compact()?;
+
+        if let Some(file) = &mut self.file {
+            let written = file
+                .write_all(&batch.entry(producer_id))
+                .map_err(Error::Write)
+                .and_then(|()| file.sync_data().map_err(Error::Flush));
+            if let Err(err) = written {
+                file.set_len(self.file_len).map_err(Error::Truncate)?;
+                return Err(err);
+            }
+            self.file_len += ENTRY_SIZE as u64;
+        }
+
+        self.evicted = self.remember(producer_id, batch);
+        Ok(())
+    }
+
+    /// Forget the latest batch of the producer because its records could
+    /// not be written. The batch it pushed out of the window is remembered
+    /// again.
+    pub fn forget(&mut self, producer_id: u64) -> Result<(), Error> {
+        if let Some(batches) = self.batches.get_mut(&producer_id) {
+            batches.pop_back();
+            if let Some(evicted) = self.evicted.take() {
+                batches.push_front(evicted);
+            }
+        }
+
+        if let Some(file) = &mut self.file {
+            let file_len = self.file_len - ENTRY_SIZE as u64;
+            file.set_len(file_len).map_err(Error::Truncate)?;
+            self.file_len = file_len;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the file with the remembered batches only if most of its
+    /// entries are batches that were pushed out of the window.
+    fn compact(&mut self) -> Result<(), Error> {
+        let remembered: usize = self.batches.values().map(VecDeque::len).sum();
+        let dir = match &self.dir {
+            Some(dir) if self.file_len > (COMPACT_RATIO * remembered * ENTRY_SIZE) as u64 => dir,
+            _ => return Ok(()),
+        };
+
+        let entries = self.entries();
+        store(dir, &entries)?;
+        match open(dir) {
+            Ok(file) => {
+                self.file = Some(file);
+                self.file_len = entries.len() as u64;
+                Ok(())
+            }
+            Err(err) => {
+                // New entries would go to the replaced file, so the state is
+                // loaded again by the next write.
+                self.file = None;
+                self.loaded = false;
+                Err(err)
+            }
+        }
+    }
+
+    /// Add a batch to the window of the producer. Returns the oldest batch
+    /// if it does not fit anymore.
+    fn remember(&mut self, producer_id: u64, batch: Batch) -> Option<Batch> {
+        let batches = self.batches.entry(producer_id).or_default();
+        batches.push_back(batch);
+        if batches.len() > self.window {
+            batches.pop_front()
+        } else {
+            None
+        }
+    }
+
+    /// Remembered batches in the file format.
+    fn entries(&self) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for (producer_id, batches) in self.batches.iter() {
+            for batch in batches {
+                entries.extend_from_slice(&batch.entry(*producer_id));
+            }
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Producers, ENTRY_SIZE};
+    use crate::Error;
+
+    #[test]
+    fn window() {
+        let tmp = tempfile::tempdir().unwrap();
+
+        {
+            let producers = Producers::new(Some(tmp.path().to_path_buf()), 2);
+            let mut state = producers.lock(0, |_| Ok(None)).unwrap();
+            assert_eq!(None, state.applied(1, 0).unwrap());
+            state.record(1, 0, 0..2, 0).unwrap();
+            state.record(2, 0, 2..3, 0).unwrap();
+            state.record(1, 1, 3..4, 0).unwrap();
+            state.record(1, 3, 4..6, 0).unwrap();
+            assert_eq!(Some(3..4), state.applied(1, 1).unwrap());
+            assert!(matches!(
+                state.applied(1, 0),
+                Err(Error::StaleSequence {
+                    producer_id: 1,
+                    sequence: 0
+                })
+            ));
+            assert!(state.applied(1, 2).is_err());
+            assert_eq!(None, state.applied(1, 4).unwrap());
+
+            state.record(2, 1, 6..8, 0).unwrap();
+            state.forget(2).unwrap();
+            assert_eq!(None, state.applied(2, 1).unwrap());
+
+            // The batch pushed out of the window comes back.
+            state.record(1, 5, 6..7, 0).unwrap();
+            assert!(state.applied(1, 1).is_err());
+            state.forget(1).unwrap();
+            assert_eq!(Some(3..4), state.applied(1, 1).unwrap());
+        }
+
+        let path = tmp.path().join("producers");
+        assert_eq!(4 * ENTRY_SIZE as u64, path.metadata().unwrap().len());
+
+        // The last batch was not written completely.
+        let producers = Producers::new(Some(tmp.path().to_path_buf()), 2);
+        assert_eq!(4 * ENTRY_SIZE as u64, path.metadata().unwrap().len());
+        let state = producers.lock(5, |_| Ok(None)).unwrap();
+        assert_eq!(Some(2..3), state.applied(2, 0).unwrap());
+        assert_eq!(Some(3..4), state.applied(1, 1).unwrap());
+        assert_eq!(None, state.applied(1, 3).unwrap());
+        assert_eq!(3 * ENTRY_SIZE as u64, path.metadata().unwrap().len());
+        drop(state);
+
+        // Other records took the place of a batch.
+        let producers = Producers::new(Some(tmp.path().to_path_buf()), 2);
+        let state = producers
+            .lock(5, |records| Ok(Some(if records == (3..4) { 1 } else { 0 })))
+            .unwrap();
+        assert_eq!(Some(2..3), state.applied(2, 0).unwrap());
+        assert_eq!(None, state.applied(1, 1).unwrap());
+        assert_eq!(2 * ENTRY_SIZE as u64, path.metadata().unwrap().len());
+        drop(state);
+
+        Producers::truncate(tmp.path(), 2).unwrap();
+        assert_eq!(ENTRY_SIZE as u64, path.metadata().unwrap().len());
+    }
+
+    #[test]
+    fn compact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("producers");
+
+        {
+            let producers = Producers::new(Some(tmp.path().to_path_buf()), 2);
+            let mut state = producers.lock(0, |_| Ok(None)).unwrap();
+            for sequence in 0..100 {
+                let start = 2 * sequence as usize;
+                state.record(1, sequence, start..start + 1, 0).unwrap();
+                state.record(2, sequence, start + 1..start + 2, 0).unwrap();
+                assert!(path.metadata().unwrap().len() <= (4 * 4 + 1) * ENTRY_SIZE as u64);
+            }
+            // The latest batch is still removed from the file.
+            state.forget(2).unwrap();
+        }
+
+        let producers = Producers::new(Some(tmp.path().to_path_buf()), 2);
+        let state = producers.lock(200, |_| Ok(None)).unwrap();
+        assert_eq!(Some(196..197), state.applied(1, 98).unwrap());
+        assert_eq!(Some(198..199), state.applied(1, 99).unwrap());
+        assert_eq!(Some(197..198), state.applied(2, 98).unwrap());
+        assert_eq!(None, state.applied(2, 99).unwrap());
+    }
+
+    #[test]
+    fn checksum() {
+        let records: [&[u8]; 2] = [b"ab", b"c"];
+        assert_eq!(super::checksum(records), super::checksum(records));
+        assert_ne!(
+            super::checksum(records),
+            super::checksum([&b"a"[..], b"bc"])
+        );
+        assert_ne!(super::checksum(records), super::checksum([&b"abc"[..]]));
+    }
+}
@@ -0,0 +1,116 @@
+//! Limits on how much a database may grow.
+
+use crate::Error;
+use std::{fmt, sync::Arc};
+
+/// A resource of a database that can be limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    /// The size of the data file in bytes, see [`Options::max_data_size`].
+    ///
+    /// [`Options::max_data_size`]: crate::Options::max_data_size
+    DataSize,
+    /// The number of records, see [`Options::max_records`].
+    ///
+    /// [`Options::max_records`]: crate::Options::max_records
+    Records,
+    /// The size of the sequential number index in bytes, see
+    /// [`Options::max_index_size`].
+    ///
+    /// [`Options::max_index_size`]: crate::Options::max_index_size
+    IndexSize,
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Quota::DataSize => write!(f, "data size"),
+            Quota::Records => write!(f, "record count"),
+            Quota::IndexSize => write!(f, "index size"),
+        }
+    }
+}
+
+/// How much of a limited resource is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub quota: Quota,
+    /// The amount in use or, for [`Error::QuotaExceeded`], the amount a write
+    /// needed.
+    pub used: usize,
+    /// The limit.
+    pub max: usize,
+}
+
+/// Fail if `used` is over the limit `max`, if any.
+pub(crate) fn check(quota: Quota, used: usize, max: Option<usize>) -> Result<(), Error> {
+    match max {
+        Some(max) if used > max => Err(Error::QuotaExceeded(QuotaUsage { quota, used, max })),
+        _ => Ok(()),
+    }
+}
+
+/// A callback for writes that bring the usage of a resource to a given share
+/// of its limit.
+#[derive(Clone)]
+pub(crate) struct Warning {
+    percent: u8,
+    callback: Arc<dyn Fn(QuotaUsage) + Send + Sync>,
+}
+
+impl Warning {
+    pub fn new(percent: u8, callback: Arc<dyn Fn(QuotaUsage) + Send + Sync>) -> Self {
+        Self { percent, callback }
+    }
+
+    pub fn percent(&self) -> u8 {
+        self.percent
+    }
+
+    /// Call the callback if the usage went from `before` to `usage.used`
+    /// crossing the warning level.
+    pub fn notify(&self, before: usize, usage: QuotaUsage) {
+        let level = (usage.max as u128 * self.percent as u128 / 100) as usize;
+        if before < level && level <= usage.used {
+            (self.callback)(usage);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Quota, QuotaUsage, Warning};
+    use crate::Error;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn warning() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let warning = {
+            let calls = calls.clone();
+            Warning::new(80, Arc::new(move |usage| calls.lock().unwrap().push(usage)))
+        };
+        let usage = |used| QuotaUsage {
+            quota: Quota::Records,
+            used,
+            max: 10,
+        };
+
+        warning.notify(0, usage(7));
+        warning.notify(7, usage(8));
+        warning.notify(8, usage(9));
+        warning.notify(0, usage(10));
+        assert_eq!(vec![usage(8), usage(10)], *calls.lock().unwrap());
+
+        assert!(check(Quota::Records, 10, Some(10)).is_ok());
+        assert!(check(Quota::Records, 11, None).is_ok());
+        assert!(matches!(
+            check(Quota::Records, 11, Some(10)),
+            Err(Error::QuotaExceeded(usage)) if usage == QuotaUsage {
+                quota: Quota::Records,
+                used: 11,
+                max: 10,
+            }
+        ));
+    }
+}
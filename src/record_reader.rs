@@ -0,0 +1,240 @@
+use crate::{Error, SharedMmap};
+use std::{
+    cmp,
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+};
+
+/// An owned reader over a record. Unlike `Cursor<&[u8]>` it keeps the record
+/// alive by itself and thus can be moved to other threads.
+#[derive(Debug, Clone)]
+pub struct RecordReader {
+    record: SharedMmap,
+    position: usize,
+}
+
+impl RecordReader {
+    pub fn new(record: SharedMmap) -> Self {
+        Self {
+            record,
+            position: 0,
+        }
+    }
+
+    /// The current position within the record.
+    pub fn position(&self) -> u64 {
+        self.position as u64
+    }
+
+    pub fn get_ref(&self) -> &SharedMmap {
+        &self.record
+    }
+
+    pub fn into_inner(self) -> SharedMmap {
+        self.record
+    }
+
+    fn remaining(&self) -> &[u8] {
+        let record = self.record.as_ref();
+        &record[cmp::min(self.position, record.len())..]
+    }
+}
+
+impl From<SharedMmap> for RecordReader {
+    fn from(record: SharedMmap) -> Self {
+        Self::new(record)
+    }
+}
+
+impl Read for RecordReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.remaining().read(buf)?;
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl BufRead for RecordReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(self.remaining())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position += amt;
+    }
+}
+
+impl Seek for RecordReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset as usize;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.record.len() as u64, offset),
+            SeekFrom::Current(offset) => (self.position as u64, offset),
+        };
+
+        let position = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position as usize;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Copy `record` located at `offset` of the `file` to `out`. The kernel copies
+/// the data from the file when possible. Otherwise `record` is written.
+#[cfg(unix)]
+pub(crate) fn send<W>(
+    file: Option<&std::fs::File>,
+    offset: usize,
+    record: &SharedMmap,
+    out: &mut W,
+) -> Result<(), Error>
+where
+    W: Write + std::os::unix::io::AsRawFd,
+{
+    out.flush().map_err(Error::Write)?;
+
+    #[cfg(target_os = "linux")]
+    if let Some(file) = file {
+        if linux::send(file, offset, record.len(), out)? {
+            return Ok(());
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, offset);
+
+    out.write_all(record.as_ref()).map_err(Error::Write)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use crate::Error;
+    use std::{
+        fs::File,
+        io,
+        os::unix::io::{AsRawFd, RawFd},
+        ptr,
+    };
+
+    /// Copy the data with `copy_file_range` if `out` is a file or with
+    /// `sendfile` otherwise. Returns `false` if neither is supported for these
+    /// files and nothing was copied.
+    pub fn send<W: AsRawFd>(
+        file: &File,
+        offset: usize,
+        len: usize,
+        out: &W,
+    ) -> Result<bool, Error> {
+        let input = file.as_raw_fd();
+        let output = out.as_raw_fd();
+
+        if copy(input, output, offset, len, copy_file_range)? {
+            return Ok(true);
+        }
+
+        copy(input, output, offset, len, sendfile)
+    }
+
+    fn copy_file_range(input: RawFd, output: RawFd, offset: &mut i64, len: usize) -> isize {
+        unsafe { libc::copy_file_range(input, offset, output, ptr::null_mut(), len, 0) }
+    }
+
+    fn sendfile(input: RawFd, output: RawFd, offset: &mut i64, len: usize) -> isize {
+        unsafe { libc::sendfile(output, input, offset, len) }
+    }
+
+    fn copy<F>(input: RawFd, output: RawFd, offset: usize, len: usize, f: F) -> Result<bool, Error>
+    where
+        F: Fn(RawFd, RawFd, &mut i64, usize) -> isize,
+    {
+        let mut offset = offset as i64;
+        let mut remaining = len;
+
+        while remaining > 0 {
+            let copied = f(input, output, &mut offset, remaining);
+
+            if copied < 0 {
+                let err = io::Error::last_os_error();
+                let unsupported = matches!(
+                    err.raw_os_error(),
+                    Some(libc::EXDEV)
+                        | Some(libc::EINVAL)
+                        | Some(libc::ENOSYS)
+                        | Some(libc::EOPNOTSUPP)
+                        | Some(libc::EBADF)
+                );
+                match err.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    _ if unsupported && remaining == len => return Ok(false),
+                    _ => return Err(Error::Write(err)),
+                }
+            }
+
+            if copied == 0 {
+                return Err(Error::Write(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            remaining -= copied as usize;
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecordReader;
+    use crate::Database;
+    use std::io::{BufRead, Read, Seek, SeekFrom};
+
+    #[test]
+    fn read_and_seek() {
+        let db = Database::memory().unwrap();
+        db.put(b"first line\nsecond line").unwrap();
+
+        let mut reader = RecordReader::new(db.get_by_seqno(0).unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!("first line\n", line);
+
+        assert_eq!(18, reader.seek(SeekFrom::End(-4)).unwrap());
+        let mut tail = String::new();
+        reader.read_to_string(&mut tail).unwrap();
+        assert_eq!("line", tail);
+
+        assert_eq!(100, reader.seek(SeekFrom::Start(100)).unwrap());
+        assert_eq!(0, reader.read(&mut [0u8; 4]).unwrap());
+        assert!(reader.seek(SeekFrom::Current(-200)).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn send() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::file(tmp.path()).unwrap();
+        db.append(&[b"first", b"second"]).unwrap();
+
+        let mut out = tempfile::tempfile().unwrap();
+        assert_eq!(Some(6), db.send_record(1, &mut out).unwrap());
+        assert_eq!(None, db.send_record(2, &mut out).unwrap());
+
+        let mut content = Vec::new();
+        out.seek(SeekFrom::Start(0)).unwrap();
+        out.read_to_end(&mut content).unwrap();
+        assert_eq!(b"second", content.as_slice());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::SharedMmap;
+use std::ops::Range;
+
+/// Records with consecutive sequential numbers read at once by
+/// [`Database::get_range`]. The records are stored one after another in a
+/// single buffer, so the whole run can be sent with a single write.
+///
+/// [`Database::get_range`]: crate::Database::get_range
+#[derive(Debug, Clone)]
+pub struct RecordRun {
+    start: usize,
+    data: SharedMmap,
+    /// Where every record is in `data` or `None` if it is missing.
+    bounds: Vec<Option<Range<usize>>>,
+    mapped: bool,
+}
+
+impl RecordRun {
+    pub(crate) fn new(
+        start: usize,
+        data: SharedMmap,
+        bounds: Vec<Option<Range<usize>>>,
+        mapped: bool,
+    ) -> Self {
+        Self {
+            start,
+            data,
+            bounds,
+            mapped,
+        }
+    }
+
+    /// The sequential number of the first record.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// The number of sequential numbers the run covers, including the ones
+    /// of missing records.
+    pub fn len(&self) -> usize {
+        self.bounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bounds.is_empty()
+    }
+
+    /// All records of the run one after another.
+    pub fn data(&self) -> &SharedMmap {
+        &self.data
+    }
+
+    /// Whether `data` is a view into the data file rather than a copy.
+    pub fn is_mapped(&self) -> bool {
+        self.mapped
+    }
+
+    /// Where the record with the given sequential number is in `data`.
+    /// Returns `None` if the record is not in the run or is missing, e.g.
+    /// because it was deleted.
+    pub fn bounds(&self, seqno: usize) -> Option<Range<usize>> {
+        self.bounds.get(seqno.checked_sub(self.start)?)?.clone()
+    }
+
+    /// Get a record of the run by its sequential number.
+    pub fn get(&self, seqno: usize) -> Option<SharedMmap> {
+        self.bounds(seqno).map(|bounds| self.data.slice(bounds))
+    }
+
+    /// Iterate the records of the run along with their sequential numbers
+    /// skipping missing ones.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, SharedMmap)> + '_ {
+        (self.start..)
+            .zip(self.bounds.iter())
+            .filter_map(move |(seqno, bounds)| {
+                bounds
+                    .clone()
+                    .map(|bounds| (seqno, self.data.slice(bounds)))
+            })
+    }
+}
@@ -1,9 +1,82 @@
-use crate::{Appender, Error};
-use std::{mem::size_of, path::PathBuf};
+use crate::{quota::Quota, Appender, Error};
+use std::{
+    mem::size_of,
+    path::{Path, PathBuf},
+};
+
+/// The number of records in a block of [`IndexEncoding::Blocked`].
+const BLOCK_RECORDS: usize = 16;
+/// The size of a block of [`IndexEncoding::Blocked`] in bytes: the location of
+/// the first record followed by the distances to the other records.
+const BLOCK_SIZE: usize = size_of::<u64>() + (BLOCK_RECORDS - 1) * size_of::<u32>();
+
+/// How record locations are stored in the sequential number index.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexEncoding {
+    /// A little-endian `u64` per record. Suits any database.
+    #[default]
+    U64,
+    /// A little-endian `u32` per record. The data file cannot grow beyond
+    /// 4 GiB.
+    U32,
+    /// Blocks of 16 records: a `u64` location of the first record in a block
+    /// and `u32` distances from it to the other records. Takes a bit more than
+    /// a half of [`IndexEncoding::U64`]. A block cannot span more than 4 GiB of
+    /// data.
+    Blocked,
+}
+
+impl IndexEncoding {
+    /// The size of the index holding `len` records in bytes.
+    fn memory_size(self, len: usize) -> usize {
+        match self {
+            IndexEncoding::U64 => len * size_of::<u64>(),
+            IndexEncoding::U32 => len * size_of::<u32>(),
+            IndexEncoding::Blocked => {
+                let blocks = len / BLOCK_RECORDS;
+                let position = len % BLOCK_RECORDS;
+                let tail = if position == 0 {
+                    0
+                } else {
+                    Self::block_position(position)
+                };
+                blocks * BLOCK_SIZE + tail
+            }
+        }
+    }
+
+    /// The number of records held by an index of `memory_size` bytes.
+    fn len(self, memory_size: usize) -> usize {
+        match self {
+            IndexEncoding::U64 => memory_size / size_of::<u64>(),
+            IndexEncoding::U32 => memory_size / size_of::<u32>(),
+            IndexEncoding::Blocked => {
+                let blocks = memory_size / BLOCK_SIZE;
+                let tail = memory_size % BLOCK_SIZE;
+                let tail = if tail < size_of::<u64>() {
+                    0
+                } else {
+                    1 + (tail - size_of::<u64>()) / size_of::<u32>()
+                };
+                blocks * BLOCK_RECORDS + tail
+            }
+        }
+    }
+
+    /// The offset of a record within a block of [`IndexEncoding::Blocked`].
+    fn block_position(position: usize) -> usize {
+        if position == 0 {
+            0
+        } else {
+            size_of::<u64>() + (position - 1) * size_of::<u32>()
+        }
+    }
+}
 
 /// Index from the sequential number of a record to its location in a flatfile.
 pub(crate) struct SeqNoIndex {
     inner: Appender,
+    encoding: IndexEncoding,
 }
 
 impl SeqNoIndex {
@@ -12,8 +85,17 @@ impl SeqNoIndex {
     /// # Arguments
     ///
     /// * `path` - the path to the file. It will be created if not exists.
-    pub fn new(path: Option<PathBuf>) -> Result<Self, Error> {
-        Appender::new(path).map(|inner| Self { inner })
+    /// * `encoding` - how record locations are stored.
+    pub fn new(path: Option<PathBuf>, encoding: IndexEncoding) -> Result<Self, Error> {
+        Appender::new(path).map(|inner| Self { inner, encoding })
+    }
+
+    /// Fail to grow the index over `max` bytes.
+    pub fn with_quota(self, max: Option<usize>) -> Self {
+        Self {
+            inner: self.inner.with_quota(Quota::IndexSize, max),
+            ..self
+        }
     }
 
     /// Add records to index. This function will block if another write is still
@@ -23,39 +105,149 @@ impl SeqNoIndex {
             return Ok(None);
         }
 
-        let size_inc: usize = records.len() * size_of::<u64>();
-        let current_seqno = self.inner.size() / size_of::<u64>();
+        let current_seqno = self.size();
+        let size_inc = self.encoding.memory_size(current_seqno + records.len())
+            - self.encoding.memory_size(current_seqno);
+
+        match self.encoding {
+            IndexEncoding::U64 => self.inner.append(size_inc, move |mut mmap| {
+                for record in records {
+                    mmap[..size_of::<u64>()].copy_from_slice(&record.to_le_bytes()[..]);
+                    mmap = &mut mmap[size_of::<u64>()..];
+                }
+            })?,
+            IndexEncoding::U32 => {
+                if records.iter().any(|record| *record > u64::from(u32::MAX)) {
+                    return Err(Error::IndexOverflow);
+                }
 
-        self.inner.append(size_inc, move |mut mmap| {
-            for record in records {
-                mmap[..size_of::<u64>()].copy_from_slice(&record.to_le_bytes()[..]);
-                mmap = &mut mmap[size_of::<u64>()..];
+                self.inner.append(size_inc, move |mut mmap| {
+                    for record in records {
+                        let record = *record as u32;
+                        mmap[..size_of::<u32>()].copy_from_slice(&record.to_le_bytes()[..]);
+                        mmap = &mut mmap[size_of::<u32>()..];
+                    }
+                })?
             }
-        })?;
+            IndexEncoding::Blocked => {
+                let position = current_seqno % BLOCK_RECORDS;
+                let base = if position == 0 {
+                    records[0]
+                } else {
+                    self.get_pointer_to_value(current_seqno - position)
+                        .ok_or(Error::SeqNoIndexDamaged)?
+                };
+
+                let mut check_base = base;
+                for (seqno, record) in (current_seqno..).zip(records.iter()) {
+                    if seqno % BLOCK_RECORDS == 0 {
+                        check_base = *record;
+                    } else if !matches!(
+                        record.checked_sub(check_base),
+                        Some(delta) if delta <= u64::from(u32::MAX)
+                    ) {
+                        return Err(Error::IndexOverflow);
+                    }
+                }
+
+                self.inner.append(size_inc, move |mut mmap| {
+                    let mut base = base;
+                    for (seqno, record) in (current_seqno..).zip(records.iter()) {
+                        if seqno % BLOCK_RECORDS == 0 {
+                            base = *record;
+                            mmap[..size_of::<u64>()].copy_from_slice(&record.to_le_bytes()[..]);
+                            mmap = &mut mmap[size_of::<u64>()..];
+                        } else {
+                            let delta = (*record - base) as u32;
+                            mmap[..size_of::<u32>()].copy_from_slice(&delta.to_le_bytes()[..]);
+                            mmap = &mut mmap[size_of::<u32>()..];
+                        }
+                    }
+                })?
+            }
+        }
 
         Ok(Some(current_seqno))
     }
 
     /// Get the location of a record with the given number.
     pub fn get_pointer_to_value(&self, seqno: usize) -> Option<u64> {
-        let offset = seqno * size_of::<u64>();
+        match self.encoding {
+            IndexEncoding::U64 => self.read_u64(seqno * size_of::<u64>()),
+            IndexEncoding::U32 => self.read_u32(seqno * size_of::<u32>()).map(u64::from),
+            IndexEncoding::Blocked => {
+                let block = (seqno / BLOCK_RECORDS) * BLOCK_SIZE;
+                let position = seqno % BLOCK_RECORDS;
+                let base = self.read_u64(block)?;
+
+                if position == 0 {
+                    return Some(base);
+                }
+
+                let delta = self.read_u32(block + IndexEncoding::block_position(position))?;
+                Some(base + u64::from(delta))
+            }
+        }
+    }
+
+    fn read_u64(&self, offset: usize) -> Option<u64> {
+        self.inner.get_data(offset, |mmap| {
+            let mut bytes = [0u8; size_of::<u64>()];
+            bytes.copy_from_slice(mmap.as_ref().get(..size_of::<u64>())?);
+
+            Some(u64::from_le_bytes(bytes))
+        })
+    }
 
+    fn read_u32(&self, offset: usize) -> Option<u32> {
         self.inner.get_data(offset, |mmap| {
-            let mut key_length_bytes = [0u8; size_of::<u64>()];
-            key_length_bytes.copy_from_slice(&mmap.as_ref()[..size_of::<u64>()]);
+            let mut bytes = [0u8; size_of::<u32>()];
+            bytes.copy_from_slice(mmap.as_ref().get(..size_of::<u32>())?);
 
-            Some(u64::from_le_bytes(key_length_bytes))
+            Some(u32::from_le_bytes(bytes))
         })
     }
 
     pub fn size(&self) -> usize {
-        self.inner.size() / size_of::<u64>()
+        self.encoding.len(self.inner.size())
+    }
+
+    /// The size of the index in bytes.
+    pub fn memory_size(&self) -> usize {
+        self.inner.size()
+    }
+
+    pub fn mappings(&self) -> usize {
+        self.inner.mappings()
+    }
+
+    /// Leave only the first `len` records in a closed index. Returns the
+    /// location of the first removed record in a flatfile if any records were
+    /// removed.
+    pub fn truncate(
+        path: &Path,
+        encoding: IndexEncoding,
+        len: usize,
+    ) -> Result<Option<u64>, Error> {
+        let pointer = {
+            let index = Self::new(Some(path.to_path_buf()), encoding)?;
+            if len >= index.size() {
+                return Ok(None);
+            }
+            index
+                .get_pointer_to_value(len)
+                .ok_or(Error::SeqNoIndexDamaged)?
+        };
+
+        Appender::truncate(path, encoding.memory_size(len))?;
+
+        Ok(Some(pointer))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::SeqNoIndex;
+    use super::{IndexEncoding, SeqNoIndex};
 
     #[quickcheck]
     fn test_read_write(records: Vec<u64>) {
@@ -65,7 +257,7 @@ mod tests {
 
         let tmp = tempfile::NamedTempFile::new().unwrap();
 
-        let index = SeqNoIndex::new(Some(tmp.path().to_path_buf())).unwrap();
+        let index = SeqNoIndex::new(Some(tmp.path().to_path_buf()), IndexEncoding::U64).unwrap();
         index.append(&records).unwrap();
 
         for (i, record) in records.iter().enumerate() {
@@ -78,7 +270,7 @@ mod tests {
     fn test_seq_number(records: Vec<u64>) {
         let tmp = tempfile::NamedTempFile::new().unwrap();
 
-        let index = SeqNoIndex::new(Some(tmp.path().to_path_buf())).unwrap();
+        let index = SeqNoIndex::new(Some(tmp.path().to_path_buf()), IndexEncoding::U64).unwrap();
         let checks_count = 100usize;
         for i in 0..checks_count {
             let result = index.append(&records).unwrap();
@@ -97,4 +289,45 @@ mod tests {
             }
         }
     }
+
+    #[quickcheck]
+    fn test_compact_encodings(lengths: Vec<u16>, split: usize) {
+        let mut offset = 0u64;
+        let records: Vec<_> = lengths
+            .iter()
+            .map(|length| {
+                let record = offset;
+                offset += u64::from(*length);
+                record
+            })
+            .collect();
+        let split = if records.is_empty() {
+            0
+        } else {
+            split % records.len()
+        };
+
+        for encoding in [IndexEncoding::U32, IndexEncoding::Blocked].iter() {
+            let index = SeqNoIndex::new(None, *encoding).unwrap();
+            index.append(&records[..split]).unwrap();
+            index.append(&records[split..]).unwrap();
+
+            assert_eq!(records.len(), index.size());
+            assert_eq!(encoding.memory_size(records.len()), index.memory_size());
+            for (i, record) in records.iter().enumerate() {
+                assert_eq!(Some(*record), index.get_pointer_to_value(i));
+            }
+            assert_eq!(None, index.get_pointer_to_value(records.len()));
+        }
+    }
+
+    #[test]
+    fn test_overflow() {
+        let index = SeqNoIndex::new(None, IndexEncoding::U32).unwrap();
+        assert!(index.append(&[0, 1 << 32]).is_err());
+
+        let index = SeqNoIndex::new(None, IndexEncoding::Blocked).unwrap();
+        assert!(index.append(&[1 << 40, (1 << 40) + 1]).is_ok());
+        assert!(index.append(&[1 << 41]).is_err());
+    }
 }
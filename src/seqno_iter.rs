@@ -1,37 +1,80 @@
-use crate::{flatfile::FlatFile, seqno::SeqNoIndex, SharedMmap};
-use std::sync::Arc;
+use crate::{Database, Error, SharedMmap};
 
 /// This structure allows to iterate over records in the order they were added
-/// to this database.
+/// to this database. Deleted records are skipped. A record that cannot be
+/// decompressed or decrypted, e.g. because it is damaged, ends the iteration
+/// as if there were no more records: use [`SeqNoIter::try_next`] to tell
+/// these cases apart.
 pub struct SeqNoIter {
-    data: Arc<FlatFile>,
-    index: Arc<SeqNoIndex>,
+    db: Database,
     seqno: usize,
+    end: usize,
+    // The location of the next record. It is known after the first record was
+    // read, so the index does not have to be looked up again.
+    offset: Option<usize>,
 }
 
 impl SeqNoIter {
-    pub(crate) fn new(data: Arc<FlatFile>, index: Arc<SeqNoIndex>, seqno: usize) -> Self {
-        Self { data, index, seqno }
+    pub(crate) fn new(db: Database, seqno: usize, end: usize) -> Self {
+        let mut iter = Self {
+            db,
+            seqno,
+            end,
+            offset: None,
+        };
+        iter.skip_deleted();
+        iter
     }
 
-    fn next_impl(&mut self) -> Option<SharedMmap> {
-        let offset = self.index.get_pointer_to_value(self.seqno)? as usize;
-        let next_offset = self
-            .index
-            .get_pointer_to_value(self.seqno + 1)
-            .map(|value| value as usize)
-            .unwrap_or_else(|| self.data.len());
-        let length = next_offset - offset;
-        let item = self.data.get_record_at_offset(offset, length)?;
+    /// The sequential number of the record that will be returned next.
+    pub fn seqno(&self) -> usize {
+        self.seqno
+    }
+
+    /// Move past deleted records, so that `seqno` tells which record is
+    /// returned next.
+    fn skip_deleted(&mut self) {
+        while self.seqno < self.end && self.db.is_deleted(self.seqno) {
+            self.seqno += 1;
+            self.offset = None;
+        }
+    }
+
+    /// Get the next record like `next`, but fail if it cannot be
+    /// decompressed or decrypted instead of ending the iteration. The record
+    /// that failed is skipped, so the iteration can go on.
+    pub fn try_next(&mut self) -> Result<Option<SharedMmap>, Error> {
+        // The record could be deleted after the iterator moved to it.
+        self.skip_deleted();
+        if self.seqno >= self.end {
+            return Ok(None);
+        }
+        let (item, next_offset) = match self.db.read_at(self.seqno, self.offset) {
+            Ok(Some(read)) => read,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                self.seqno += 1;
+                self.offset = None;
+                self.skip_deleted();
+                return Err(err);
+            }
+        };
         self.seqno += 1;
-        Some(item)
+        self.offset = Some(next_offset);
+        self.skip_deleted();
+        Ok(Some(item))
     }
 }
 
+/// Iteration ends at a record that cannot be decompressed or decrypted: use
+/// [`SeqNoIter::try_next`] to learn about such records.
 impl Iterator for SeqNoIter {
     type Item = SharedMmap;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next_impl()
+        self.try_next().unwrap_or_else(|_| {
+            self.end = self.seqno;
+            None
+        })
     }
 }
@@ -1,24 +1,47 @@
 use memmap2::Mmap;
 use std::{
-    ops::{Bound::*, RangeBounds},
+    borrow::Borrow,
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+    ops::{Bound::*, Deref, RangeBounds},
     slice,
     sync::Arc,
 };
 
-/// A structure that implements a view into memory mapping.
+/// A structure that implements a view into memory mapping. Records that are
+/// transformed on read (e.g. decompressed) are held in memory instead.
 #[derive(Debug, Clone)]
 pub struct SharedMmap {
-    mmap: Arc<Mmap>,
+    mmap: Arc<Backing>,
     len: usize,
     slice: *const u8,
 }
 
+#[derive(Debug)]
+enum Backing {
+    Mmap(Mmap),
+    Owned(Box<[u8]>),
+}
+
+impl Backing {
+    fn as_ptr(&self) -> *const u8 {
+        match self {
+            Backing::Mmap(mmap) => mmap.as_ptr(),
+            Backing::Owned(data) => data.as_ptr(),
+        }
+    }
+}
+
 impl SharedMmap {
     pub(crate) fn new(mmap: Mmap) -> SharedMmap {
         let len = mmap.len();
-        let slice = mmap.as_ptr();
+        Self::with_backing(Backing::Mmap(mmap), len)
+    }
+
+    fn with_backing(backing: Backing, len: usize) -> SharedMmap {
+        let slice = backing.as_ptr();
         SharedMmap {
-            mmap: Arc::new(mmap),
+            mmap: Arc::new(backing),
             len,
             slice,
         }
@@ -77,11 +100,23 @@ impl SharedMmap {
         }
     }
 
+    /// Whether other views share the memory mapping of this one.
+    pub(crate) fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.mmap) > 1
+    }
+
     fn get_ref(&self) -> &[u8] {
         unsafe { slice::from_raw_parts(self.slice, self.len) }
     }
 }
 
+impl From<Vec<u8>> for SharedMmap {
+    fn from(data: Vec<u8>) -> Self {
+        let len = data.len();
+        Self::with_backing(Backing::Owned(data.into_boxed_slice()), len)
+    }
+}
+
 // Those are safe to implement because the underlying `*const u8` is never
 // modified.
 unsafe impl Send for SharedMmap {}
@@ -92,3 +127,108 @@ impl AsRef<[u8]> for SharedMmap {
         self.get_ref()
     }
 }
+
+impl Deref for SharedMmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.get_ref()
+    }
+}
+
+impl Borrow<[u8]> for SharedMmap {
+    fn borrow(&self) -> &[u8] {
+        self.get_ref()
+    }
+}
+
+// Comparisons and hashing use the content of the view, so that `SharedMmap`
+// can be used interchangeably with `[u8]` in collections.
+
+impl PartialEq for SharedMmap {
+    fn eq(&self, other: &Self) -> bool {
+        self.get_ref() == other.get_ref()
+    }
+}
+
+impl Eq for SharedMmap {}
+
+impl PartialEq<[u8]> for SharedMmap {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.get_ref() == other
+    }
+}
+
+impl PartialOrd for SharedMmap {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SharedMmap {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.get_ref().cmp(other.get_ref())
+    }
+}
+
+impl Hash for SharedMmap {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.get_ref().hash(state)
+    }
+}
+
+/// Zero-copy conversion: the memory mapping or the buffer is kept alive while
+/// the `Bytes` are.
+#[cfg(feature = "bytes")]
+impl From<SharedMmap> for bytes::Bytes {
+    fn from(mmap: SharedMmap) -> Self {
+        bytes::Bytes::from_owner(mmap)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for SharedMmap {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.get_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Database;
+    use std::collections::HashSet;
+
+    #[test]
+    fn compare_and_hash() {
+        let db = Database::memory().unwrap();
+        db.append(&[b"first", b"second", b"first"]).unwrap();
+
+        let first = db.get_by_seqno(0).unwrap();
+        let second = db.get_by_seqno(1).unwrap();
+        let third = db.get_by_seqno(2).unwrap();
+
+        assert_eq!(first, third);
+        assert!(first < second);
+        assert_eq!(b"first"[..], *first);
+        assert!(first.starts_with(b"fi"));
+
+        let set: HashSet<_> = vec![first, second, third].into_iter().collect();
+        assert_eq!(2, set.len());
+        assert!(set.contains(&b"second"[..]));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn into_bytes() {
+        let db = Database::memory().unwrap();
+        db.put(b"some data").unwrap();
+
+        let record = db.get_by_seqno(0).unwrap();
+        let pointer = record.as_ptr();
+        let bytes = bytes::Bytes::from(record);
+        drop(db);
+
+        assert_eq!(pointer, bytes.as_ptr());
+        assert_eq!(b"some data", bytes.as_ref());
+    }
+}
@@ -0,0 +1,14 @@
+/// Database statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    /// The number of records.
+    pub records: usize,
+    /// The number of deleted records.
+    pub deleted: usize,
+    /// The size of the data file in bytes.
+    pub data_size: usize,
+    /// The size of the sequential number index in bytes.
+    pub index_size: usize,
+    /// The number of memory mappings used by the data file and the index.
+    pub mappings: usize,
+}
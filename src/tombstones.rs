@@ -0,0 +1,237 @@
+//! Deleted records. A bitmap with a bit for every sequential number is kept
+//! in memory and persisted in the `deleted` file of the database directory.
+//! Bit `seqno % 8` of byte `seqno / 8` is set if the record is deleted.
+
+use crate::{appender::write_at, Error};
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{PoisonError, RwLock},
+};
+
+pub(crate) struct Tombstones {
+    path: Option<PathBuf>,
+    state: RwLock<State>,
+}
+
+struct State {
+    bits: Vec<u8>,
+    /// Opened on the first deletion.
+    file: Option<File>,
+}
+
+impl Tombstones {
+    /// Load the bitmap stored in `dir`, if any. Bits past `records`, the
+    /// number of records in the database, are ignored. The file is not
+    /// changed, because another process may be writing to the database.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - the database directory or `None` for in-memory databases.
+    /// * `records` - the number of records in the database.
+    pub fn load(dir: Option<PathBuf>, records: usize) -> Result<Self, Error> {
+        let path = dir.map(|dir| dir.join("deleted"));
+        let mut bits = Vec::new();
+
+        if let Some(path) = &path {
+            bits = read(path)?;
+            clear_from(&mut bits, records);
+        }
+
+        Ok(Self {
+            path,
+            state: RwLock::new(State { bits, file: None }),
+        })
+    }
+
+    /// Clear bits past `records` in the bitmap stored in `dir`, so that
+    /// records written after the database was truncated are not deleted. The
+    /// database must not be opened while doing this.
+    pub fn truncate(dir: &Path, records: usize) -> Result<(), Error> {
+        let path = dir.join("deleted");
+        let mut bits = read(&path)?;
+        if clear_from(&mut bits, records) {
+            fs::write(&path, &bits).map_err(Error::Write)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_deleted(&self, seqno: usize) -> bool {
+        let state = self.state.read().unwrap_or_else(PoisonError::into_inner);
+        state
+            .bits
+            .get(seqno / 8)
+            .is_some_and(|byte| byte & mask(seqno) != 0)
+    }
+
+    /// Mark a record as deleted. Returns `false` if it was already deleted.
+    pub fn delete(&self, seqno: usize) -> Result<bool, Error> {
+        let mut state = self.state.write().unwrap_or_else(PoisonError::into_inner);
+        let index = seqno / 8;
+
+        if state.bits.len() <= index {
+            state.bits.resize(index + 1, 0);
+        } else if state.bits[index] & mask(seqno) != 0 {
+            return Ok(false);
+        }
+
+        let byte = state.bits[index] | mask(seqno);
+        self.store(&mut state, index, &[byte])?;
+        state.bits[index] = byte;
+        Ok(true)
+    }
+
+    /// Mark all records in `seqnos` as deleted.
+    pub fn delete_range(&self, seqnos: Range<usize>) -> Result<(), Error> {
+        if seqnos.is_empty() {
+            return Ok(());
+        }
+
+        let mut state = self.state.write().unwrap_or_else(PoisonError::into_inner);
+        let (first, last) = (seqnos.start / 8, (seqnos.end - 1) / 8);
+        if state.bits.len() <= last {
+            state.bits.resize(last + 1, 0);
+        }
+
+        let mut bytes = state.bits[first..=last].to_vec();
+        for seqno in seqnos {
+            bytes[seqno / 8 - first] |= mask(seqno);
+        }
+        self.store(&mut state, first, &bytes)?;
+        state.bits[first..=last].copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    /// Write `bytes` of the bitmap starting from byte `index` to the file.
+    fn store(&self, state: &mut State, index: usize, bytes: &[u8]) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if state.file.is_none() {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(|err| Error::FileOpen(path.clone(), err))?;
+            state.file = Some(file);
+        }
+        let file = state.file.as_ref().expect("the file was opened");
+        write_at(file, bytes, index as u64).map_err(Error::Write)
+    }
+
+    /// The number of deleted records before the first record that is not
+    /// deleted.
+    pub fn leading(&self) -> usize {
+        let state = self.state.read().unwrap_or_else(PoisonError::into_inner);
+        let full = state
+            .bits
+            .iter()
+            .take_while(|byte| **byte == u8::MAX)
+            .count();
+        let rest = state
+            .bits
+            .get(full)
+            .map_or(0, |byte| byte.trailing_ones() as usize);
+        full * 8 + rest
+    }
+
+    /// The number of deleted records.
+    pub fn count(&self) -> usize {
+        let state = self.state.read().unwrap_or_else(PoisonError::into_inner);
+        state
+            .bits
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+}
+
+/// Read the bitmap stored at `path`. It is empty if there is no such file.
+fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    match fs::read(path) {
+        Ok(data) => Ok(data),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(Error::FileOpen(path.to_path_buf(), err)),
+    }
+}
+
+fn mask(seqno: usize) -> u8 {
+    1 << (seqno % 8)
+}
+
+/// Clear bits starting from `seqno`. Returns `false` if none of them were set.
+fn clear_from(bits: &mut Vec<u8>, seqno: usize) -> bool {
+    let index = seqno / 8;
+    let keep = mask(seqno) - 1;
+    let set = match bits.get(index..) {
+        Some([first, rest @ ..]) => first & !keep != 0 || rest.iter().any(|byte| *byte != 0),
+        _ => false,
+    };
+
+    if set {
+        bits[index] &= keep;
+        bits.truncate(index + 1);
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tombstones;
+
+    #[test]
+    fn persist() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = || Some(tmp.path().to_path_buf());
+
+        {
+            let tombstones = Tombstones::load(dir(), 100).unwrap();
+            assert!(!tombstones.is_deleted(3));
+            assert!(tombstones.delete(3).unwrap());
+            assert!(!tombstones.delete(3).unwrap());
+            assert!(tombstones.delete(17).unwrap());
+            assert!(tombstones.delete(42).unwrap());
+            assert!(tombstones.is_deleted(17));
+            assert!(!tombstones.is_deleted(16));
+            assert_eq!(3, tombstones.count());
+        }
+
+        let tombstones = Tombstones::load(dir(), 100).unwrap();
+        assert!(tombstones.is_deleted(3));
+        assert!(tombstones.is_deleted(42));
+        assert_eq!(3, tombstones.count());
+        drop(tombstones);
+
+        // Another process sees fewer records and leaves the file alone.
+        let tombstones = Tombstones::load(dir(), 17).unwrap();
+        assert!(!tombstones.is_deleted(17));
+        assert!(!tombstones.is_deleted(42));
+        assert_eq!(6, std::fs::read(tmp.path().join("deleted")).unwrap().len());
+        drop(tombstones);
+
+        // The database was truncated to 17 records.
+        Tombstones::truncate(tmp.path(), 17).unwrap();
+        let tombstones = Tombstones::load(dir(), 17).unwrap();
+        assert!(tombstones.is_deleted(3));
+        assert!(!tombstones.is_deleted(17));
+        assert!(!tombstones.is_deleted(42));
+        assert_eq!(3, std::fs::read(tmp.path().join("deleted")).unwrap().len());
+
+        tombstones.delete_range(5..30).unwrap();
+        drop(tombstones);
+        let tombstones = Tombstones::load(dir(), 40).unwrap();
+        assert!(!tombstones.is_deleted(4));
+        assert!(tombstones.is_deleted(5));
+        assert!(tombstones.is_deleted(29));
+        assert!(!tombstones.is_deleted(30));
+        assert_eq!(26, tombstones.count());
+        assert_eq!(0, tombstones.leading());
+        tombstones.delete_range(0..5).unwrap();
+        assert_eq!(30, tombstones.leading());
+    }
+}
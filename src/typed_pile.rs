@@ -0,0 +1,358 @@
+use crate::{Codec, Database, Error, SeqNoIter};
+use std::{marker::PhantomData, ops::RangeBounds};
+
+/// A database of values of type `T`, stored as records encoded with `C`.
+pub struct TypedPile<T, C> {
+    db: Database,
+    codec: C,
+    _values: PhantomData<fn(T) -> T>,
+}
+
+impl<T, C: Codec<T>> TypedPile<T, C> {
+    pub fn new(db: Database, codec: C) -> Self {
+        Self {
+            db,
+            codec,
+            _values: PhantomData,
+        }
+    }
+
+    /// Encode and write an array of values. Returns the sequential number of
+    /// the first of them or `None` if `values` is empty.
+    pub fn append(&self, values: &[T]) -> Result<Option<usize>, Error> {
+        let mut buf = Vec::new();
+        let mut ends = Vec::with_capacity(values.len());
+        for value in values {
+            self.codec
+                .encode(value, &mut buf)
+                .map_err(|err| Error::Encode(Box::new(err)))?;
+            ends.push(buf.len());
+        }
+
+        let mut start = 0;
+        let records: Vec<&[u8]> = ends
+            .into_iter()
+            .map(|end| {
+                let record = &buf[start..end];
+                start = end;
+                record
+            })
+            .collect();
+
+        self.db.append_get_seqno(&records)
+    }
+
+    pub fn put(&self, value: &T) -> Result<usize, Error> {
+        self.append(std::slice::from_ref(value))
+            .map(|seqno| seqno.expect("one value was written"))
+    }
+
+    /// Get the value with the given sequential number.
+    pub fn get(&self, seqno: usize) -> Result<Option<T>, Error> {
+        self.db
+            .try_get_by_seqno(seqno)?
+            .map(|record| self.decode(seqno, &record))
+            .transpose()
+    }
+
+    /// Iterate over values in the given range of sequential numbers.
+    pub fn iter_range(&self, range: impl RangeBounds<usize>) -> TypedIter<'_, T, C> {
+        TypedIter {
+            inner: self.db.iter_range(range),
+            pile: self,
+        }
+    }
+
+    pub fn iter(&self) -> TypedIter<'_, T, C> {
+        self.iter_range(..)
+    }
+
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.db.is_empty()
+    }
+
+    /// The underlying database of raw records.
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> Database {
+        self.db
+    }
+
+    fn decode(&self, seqno: usize, record: &[u8]) -> Result<T, Error> {
+        self.codec
+            .decode(record)
+            .map_err(|err| Error::Decode(seqno, Box::new(err)))
+    }
+}
+
+/// Iterates over values of a `TypedPile` in the order they were added.
+pub struct TypedIter<'a, T, C> {
+    inner: SeqNoIter,
+    pile: &'a TypedPile<T, C>,
+}
+
+impl<T, C: Codec<T>> Iterator for TypedIter<'_, T, C> {
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let seqno = self.inner.seqno();
+        match self.inner.try_next() {
+            Ok(record) => record.map(|record| self.pile.decode(seqno, &record)),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[cfg(feature = "rkyv")]
+mod archived {
+    use super::TypedPile;
+    use crate::{
+        codec::rkyv_support::{aligned, is_aligned, AlignedVec, Archivable},
+        Error, Rkyv, SharedMmap,
+    };
+    use rkyv::{rancor, Archive};
+    use std::{marker::PhantomData, ops::Deref};
+
+    /// An `rkyv` archive of a value. It points into the database file unless
+    /// the record is not aligned well enough and had to be copied.
+    pub struct ArchivedRecord<T> {
+        bytes: Bytes,
+        _value: PhantomData<fn() -> T>,
+    }
+
+    enum Bytes {
+        Mapped(SharedMmap),
+        Copied(AlignedVec),
+    }
+
+    impl<T: Archivable> ArchivedRecord<T> {
+        fn new(seqno: usize, record: SharedMmap) -> Result<Self, Error> {
+            let bytes = if is_aligned(&record) {
+                Bytes::Mapped(record)
+            } else {
+                Bytes::Copied(aligned(&record))
+            };
+
+            rkyv::access::<T::Archived, rancor::Error>(bytes.as_ref())
+                .map_err(|err| Error::Decode(seqno, Box::new(err)))?;
+
+            Ok(Self {
+                bytes,
+                _value: PhantomData,
+            })
+        }
+
+        /// Whether the archive is read straight from the database.
+        pub fn is_mapped(&self) -> bool {
+            matches!(self.bytes, Bytes::Mapped(_))
+        }
+    }
+
+    impl<T: Archive> Deref for ArchivedRecord<T> {
+        type Target = T::Archived;
+
+        fn deref(&self) -> &Self::Target {
+            // The archive was validated when the record was created.
+            unsafe { rkyv::access_unchecked(self.bytes.as_ref()) }
+        }
+    }
+
+    impl AsRef<[u8]> for Bytes {
+        fn as_ref(&self) -> &[u8] {
+            match self {
+                Bytes::Mapped(record) => record,
+                Bytes::Copied(record) => record,
+            }
+        }
+    }
+
+    impl<T: Archivable> TypedPile<T, Rkyv> {
+        /// Access the archived value with the given sequential number without
+        /// deserializing it.
+        pub fn get_archived(&self, seqno: usize) -> Result<Option<ArchivedRecord<T>>, Error> {
+            self.db
+                .get_by_seqno(seqno)
+                .map(|record| ArchivedRecord::new(seqno, record))
+                .transpose()
+        }
+    }
+}
+
+#[cfg(feature = "rkyv")]
+pub use archived::ArchivedRecord;
+
+#[cfg(test)]
+mod tests {
+    use super::TypedPile;
+    use crate::{Codec, Database, Error};
+    use std::{io, str};
+
+    /// Stores strings as they are. Fails to encode empty strings.
+    struct Utf8;
+
+    impl Codec<String> for Utf8 {
+        type Error = io::Error;
+
+        fn encode(&self, value: &String, buf: &mut Vec<u8>) -> Result<(), Self::Error> {
+            if value.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty string"));
+            }
+            buf.extend_from_slice(value.as_bytes());
+            Ok(())
+        }
+
+        fn decode(&self, record: &[u8]) -> Result<String, Self::Error> {
+            str::from_utf8(record)
+                .map(str::to_owned)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+    }
+
+    #[quickcheck]
+    fn append_and_get(values: Vec<String>) {
+        let values: Vec<_> = values.into_iter().filter(|x| !x.is_empty()).collect();
+        let pile = TypedPile::new(Database::memory().unwrap(), Utf8);
+
+        pile.append(&values).unwrap();
+        assert_eq!(values.len(), pile.len());
+
+        for (seqno, value) in values.iter().enumerate() {
+            assert_eq!(Some(value), pile.get(seqno).unwrap().as_ref());
+        }
+        assert!(pile.get(values.len()).unwrap().is_none());
+
+        let read: Vec<_> = pile.iter().map(Result::unwrap).collect();
+        assert_eq!(values, read);
+    }
+
+    #[test]
+    fn errors() {
+        let pile = TypedPile::new(Database::memory().unwrap(), Utf8);
+
+        let values = ["first".to_owned(), String::new()];
+        assert!(matches!(pile.append(&values), Err(Error::Encode(_))));
+        assert!(pile.is_empty());
+
+        pile.database().put(&[0xff, 0xfe]).unwrap();
+        pile.put(&"second".to_owned()).unwrap();
+        assert!(matches!(pile.get(0), Err(Error::Decode(0, _))));
+
+        let mut iter = pile.iter();
+        assert!(matches!(iter.next(), Some(Err(Error::Decode(0, _)))));
+        assert_eq!("second", iter.next().unwrap().unwrap());
+        assert!(iter.next().is_none());
+    }
+
+    #[cfg(feature = "aes-gcm")]
+    #[test]
+    fn damaged() {
+        use crate::{Cipher, Options};
+        use std::{fs, sync::Arc};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let options = || Options::new().encryption(Cipher::Aes256Gcm, Arc::new([1u8; 32]));
+        {
+            let db = Database::file_with_options(tmp.path(), options()).unwrap();
+            let pile = TypedPile::new(db, Utf8);
+            pile.append(&["first".to_owned(), "second".to_owned()])
+                .unwrap();
+        }
+        let mut data = fs::read(tmp.path().join("data")).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(tmp.path().join("data"), data).unwrap();
+
+        let db = Database::file_with_options(tmp.path(), options()).unwrap();
+        let pile = TypedPile::new(db, Utf8);
+        assert!(matches!(pile.get(1), Err(Error::Authentication)));
+
+        let mut iter = pile.iter();
+        assert_eq!("first", iter.next().unwrap().unwrap());
+        assert!(matches!(iter.next(), Some(Err(Error::Authentication))));
+        assert!(iter.next().is_none());
+    }
+
+    #[cfg(any(feature = "bincode", feature = "postcard", feature = "json"))]
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Point {
+        name: String,
+        x: i64,
+        y: i64,
+    }
+
+    #[cfg(any(feature = "bincode", feature = "postcard", feature = "json"))]
+    fn serde_round_trip<C: Codec<Point>>(codec: C) {
+        let pile = TypedPile::new(Database::memory().unwrap(), codec);
+        let points: Vec<_> = (0..10)
+            .map(|i| Point {
+                name: format!("point {}", i),
+                x: i,
+                y: -i,
+            })
+            .collect();
+
+        assert_eq!(Some(0), pile.append(&points).unwrap());
+        assert_eq!(Some(points[3].clone()), pile.get(3).unwrap());
+
+        let read: Vec<_> = pile.iter_range(5..).map(Result::unwrap).collect();
+        assert_eq!(&points[5..], read.as_slice());
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode() {
+        serde_round_trip(crate::Bincode);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard() {
+        serde_round_trip(crate::Postcard);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        serde_round_trip(crate::Json);
+    }
+
+    #[cfg(feature = "rkyv")]
+    #[test]
+    fn rkyv() {
+        #[derive(Debug, PartialEq, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+        struct Entry {
+            key: u32,
+            value: Vec<u8>,
+        }
+
+        let pile = TypedPile::new(Database::memory().unwrap(), crate::Rkyv);
+        // Odd sizes put some of the records at unaligned offsets.
+        let entries: Vec<_> = (0..8)
+            .map(|key| Entry {
+                key,
+                value: vec![key as u8; key as usize * 3 + 1],
+            })
+            .collect();
+        pile.append(&entries).unwrap();
+
+        for (seqno, entry) in entries.iter().enumerate() {
+            assert_eq!(Some(entry), pile.get(seqno).unwrap().as_ref());
+
+            let archived = pile.get_archived(seqno).unwrap().unwrap();
+            assert_eq!(entry.key, archived.key);
+            assert_eq!(entry.value.as_slice(), archived.value.as_slice());
+        }
+        assert!(pile.get_archived(0).unwrap().unwrap().is_mapped());
+
+        pile.database().put(b"garbage").unwrap();
+        assert!(matches!(
+            pile.get_archived(entries.len()),
+            Err(Error::Decode(_, _))
+        ));
+    }
+}
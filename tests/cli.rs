@@ -0,0 +1,144 @@
+use assert_cmd::Command;
+use predicates::prelude::*;
+
+fn pile() -> Command {
+    Command::cargo_bin("pile").unwrap()
+}
+
+#[test]
+fn stat_does_not_create_files() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("db");
+
+    pile()
+        .arg(&path)
+        .arg("stat")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is not a database"));
+    assert!(!path.exists());
+}
+
+#[test]
+fn append_and_get() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("db");
+
+    pile()
+        .arg(&path)
+        .args(["append", "--lines"])
+        .write_stdin("first\nsecond\nthird\n")
+        .assert()
+        .success()
+        .stderr("appended records 0..3\n");
+    pile()
+        .arg(&path)
+        .arg("append")
+        .write_stdin("fourth")
+        .assert()
+        .success()
+        .stderr("appended records 3..4\n");
+
+    pile()
+        .arg(&path)
+        .args(["get", "2"])
+        .assert()
+        .success()
+        .stdout("third\n");
+    pile()
+        .arg(&path)
+        .args(["--output", "hex", "get", "0"])
+        .assert()
+        .success()
+        .stdout("0: 6669727374\n");
+    pile()
+        .arg(&path)
+        .args(["get", "4"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("record 4 not found"));
+
+    pile()
+        .arg(&path)
+        .arg("stat")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("records: 4\ndeleted: 0\n"));
+}
+
+#[test]
+fn append_empty() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("db");
+
+    pile()
+        .arg(&path)
+        .args(["append", "--lines"])
+        .write_stdin("first\n\nthird\n")
+        .assert()
+        .success()
+        .stderr("appended records 0..3\n");
+    pile()
+        .arg(&path)
+        .arg("append")
+        .write_stdin("")
+        .assert()
+        .success()
+        .stderr("appended records 3..4\n");
+    pile()
+        .arg(&path)
+        .args(["append", "--lines", "--skip-empty"])
+        .write_stdin("\nfifth\n")
+        .assert()
+        .success()
+        .stderr("appended records 4..5\n");
+
+    pile()
+        .arg(&path)
+        .args(["--output", "hex", "cat"])
+        .assert()
+        .success()
+        .stdout("0: 6669727374\n1: \n2: 7468697264\n3: \n4: 6669667468\n");
+}
+
+#[test]
+fn encrypted() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("db");
+    let key = tmp.path().join("key");
+    std::fs::write(&key, [7u8; 32]).unwrap();
+
+    pile()
+        .arg(&path)
+        .arg("--key-file")
+        .arg(&key)
+        .args(["--cipher", "chacha20-poly1305", "append"])
+        .write_stdin("secret")
+        .assert()
+        .success();
+    pile()
+        .arg(&path)
+        .arg("--key-file")
+        .arg(&key)
+        .args(["get", "0"])
+        .assert()
+        .success()
+        .stdout("secret\n");
+
+    pile()
+        .arg(&path)
+        .args(["get", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("is encrypted: pass its key"));
+
+    std::fs::write(&key, b"short").unwrap();
+    pile()
+        .arg(&path)
+        .arg("--key-file")
+        .arg(&key)
+        .args(["get", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("does not hold a 32-byte key"));
+}